@@ -1,12 +1,13 @@
 use std::error::Error;
+use std::thread;
+use std::time::Duration;
 
 use rppal::i2c::I2c;
-use si5351a_adafruit::{PLL, RDiv, Si5351};
+use si5351a_adafruit::{DeviceConfig, PLL, Si5351};
 
 fn main() -> Result<(), Box<dyn Error>> {
     let i2c = I2c::new()?;
-    let mut clock_gen = Si5351::new();
-    clock_gen.begin(i2c).unwrap();
+    let mut clock_gen = Si5351::new(i2c, DeviceConfig::default()).unwrap();
     // clock_gen.setup_pll(PLL::A, 31, 45728, 100000).unwrap();
     // clock_gen.setup_multisynth(0, PLL::A, 64, 0, 1).unwrap();
     // clock_gen.setup_multisynth(1, PLL::A, 64, 0, 1).unwrap();
@@ -15,5 +16,7 @@ fn main() -> Result<(), Box<dyn Error>> {
     // clock_gen.setup_rdiv(2, RDiv::Div128).unwrap();
     clock_gen.set_freq(0, PLL::A, 12_288_000).unwrap();
     clock_gen.enable_outputs(true).unwrap();
-    loop {}
+    loop {
+        thread::sleep(Duration::from_secs(1));
+    }
 }
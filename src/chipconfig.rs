@@ -0,0 +1,392 @@
+//! The whole clock tree described as one value and applied in one call.
+//!
+//! ```no_run
+//! # fn example<I2C: embedded_hal::i2c::I2c>(
+//! #     clock_gen: &mut si5351a_adafruit::Si5351<I2C>,
+//! # ) -> Result<(), si5351a_adafruit::Error> {
+//! use si5351a_adafruit::{ChipConfig, OutputConfig, OutputFreq, PLL};
+//!
+//! let mut config = ChipConfig::default();
+//! config.outputs[0] = OutputConfig {
+//!     freq: Some(OutputFreq::Hz(12_288_000)),
+//!     enabled: true,
+//!     ..OutputConfig::default()
+//! };
+//! config.outputs[1] = OutputConfig {
+//!     freq: Some(OutputFreq::Hz(10_000_000)),
+//!     pll: PLL::B,
+//!     enabled: true,
+//!     ..OutputConfig::default()
+//! };
+//! clock_gen.apply(&config)?;
+//! # Ok(())
+//! # }
+//! ```
+
+use embedded_hal::delay::DelayNs;
+use embedded_hal::i2c::I2c;
+
+use crate::freqplan::{FreqPlan, multisynth_divider};
+use crate::{
+    CrystalFreq, CrystalLoad, DriveStrength, Error, Observer, OutputState, PLL, RDiv, Registers,
+    Si5351, check,
+};
+
+/// Configuration of the whole chip, applied with [`Si5351::apply`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ChipConfig {
+    /// The crystal frequency
+    pub crystal_freq: CrystalFreq,
+    /// Internal load capacitance for the crystal
+    pub crystal_load: CrystalLoad,
+    /// The multipliers of PLL A and PLL B. `None` lets the first output
+    /// with a frequency in Hz on that PLL choose it, like
+    /// [`Si5351::set_freq`] does.
+    pub plls: [Option<PllConfig>; 2],
+    /// The outputs CLK0..CLK2
+    pub outputs: [OutputConfig; 3],
+    /// Spread spectrum modulation of PLL A, `None` disables it
+    pub spread_spectrum: Option<SpreadSpectrum>,
+}
+
+impl Default for ChipConfig {
+    /// The Adafruit module with all outputs off
+    fn default() -> Self {
+        Self {
+            crystal_freq: CrystalFreq::MHZ25,
+            crystal_load: CrystalLoad::PF10,
+            plls: [None; 2],
+            outputs: [OutputConfig::default(); 3],
+            spread_spectrum: None,
+        }
+    }
+}
+
+/// The feedback multiplier `mult + num / denom` of a PLL, as passed to
+/// [`Si5351::setup_pll`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct PllConfig {
+    /// The integer multiplier (15..90)
+    pub mult: u32,
+    /// The 20-bit numerator (0..1,048,575)
+    pub num: u32,
+    /// The 20-bit denominator (1..1,048,575)
+    pub denom: u32,
+}
+
+/// Spread spectrum modulation of PLL A, as passed to
+/// [`Si5351::setup_spread_spectrum`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct SpreadSpectrum {
+    /// The spread in steps of 0.01% (10..150, i.e. 0.1% to 1.5%)
+    pub amplitude: u16,
+    /// Where the spread lies relative to the nominal frequency
+    pub mode: SpreadMode,
+}
+
+/// Where the spread spectrum modulation lies relative to the nominal
+/// frequency
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum SpreadMode {
+    /// Between the nominal frequency and `amplitude` below it
+    Down,
+    /// `amplitude` around the nominal frequency
+    Center,
+}
+
+/// How an output frequency is given
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum OutputFreq {
+    /// A frequency in Hz, divided down from the PLL of the output
+    Hz(u32),
+    /// Explicit dividers, as passed to [`Si5351::setup_multisynth`] and
+    /// [`Si5351::setup_rdiv`]
+    Dividers {
+        div: u32,
+        num: u32,
+        denom: u32,
+        r_div: RDiv,
+    },
+}
+
+/// The level of an output while it is disabled
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+pub enum DisableState {
+    Low = 0,
+    High = 1,
+    HighImpedance = 2,
+    /// The output keeps running when disabled
+    Never = 3,
+}
+
+/// Configuration of a single output
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct OutputConfig {
+    /// The output frequency, `None` powers the output down
+    pub freq: Option<OutputFreq>,
+    /// The PLL feeding the multisynth
+    pub pll: PLL,
+    /// The drive current
+    pub drive: DriveStrength,
+    /// Whether the output is inverted
+    pub invert: bool,
+    /// The level of the output while it is disabled
+    pub disable_state: DisableState,
+    /// The initial phase offset in quarter periods of the PLL (0..127)
+    pub phase: u8,
+    /// Whether the output is enabled
+    pub enabled: bool,
+}
+
+impl Default for OutputConfig {
+    /// Powered down and disabled
+    fn default() -> Self {
+        Self {
+            freq: None,
+            pll: PLL::A,
+            drive: DriveStrength::MA8,
+            invert: false,
+            disable_state: DisableState::Low,
+            phase: 0,
+            enabled: false,
+        }
+    }
+}
+
+impl<I2C: I2c, DELAY: DelayNs, OBS: Observer> Si5351<I2C, DELAY, OBS> {
+    /// Configures the whole chip as described by `config`.
+    ///
+    /// The configuration is set up with [`Si5351::setup_pll`],
+    /// [`Si5351::setup_multisynth`] and [`Si5351::setup_rdiv`] in a
+    /// [`Transaction`](crate::Transaction), so an invalid configuration
+    /// leaves the chip alone and only the registers that differ from the
+    /// current state are written.
+    ///
+    /// Returns `Error::InvalidParameter` if a parameter is out of range, an
+    /// enabled output has no frequency, or an output with explicit dividers
+    /// uses a PLL which is neither configured nor chosen by another output.
+    pub fn apply(&mut self, config: &ChipConfig) -> Result<(), Error> {
+        let mut transaction = self.transaction();
+        transaction.stage(|driver| driver.stage_chip_config(config))?;
+        transaction.commit()
+    }
+
+    fn stage_chip_config(&mut self, config: &ChipConfig) -> Result<(), Error> {
+        for output in &config.outputs {
+            check(
+                output.freq.is_some() || !output.enabled,
+                Error::InvalidParameter,
+            )?;
+        }
+        self.config.crystal_freq = config.crystal_freq;
+        self.config.crystal_load = config.crystal_load;
+        self.write8(
+            Registers::CrystalInternalLoadCapacitance as u8,
+            config.crystal_load as u8,
+        )?;
+        // The PLL settings of the previous configuration no longer apply
+        self.config.plla_configured = false;
+        self.config.pllb_configured = false;
+        // Outputs whose frequency chose the multiplier of their PLL, with
+        // the dividers of that plan
+        let mut planned = [None; 3];
+        for (index, pll) in [PLL::A, PLL::B].into_iter().enumerate() {
+            if let Some(PllConfig { mult, num, denom }) = config.plls[index] {
+                self.setup_pll(pll, mult, num, denom)?;
+                continue;
+            }
+            let first_in_hz = config.outputs.iter().position(|output| {
+                output.pll == pll && matches!(output.freq, Some(OutputFreq::Hz(_)))
+            });
+            if let Some(output) = first_in_hz {
+                let Some(OutputFreq::Hz(freq)) = config.outputs[output].freq else {
+                    unreachable!()
+                };
+                let plan = FreqPlan::new(config.crystal_freq as u32, freq)?;
+                self.setup_pll(pll, plan.mult, plan.num, plan.denom)?;
+                planned[output] = Some((plan.ms_div, plan.r_div));
+            }
+        }
+        let mut disable_state = 0;
+        for (index, output) in config.outputs.iter().enumerate() {
+            let clk_control = Registers::CLK0Control as u8 + index as u8;
+            match output.freq {
+                None => {
+                    // Power down the output driver
+                    self.write8(clk_control, 0x80)?;
+                    self.outputs[index] = OutputState::new();
+                }
+                Some(OutputFreq::Dividers {
+                    div,
+                    num,
+                    denom,
+                    r_div,
+                }) => {
+                    self.setup_multisynth(index, output.pll, div, num, denom)?;
+                    self.setup_rdiv(index, r_div)?;
+                }
+                Some(OutputFreq::Hz(freq)) => {
+                    let (ms_div, r_div) = match planned[index] {
+                        Some((ms_div, r_div)) => ((ms_div, 0, 1), r_div),
+                        None => {
+                            // Another output chose the PLL, divide it down
+                            check(self.pll_configured(output.pll), Error::InvalidParameter)?;
+                            let ratio = self.pll_ratio(output.pll);
+                            let vco_numerator = self.crystal_millihertz()
+                                * (ratio.a as u128 * ratio.c as u128 + ratio.b as u128);
+                            let (ms, r_div) = multisynth_divider(
+                                vco_numerator,
+                                ratio.c as u128,
+                                freq as u64 * 1000,
+                                (0, 7),
+                                true,
+                            )?;
+                            ((ms.a, ms.b, ms.c), r_div)
+                        }
+                    };
+                    self.setup_multisynth(index, output.pll, ms_div.0, ms_div.1, ms_div.2)?;
+                    self.setup_rdiv(index, r_div)?;
+                }
+            }
+            if output.freq.is_some() {
+                self.set_drive_strength(index, output.drive)?;
+                let control = self.registers.get(clk_control).unwrap_or(0);
+                let invert = if output.invert { 1 << 4 } else { 0 };
+                self.write8(clk_control, (control & !(1 << 4)) | invert)?;
+            }
+            self.set_phase_offset(index, output.phase)?;
+            self.enable_output(index, output.enabled)?;
+            disable_state |= (output.disable_state as u8) << (2 * index);
+        }
+        // Keep the disable state of CLK3
+        let reg = Registers::CLK3_0DisableState as u8;
+        let mut regval = 0;
+        self.read8(reg, &mut regval)?;
+        self.write8(reg, (regval & 0xc0) | disable_state)?;
+        match config.spread_spectrum {
+            Some(spread) => self.setup_spread_spectrum(spread),
+            None => self.enable_spread_spectrum(false),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::DeviceConfig;
+    use crate::testing::RegisterFile;
+
+    fn driver() -> Si5351<RegisterFile> {
+        Si5351::new(RegisterFile::new(), DeviceConfig::default()).unwrap()
+    }
+
+    /// PLL A at 25MHz * 32.5 driving CLK0
+    fn config(spread_spectrum: Option<SpreadSpectrum>) -> ChipConfig {
+        let mut config = ChipConfig {
+            spread_spectrum,
+            ..ChipConfig::default()
+        };
+        config.plls[0] = Some(PllConfig {
+            mult: 32,
+            num: 500_000,
+            denom: 1_000_000,
+        });
+        config.outputs[0] = OutputConfig {
+            freq: Some(OutputFreq::Hz(10_000_000)),
+            enabled: true,
+            ..OutputConfig::default()
+        };
+        config
+    }
+
+    fn spread_registers(clock_gen: &Si5351<RegisterFile>) -> &[u8] {
+        &clock_gen.i2c_dev.registers()[149..=161]
+    }
+
+    #[test]
+    fn applies_down_spread() {
+        let mut clock_gen = driver();
+        let spread = SpreadSpectrum {
+            amplitude: 150,
+            mode: SpreadMode::Down,
+        };
+        clock_gen.apply(&config(Some(spread))).unwrap();
+        // SSDN = 64 * 32.5 * 1.5% / (1.015 * 198) = 0 + 5086 / 32767,
+        // SSUDP = 25MHz / (4 * 31.5kHz) = 198, no up step
+        assert_eq!(
+            spread_registers(&clock_gen),
+            [
+                0x93, 0xde, 0x7f, 0xff, 0x00, 0x00, 0xc6, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00
+            ]
+        );
+    }
+
+    #[test]
+    fn applies_center_spread() {
+        let mut clock_gen = driver();
+        let spread = SpreadSpectrum {
+            amplitude: 150,
+            mode: SpreadMode::Center,
+        };
+        clock_gen.apply(&config(Some(spread))).unwrap();
+        // SSDN = 128 * 32.5 * 1.5% / (1.015 * 198) = 0 + 10173 / 32767,
+        // SSUP = 128 * 32.5 * 1.5% / (0.985 * 198) = 0 + 10483 / 32767
+        assert_eq!(
+            spread_registers(&clock_gen),
+            [
+                0xa7, 0xbd, 0xff, 0xff, 0x00, 0x00, 0xc6, 0x28, 0xf3, 0x7f, 0xff, 0x00, 0x00
+            ]
+        );
+    }
+
+    #[test]
+    fn disables_spread() {
+        let mut clock_gen = driver();
+        let spread = SpreadSpectrum {
+            amplitude: 50,
+            mode: SpreadMode::Down,
+        };
+        clock_gen.apply(&config(Some(spread))).unwrap();
+        assert_eq!(clock_gen.i2c_dev.registers()[149] & 0x80, 0x80);
+        clock_gen.apply(&config(None)).unwrap();
+        assert_eq!(clock_gen.i2c_dev.registers()[149] & 0x80, 0);
+    }
+
+    #[test]
+    fn rejects_invalid_spread() {
+        let mut clock_gen = driver();
+        for amplitude in [0, 9, 151] {
+            let spread = SpreadSpectrum {
+                amplitude,
+                mode: SpreadMode::Center,
+            };
+            assert_eq!(
+                clock_gen.apply(&config(Some(spread))),
+                Err(Error::InvalidParameter)
+            );
+        }
+        let spread = SpreadSpectrum {
+            amplitude: 100,
+            mode: SpreadMode::Down,
+        };
+        assert_eq!(
+            clock_gen.setup_spread_spectrum(spread),
+            Err(Error::InvalidParameter)
+        );
+    }
+}
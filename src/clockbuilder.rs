@@ -0,0 +1,273 @@
+//! Reading and writing the register map exports of ClockBuilder Pro.
+//!
+//! Both the "Register Map" text export and the C header export describe a
+//! list of `(address, value)` pairs which can be handed straight to
+//! [`Si5351::apply_register_map`](crate::Si5351::apply_register_map).
+//! The parsers need the `alloc` feature, the writers work with any
+//! [`core::fmt::Write`] sink so the driver state from
+//! [`Si5351::register_map`](crate::Si5351::register_map) can be exported on
+//! the device itself.
+
+#[cfg(feature = "alloc")]
+use alloc::{string::String, vec::Vec};
+use core::fmt::{self, Write};
+
+#[cfg(feature = "alloc")]
+use crate::{Error, LAST_REGISTER};
+
+/// Writes `(address, value)` pairs in the layout of the ClockBuilder Pro
+/// "Register Map" text export.
+pub fn write_register_map<W, R>(out: &mut W, regs: R) -> fmt::Result
+where
+    W: Write,
+    R: IntoIterator<Item = (u8, u8)>,
+{
+    out.write_str("# Si5351A Rev B Register Map\n")?;
+    out.write_str("#\n")?;
+    out.write_str("# This file represents a series of Silicon Labs Si5351A Rev B\n")?;
+    out.write_str("# register writes that can be performed to load a single configuration\n")?;
+    out.write_str("# on a device.\n")?;
+    out.write_str("#\n")?;
+    out.write_str("Address,Data\n")?;
+    for (address, value) in regs {
+        writeln!(out, "{},{:02X}h", address, value)?;
+    }
+    Ok(())
+}
+
+/// Writes `(address, value)` pairs in the layout of the ClockBuilder Pro C
+/// header export.
+pub fn write_c_header<W, R>(out: &mut W, regs: R) -> fmt::Result
+where
+    W: Write,
+    R: IntoIterator<Item = (u8, u8)>,
+    R::IntoIter: Clone,
+{
+    let regs = regs.into_iter();
+    out.write_str("/*\n")?;
+    out.write_str(" * Si5351A Rev B Configuration Register Export Header File\n")?;
+    out.write_str(" *\n")?;
+    out.write_str(" * This file represents a series of Silicon Labs Si5351A Rev B\n")?;
+    out.write_str(" * register writes that can be performed to load a single configuration\n")?;
+    out.write_str(" * on a device.\n")?;
+    out.write_str(" */\n\n")?;
+    out.write_str("#ifndef SI5351A_REVB_REG_CONFIG_HEADER\n")?;
+    out.write_str("#define SI5351A_REVB_REG_CONFIG_HEADER\n\n")?;
+    writeln!(
+        out,
+        "#define SI5351A_REVB_REG_CONFIG_NUM_REGS\t\t\t\t{}\n",
+        regs.clone().count()
+    )?;
+    out.write_str("typedef struct\n{\n")?;
+    out.write_str("\tunsigned int address; /* 16-bit register address */\n")?;
+    out.write_str("\tunsigned char value; /* 8-bit register data */\n\n")?;
+    out.write_str("} si5351a_revb_register_t;\n\n")?;
+    out.write_str("si5351a_revb_register_t const si5351a_revb_registers[SI5351A_REVB_REG_CONFIG_NUM_REGS] =\n{\n")?;
+    for (address, value) in regs {
+        writeln!(out, "\t{{ 0x{:04X}, 0x{:02X} }},", address, value)?;
+    }
+    out.write_str("};\n\n")?;
+    out.write_str("#endif\n")
+}
+
+/// Parses the ClockBuilder Pro "Register Map" text export.
+///
+/// The export looks like this:
+///
+/// ```text
+/// # Si5351A Rev B Register Map
+/// #
+/// Address,Data
+/// 2,53h
+/// 3,00h
+/// 15,00h
+/// ```
+///
+/// Comment lines starting with `#`, blank lines and the `Address,Data`
+/// heading are skipped. Values may be written as `53h`, `0x53` or in decimal.
+#[cfg(feature = "alloc")]
+pub fn parse_register_map(text: &str) -> Result<Vec<(u8, u8)>, Error> {
+    let mut regs = Vec::new();
+    for line in text.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (address, value) = line.split_once(',').ok_or(Error::InvalidRegisterMap)?;
+        if address.trim().eq_ignore_ascii_case("address") {
+            continue;
+        }
+        regs.push(parse_pair(address, value)?);
+    }
+    check_not_empty(regs)
+}
+
+/// Parses the ClockBuilder Pro C header export.
+///
+/// Every `{ address, value }` initialiser of the register array is
+/// collected, e.g.:
+///
+/// ```text
+/// si5351a_revb_register_t const si5351a_revb_registers[SI5351A_REVB_REG_CONFIG_NUM_REGS] =
+/// {
+///     { 0x0002, 0x53 },
+///     { 0x0003, 0x00 },
+/// };
+/// ```
+///
+/// Comments are removed first, also between the braces of an initialiser.
+/// Braces that do not contain exactly two numbers, such as the `typedef`
+/// of the register struct, are ignored.
+#[cfg(feature = "alloc")]
+pub fn parse_c_header(text: &str) -> Result<Vec<(u8, u8)>, Error> {
+    let mut regs = Vec::new();
+    let text = strip_comments(text);
+    let mut rest = text.as_str();
+    while let Some(open) = rest.find('{') {
+        rest = &rest[open + 1..];
+        let Some(close) = rest.find(['{', '}']) else {
+            break;
+        };
+        let body = &rest[..close];
+        if let Some((address, value)) = body.split_once(',') {
+            let value = value.trim_end().trim_end_matches(',');
+            if is_number(address) && is_number(value) {
+                regs.push(parse_pair(address, value)?);
+            }
+        }
+    }
+    check_not_empty(regs)
+}
+
+#[cfg(feature = "alloc")]
+fn check_not_empty(regs: Vec<(u8, u8)>) -> Result<Vec<(u8, u8)>, Error> {
+    if regs.is_empty() {
+        Err(Error::InvalidRegisterMap)
+    } else {
+        Ok(regs)
+    }
+}
+
+#[cfg(feature = "alloc")]
+fn parse_pair(address: &str, value: &str) -> Result<(u8, u8), Error> {
+    let address = parse_number(address)?;
+    let value = parse_number(value)?;
+    if address > LAST_REGISTER as u32 {
+        return Err(Error::AddressOutOfRange);
+    }
+    let value = u8::try_from(value).map_err(|_| Error::InvalidRegisterMap)?;
+    Ok((address as u8, value))
+}
+
+/// Parses `0x53`, `53h` or `83`
+#[cfg(feature = "alloc")]
+fn parse_number(text: &str) -> Result<u32, Error> {
+    let text = text.trim();
+    let (digits, radix) = if let Some(hex) = text.strip_prefix("0x").or(text.strip_prefix("0X")) {
+        (hex, 16)
+    } else if let Some(hex) = text.strip_suffix('h').or(text.strip_suffix('H')) {
+        (hex, 16)
+    } else {
+        (text, 10)
+    };
+    u32::from_str_radix(digits, radix).map_err(|_| Error::InvalidRegisterMap)
+}
+
+#[cfg(feature = "alloc")]
+fn is_number(text: &str) -> bool {
+    parse_number(text).is_ok()
+}
+
+/// Removes `/* ... */` comments, an unterminated one up to the end of the
+/// text. Every comment is replaced by a space, as in C.
+#[cfg(feature = "alloc")]
+fn strip_comments(text: &str) -> String {
+    let mut stripped = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find("/*") {
+        stripped.push_str(&rest[..start]);
+        stripped.push(' ');
+        match rest[start + 2..].find("*/") {
+            Some(end) => rest = &rest[start + 2 + end + 2..],
+            None => return stripped,
+        }
+    }
+    stripped.push_str(rest);
+    stripped
+}
+
+#[cfg(all(test, feature = "alloc"))]
+mod tests {
+    use super::*;
+
+    /// The registers of both exports in `testdata`
+    const EXPORTED: [(u8, u8); 12] = [
+        (2, 0x53),
+        (3, 0x00),
+        (16, 0x4f),
+        (17, 0x4f),
+        (18, 0x6f),
+        (26, 0x00),
+        (27, 0x05),
+        (28, 0x00),
+        (149, 0x00),
+        (165, 0x00),
+        (177, 0xac),
+        (183, 0x92),
+    ];
+
+    #[test]
+    fn parses_c_header_export() {
+        let text = include_str!("../testdata/si5351a_revb_registers.h");
+        assert_eq!(parse_c_header(text), Ok(EXPORTED.to_vec()));
+    }
+
+    #[test]
+    fn parses_register_map_export() {
+        let text = include_str!("../testdata/si5351a_revb_registers.txt");
+        assert_eq!(parse_register_map(text), Ok(EXPORTED.to_vec()));
+    }
+
+    #[test]
+    fn keeps_rows_with_comments() {
+        let text = "{ /* CLK0 */ 0x0010, 0x4F }, { 0x0011, /* a } b */ 0x4F },";
+        assert_eq!(parse_c_header(text), Ok(vec![(16, 0x4f), (17, 0x4f)]));
+    }
+
+    #[test]
+    fn strips_comments() {
+        assert_eq!(strip_comments("a/* b */c/**/d"), "a c d");
+        assert_eq!(strip_comments("a /* b"), "a  ");
+        assert_eq!(strip_comments("a */ b"), "a */ b");
+    }
+
+    #[test]
+    fn rejects_exports_without_registers() {
+        assert_eq!(
+            parse_c_header("/* { 0x0002, 0x53 }, */"),
+            Err(Error::InvalidRegisterMap)
+        );
+        assert_eq!(
+            parse_register_map("# Si5351A\nAddress,Data\n"),
+            Err(Error::InvalidRegisterMap)
+        );
+    }
+
+    #[test]
+    fn register_map_has_text_export_title() {
+        let mut map = String::new();
+        write_register_map(&mut map, EXPORTED).unwrap();
+        assert!(map.starts_with("# Si5351A Rev B Register Map\n"));
+        assert!(!map.contains("Header File"));
+    }
+
+    #[test]
+    fn writers_round_trip() {
+        let mut header = String::new();
+        write_c_header(&mut header, EXPORTED).unwrap();
+        assert_eq!(parse_c_header(&header), Ok(EXPORTED.to_vec()));
+        let mut map = String::new();
+        write_register_map(&mut map, EXPORTED).unwrap();
+        assert_eq!(parse_register_map(&map), Ok(EXPORTED.to_vec()));
+    }
+}
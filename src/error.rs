@@ -0,0 +1,86 @@
+//! The error type of the driver
+
+// The derives match on the deprecated `Error::I2CTimeOut`
+#![allow(deprecated)]
+
+use core::fmt;
+
+use embedded_hal::i2c::{ErrorKind, NoAcknowledgeSource};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u16)]
+pub enum Error {
+    OperationTimeOut = 0x1,
+    AddressOutOfRange = 0x2,
+    BufferOverflow = 0x3,
+    InvalidParameter = 0x4,
+    DeviceNotInitialsed = 0x5,
+    /// A register read back in verify mode differs from what was written
+    UnexpectedValue {
+        /// The register address
+        address: u8,
+        /// The value written
+        expected: u8,
+        /// The value read back
+        read: u8,
+    } = 0x6,
+    InvalidRegisterMap = 0x7,
+    PllConflict = 0x8,
+    /// The output has already been handed out by
+    /// [`Si5351::split`](crate::Si5351::split)
+    OutputTaken = 0x9,
+    I2CDeviceNotFound = 0x101,
+    I2CNoACK = 0x102,
+    /// Never returned, kept until the next major release so that matches
+    /// on it still compile. Timeouts arrive as `I2CTransaction`.
+    #[deprecated(note = "never returned, timeouts arrive as `Error::I2CTransaction`")]
+    I2CTimeOut = 0x103,
+    /// Any other bus error, with its kind. embedded-hal has no kind for
+    /// timeouts, they usually arrive as `ErrorKind::Other`
+    I2CTransaction(
+        #[cfg_attr(feature = "serde", serde(with = "crate::serde_impls::error_kind"))] ErrorKind,
+    ) = 0x104,
+}
+
+impl From<ErrorKind> for Error {
+    fn from(kind: ErrorKind) -> Self {
+        match kind {
+            ErrorKind::NoAcknowledge(NoAcknowledgeSource::Address) => Error::I2CDeviceNotFound,
+            ErrorKind::NoAcknowledge(_) => Error::I2CNoACK,
+            kind => Error::I2CTransaction(kind),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::OperationTimeOut => f.write_str("operation timed out"),
+            Error::AddressOutOfRange => f.write_str("register address out of range"),
+            Error::BufferOverflow => f.write_str("buffer too small"),
+            Error::InvalidParameter => f.write_str("invalid parameter"),
+            Error::DeviceNotInitialsed => f.write_str("device not initialised"),
+            Error::UnexpectedValue {
+                address,
+                expected,
+                read,
+            } => write!(
+                f,
+                "register {address} reads {read:#04x}, {expected:#04x} was written"
+            ),
+            Error::InvalidRegisterMap => f.write_str("invalid register map"),
+            Error::PllConflict => f.write_str("PLL in use by another output"),
+            Error::OutputTaken => f.write_str("output already handed out"),
+            Error::I2CDeviceNotFound => {
+                f.write_str("I2C address not acknowledged, device not found")
+            }
+            Error::I2CNoACK => f.write_str("I2C data not acknowledged"),
+            Error::I2CTimeOut => f.write_str("I2C timeout"),
+            Error::I2CTransaction(kind) => write!(f, "I2C transaction failed: {kind}"),
+        }
+    }
+}
+
+impl core::error::Error for Error {}
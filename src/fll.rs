@@ -0,0 +1,450 @@
+//! Software frequency locked loop, e.g. to discipline the board to a GPS
+//! 1PPS signal.
+//!
+//! The reference correction of the driver ([`Si5351::set_correction`])
+//! tells it how far the crystal is off. The PLLs in use are moved to
+//! compensate through their fractional numerators, without a PLL reset.
+//! [`Fll`] closes the loop: it compares edge counts of an output, measured
+//! by the MCU over a known gate time, with the expected count and runs a PI
+//! controller on the frequency error.
+//!
+//! ```no_run
+//! # fn example<I2C: embedded_hal::i2c::I2c>(
+//! #     clock_gen: &mut si5351a_adafruit::Si5351<I2C>,
+//! #     mut count_clk0_edges: impl FnMut() -> u64,
+//! # ) -> Result<(), si5351a_adafruit::Error> {
+//! use si5351a_adafruit::{Fll, FllConfig, PLL};
+//!
+//! clock_gen.set_freq(0, PLL::A, 10_000_000)?;
+//! let mut fll = Fll::new(0, FllConfig::default());
+//! loop {
+//!     // Edges of CLK0 between two 1PPS pulses
+//!     let count = count_clk0_edges();
+//!     fll.update(clock_gen, count, 1)?;
+//!     if fll.is_locked() {
+//!         // ...
+//!     }
+//! }
+//! # }
+//! ```
+
+use embedded_hal::delay::DelayNs;
+use embedded_hal::i2c::I2c;
+
+use crate::freqplan::{FRAC_DENOM, Ratio};
+use crate::{Error, Observer, PLL, Si5351, check};
+
+/// Largest reference correction, in parts per trillion (±1000ppm)
+const MAX_CORRECTION_PPT: i64 = 1_000_000_000;
+
+/// Settings of the [`Fll`] controller
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct FllConfig {
+    /// Proportional gain, correction in ppb per ppb of error
+    pub kp: f64,
+    /// Integral gain, correction in ppb per ppb of error and update
+    pub ki: f64,
+    /// The loop counts as locked while the error stays below this (ppb).
+    /// Keep it above the PLL numerator step, which the loop dithers over.
+    pub lock_threshold_ppb: f64,
+    /// Number of consecutive updates below the threshold before the loop
+    /// counts as locked
+    pub lock_count: u32,
+    /// The correction is limited to ± this (ppb)
+    pub max_correction_ppb: f64,
+}
+
+impl Default for FllConfig {
+    fn default() -> Self {
+        Self {
+            kp: 0.1,
+            ki: 0.4,
+            lock_threshold_ppb: 50.0,
+            lock_count: 8,
+            max_correction_ppb: 100_000.0,
+        }
+    }
+}
+
+/// A PI controller disciplining the reference correction of the driver to
+/// edge counts of an output
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Fll {
+    output: usize,
+    config: FllConfig,
+    target: Option<u64>,
+    integrator: f64,
+    correction_ppb: f64,
+    error_ppb: f64,
+    /// Rounding of the PLL numerator carried over to the next update
+    dither_ppt: i64,
+    in_threshold: u32,
+    locked: bool,
+}
+
+impl Fll {
+    /// A new controller, starting unlocked with no correction
+    ///
+    /// output: The output channel the MCU counts (0..2)
+    ///
+    /// config: The loop settings
+    pub fn new(output: usize, config: FllConfig) -> Self {
+        Self {
+            output,
+            config,
+            target: None,
+            integrator: 0.0,
+            correction_ppb: 0.0,
+            error_ppb: 0.0,
+            dither_ppt: 0,
+            in_threshold: 0,
+            locked: false,
+        }
+    }
+
+    /// Feeds a measurement into the loop and applies the new correction.
+    ///
+    /// The expected count is the output frequency at the first update
+    /// (after [`Fll::new`] or [`Fll::reset`]) times the gate time, so the
+    /// loop also takes out the rounding of the PLL ratios. Counting over
+    /// longer gates reduces the ±1 count quantisation, e.g. 10MHz over 100s
+    /// resolves 1ppb.
+    ///
+    /// The PLL numerator moves the frequency in steps of about 26 to 30ppb
+    /// (one part in 2^20 of the multiplier). The rounding of each update is
+    /// carried over to the next one, so the correction dithers between
+    /// neighbouring steps and its average over a number of gates resolves
+    /// well below 1ppb. A single gate can still be off by half a step.
+    ///
+    /// If the correction cannot be written, the controller state is left as
+    /// it was, so the next update starts from the correction last applied.
+    ///
+    /// si5351: The driver, with the output configured
+    ///
+    /// count: The number of output edges counted during the gate
+    ///
+    /// gate_seconds: The gate time in seconds
+    pub fn update<I2C: I2c, DELAY: DelayNs, OBS: Observer>(
+        &mut self,
+        si5351: &mut Si5351<I2C, DELAY, OBS>,
+        count: u64,
+        gate_seconds: u32,
+    ) -> Result<(), Error> {
+        check(gate_seconds > 0, Error::InvalidParameter)?;
+        let freq = match self.target {
+            Some(freq) => freq,
+            None => si5351
+                .output_freq_millihertz(self.output)
+                .ok_or(Error::InvalidParameter)?,
+        };
+        self.target = Some(freq);
+        let expected = freq as f64 * gate_seconds as f64 / 1000.0;
+        // A positive error means the crystal runs faster than assumed
+        let error_ppb = (count as f64 - expected) / expected * 1e9;
+        self.error_ppb = error_ppb;
+
+        let max = self.config.max_correction_ppb;
+        let integrator = clamp(self.integrator + self.config.ki * error_ppb, max);
+        let correction_ppb = clamp(integrator + self.config.kp * error_ppb, max);
+        let ppt = (round(correction_ppb * 1000.0) + self.dither_ppt)
+            .clamp(-MAX_CORRECTION_PPT, MAX_CORRECTION_PPT);
+        si5351.set_correction(ppt)?;
+        // Only integrate errors the device now corrects, so failed writes
+        // don't wind the integrator up
+        self.integrator = integrator;
+        self.correction_ppb = correction_ppb;
+        // The PLL runs too fast by the rounding of its numerator, which is
+        // the same as too little correction: add it to the next update
+        let pll = si5351.outputs[self.output].pll;
+        self.dither_ppt = si5351.correction_error_ppt(pll).unwrap_or(0);
+
+        let threshold = self.config.lock_threshold_ppb;
+        if -threshold < error_ppb && error_ppb < threshold {
+            self.in_threshold = self.in_threshold.saturating_add(1);
+            if self.in_threshold >= self.config.lock_count {
+                self.locked = true;
+            }
+        } else {
+            self.in_threshold = 0;
+            self.locked = false;
+        }
+        Ok(())
+    }
+
+    /// Whether the error has stayed below the lock threshold for the
+    /// configured number of updates
+    pub fn is_locked(&self) -> bool {
+        self.locked
+    }
+
+    /// The correction currently applied to the reference, in ppb
+    pub fn correction_ppb(&self) -> f64 {
+        self.correction_ppb
+    }
+
+    /// The frequency error of the last measurement, in ppb
+    pub fn error_ppb(&self) -> f64 {
+        self.error_ppb
+    }
+
+    /// Clears the loop state, e.g. after losing the 1PPS signal or retuning
+    /// the output. The correction applied to the driver is left as it is,
+    /// the next update takes the output frequency as the new target.
+    pub fn reset(&mut self) {
+        self.target = None;
+        self.integrator = self.correction_ppb;
+        self.in_threshold = 0;
+        self.locked = false;
+    }
+}
+
+/// Limits `value` to ±`max`
+fn clamp(value: f64, max: f64) -> f64 {
+    value.max(-max).min(max)
+}
+
+/// Rounds to the nearest integer (`core` has no `f64::round`)
+fn round(value: f64) -> i64 {
+    if value < 0.0 {
+        (value - 0.5) as i64
+    } else {
+        (value + 0.5) as i64
+    }
+}
+
+impl<I2C: I2c, DELAY: DelayNs, OBS: Observer> Si5351<I2C, DELAY, OBS> {
+    /// Sets the reference correction: how far the crystal is off its
+    /// nominal frequency, in parts per trillion (positive if it runs fast).
+    ///
+    /// The configured PLLs are moved to compensate by rewriting only their
+    /// changed fractional parameters, without a PLL reset. The fast retune
+    /// helpers, hop tables, sweeps and quadrature oscillators take the
+    /// correction into account, [`Si5351::set_freq`] and the raw setup
+    /// functions program nominal ratios.
+    ///
+    /// Returns `Error::InvalidParameter` without writing anything if a PLL
+    /// multiplier would end up out of range. If a write fails, the
+    /// correction keeps its previous value; calling this again completes
+    /// the move of the PLLs.
+    ///
+    /// ppt: The crystal frequency error in parts per trillion (±1000ppm)
+    pub fn set_correction(&mut self, ppt: i64) -> Result<(), Error> {
+        check(
+            (-MAX_CORRECTION_PPT..=MAX_CORRECTION_PPT).contains(&ppt),
+            Error::InvalidParameter,
+        )?;
+        let old_xtal = self.crystal_millihertz();
+        let new_xtal = self.crystal_millihertz_at(ppt);
+        // Work out both PLLs before writing anything, so an out of range
+        // multiplier leaves the device and the driver state alone
+        let mut corrections = [None; 2];
+        for (pll, configured) in [
+            (PLL::A, self.config.plla_configured),
+            (PLL::B, self.config.pllb_configured),
+        ] {
+            if !configured {
+                continue;
+            }
+            // Keep the PLL frequency with the new crystal. Start from the
+            // unrounded frequency if the ratio is still the one written
+            // here, so small corrections add up instead of rounding away.
+            let ratio = self.pll_ratio(pll);
+            let (numerator, vco_denominator) = match self.config.corrected_plls[pll as usize] {
+                Some((corrected, numerator, denominator)) if corrected == ratio => {
+                    (numerator, denominator)
+                }
+                _ => (
+                    old_xtal * (ratio.a as u128 * ratio.c as u128 + ratio.b as u128),
+                    ratio.c as u128,
+                ),
+            };
+            let denominator = new_xtal * vco_denominator;
+            let mut mult = (numerator / denominator) as u32;
+            let mut num = (((numerator % denominator) * FRAC_DENOM as u128 + denominator / 2)
+                / denominator) as u32;
+            if num == FRAC_DENOM {
+                mult += 1;
+                num = 0;
+            }
+            check((15..=90).contains(&mult), Error::InvalidParameter)?;
+            let corrected = Ratio::new(mult, num, FRAC_DENOM);
+            corrections[pll as usize] = Some((pll, (corrected, numerator, vco_denominator)));
+        }
+        for (pll, corrected) in corrections.into_iter().flatten() {
+            self.write_pll_ratio(pll, corrected.0)?;
+            // The PLL now runs corrected, even if writing the other one
+            // fails: its unrounded frequency no longer depends on the
+            // correction below
+            self.config.corrected_plls[pll as usize] = Some(corrected);
+        }
+        self.config.correction_ppt = ppt;
+        Ok(())
+    }
+
+    /// How far a PLL corrected by [`Si5351::set_correction`] is off the
+    /// frequency it should run at, in parts per trillion, due to the
+    /// rounding of its numerator. `None` if the PLL has not been corrected
+    /// since it was last set up.
+    pub(crate) fn correction_error_ppt(&self, pll: PLL) -> Option<i64> {
+        let (ratio, numerator, vco_denominator) = self.config.corrected_plls[pll as usize]?;
+        if ratio != self.pll_ratio(pll) {
+            return None;
+        }
+        let actual = (self.crystal_millihertz()
+            * (ratio.a as u128 * ratio.c as u128 + ratio.b as u128)
+            * vco_denominator) as i128;
+        let wanted = (numerator * ratio.c as u128) as i128;
+        Some(((actual - wanted) * 1_000_000_000_000 / wanted) as i64)
+    }
+
+    /// The reference correction in parts per trillion
+    pub fn correction(&self) -> i64 {
+        self.config.correction_ppt
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{FaultyI2c, RegisterFile};
+    use crate::{DeviceConfig, RetryPolicy};
+
+    fn driver() -> Si5351<FaultyI2c> {
+        let config = DeviceConfig {
+            retry: RetryPolicy {
+                retries: 0,
+                ..RetryPolicy::default()
+            },
+            ..DeviceConfig::default()
+        };
+        Si5351::new(FaultyI2c::new(RegisterFile::new()), config).unwrap()
+    }
+
+    /// The PLL multiplier relative to `reference`, in ppb
+    fn offset_ppb(ratio: Ratio, reference: Ratio) -> f64 {
+        let value = |r: Ratio| r.a as f64 + r.b as f64 / r.c as f64;
+        (value(ratio) / value(reference) - 1.0) * 1e9
+    }
+
+    #[test]
+    fn moves_plls_against_correction() {
+        let mut clock_gen = driver();
+        clock_gen.set_freq(0, PLL::A, 10_000_000).unwrap();
+        let nominal = clock_gen.pll_ratio(PLL::A);
+        clock_gen.set_correction(2_000_000).unwrap();
+        assert_eq!(clock_gen.correction(), 2_000_000);
+        // A crystal 2ppm fast needs a 2ppm lower multiplier
+        let offset = offset_ppb(clock_gen.pll_ratio(PLL::A), nominal);
+        assert!((offset + 2000.0).abs() < 30.0, "{offset}");
+        assert!(clock_gen.correction_error_ppt(PLL::A).unwrap().abs() < 15_000);
+        assert_eq!(clock_gen.correction_error_ppt(PLL::B), None);
+    }
+
+    #[test]
+    fn leaves_state_alone_on_invalid_multiplier() {
+        let mut clock_gen = driver();
+        clock_gen.set_freq(0, PLL::A, 10_000_000).unwrap();
+        clock_gen.setup_pll(PLL::B, 90, 999_000, 1_000_000).unwrap();
+        let registers = clock_gen.i2c_dev.inner().registers().to_owned();
+        let ratio = clock_gen.pll_ratio(PLL::A);
+        // PLL B would need a multiplier above 90
+        assert_eq!(
+            clock_gen.set_correction(-MAX_CORRECTION_PPT),
+            Err(Error::InvalidParameter)
+        );
+        assert_eq!(clock_gen.correction(), 0);
+        assert_eq!(clock_gen.pll_ratio(PLL::A), ratio);
+        assert_eq!(clock_gen.i2c_dev.inner().registers(), &registers);
+        assert_eq!(
+            clock_gen.set_correction(MAX_CORRECTION_PPT + 1),
+            Err(Error::InvalidParameter)
+        );
+    }
+
+    #[test]
+    fn keeps_state_consistent_after_failed_write() {
+        let mut expected = driver();
+        expected.set_freq(0, PLL::A, 10_000_000).unwrap();
+        expected.set_freq(1, PLL::B, 14_000_000).unwrap();
+        expected.set_correction(1_500_000).unwrap();
+
+        let mut clock_gen = driver();
+        clock_gen.set_freq(0, PLL::A, 10_000_000).unwrap();
+        clock_gen.set_freq(1, PLL::B, 14_000_000).unwrap();
+        // The correction only changes the lowest P2 byte of PLL B
+        clock_gen.i2c_dev = clock_gen.i2c_dev.clone().nak_on_register(40, 1);
+        assert_eq!(clock_gen.set_correction(1_500_000), Err(Error::I2CNoACK));
+        // PLL A was written, the correction is not committed
+        assert_eq!(clock_gen.pll_ratio(PLL::A), expected.pll_ratio(PLL::A));
+        assert_eq!(clock_gen.correction(), 0);
+        clock_gen.set_correction(1_500_000).unwrap();
+        assert_eq!(clock_gen.correction(), 1_500_000);
+        for pll in [PLL::A, PLL::B] {
+            assert_eq!(clock_gen.pll_ratio(pll), expected.pll_ratio(pll));
+        }
+        assert_eq!(
+            clock_gen.i2c_dev.inner().registers(),
+            expected.i2c_dev.inner().registers()
+        );
+    }
+
+    #[test]
+    fn dithers_below_numerator_step() {
+        let mut clock_gen = driver();
+        clock_gen.set_freq(0, PLL::A, 10_000_000).unwrap();
+        let nominal = clock_gen.pll_ratio(PLL::A);
+        let freq = clock_gen.output_freq_millihertz(0).unwrap();
+        // Proportional only, so every update asks for the same 10ppb
+        let config = FllConfig {
+            kp: 1.0,
+            ki: 0.0,
+            ..FllConfig::default()
+        };
+        let mut fll = Fll::new(0, config);
+        let count = freq / 10 + freq / 10 / 100_000_000;
+        let mut sum = 0.0;
+        let mut steps = [false; 2];
+        for _ in 0..100 {
+            fll.update(&mut clock_gen, count, 100).unwrap();
+            assert!((fll.error_ppb() - 10.0).abs() < 0.01);
+            let offset = -offset_ppb(clock_gen.pll_ratio(PLL::A), nominal);
+            steps[(offset > 5.0) as usize] = true;
+            sum += offset;
+        }
+        // 10ppb is less than half a numerator step: the PLL alternates
+        // between two steps, averaging out to the wanted correction
+        assert_eq!(steps, [true, true]);
+        let average = sum / 100.0;
+        assert!((average - 10.0).abs() < 0.5, "{average}");
+    }
+
+    #[test]
+    fn does_not_integrate_unapplied_corrections() {
+        let mut expected = driver();
+        expected.set_freq(0, PLL::A, 10_000_000).unwrap();
+        let mut expected_fll = Fll::new(0, FllConfig::default());
+
+        let mut clock_gen = driver();
+        clock_gen.set_freq(0, PLL::A, 10_000_000).unwrap();
+        let mut fll = Fll::new(0, FllConfig::default());
+        let freq = clock_gen.output_freq_millihertz(0).unwrap();
+        // 1ppm fast
+        let count = freq / 1000 + freq / 1000 / 1_000_000;
+        // NAK every PLL write of three updates
+        let next = clock_gen.i2c_dev.transactions();
+        clock_gen.i2c_dev = clock_gen.i2c_dev.clone().nak_on_transaction(next, 3);
+        for _ in 0..3 {
+            assert_eq!(fll.update(&mut clock_gen, count, 1), Err(Error::I2CNoACK));
+            assert_eq!(fll.correction_ppb(), 0.0);
+            assert_eq!(clock_gen.correction(), 0);
+        }
+        fll.update(&mut clock_gen, count, 1).unwrap();
+        expected_fll.update(&mut expected, count, 1).unwrap();
+        assert_eq!(fll, expected_fll);
+        assert_eq!(clock_gen.correction(), expected.correction());
+        assert_eq!(clock_gen.pll_ratio(PLL::A), expected.pll_ratio(PLL::A));
+    }
+}
@@ -0,0 +1,499 @@
+//! Frequency plans computed with integer arithmetic only.
+//!
+//! Everything in here is a `const fn`, so fixed frequency plans can be
+//! computed and checked at compile time with the [`plan!`](crate::plan!)
+//! macro and applied with a single burst write by
+//! [`Si5351::apply_plan`](crate::Si5351::apply_plan):
+//!
+//! ```
+//! use si5351a_adafruit::{freqplan::Plan, plan};
+//!
+//! const PLAN: Plan = plan!(CLK0 = 12_288_000, CLK1 = 24_576_000);
+//! ```
+//!
+//! A frequency that cannot be generated is a compile error:
+//!
+//! ```compile_fail
+//! use si5351a_adafruit::{freqplan::Plan, plan};
+//!
+//! const PLAN: Plan = plan!(CLK0 = 200_000_000);
+//! ```
+
+use crate::{CrystalFreq, Error, PLL, RDiv};
+
+/// Denominator used for fractional PLL and multisynth settings
+pub(crate) const FRAC_DENOM: u32 = 1_048_575;
+
+/// Lowest allowed PLL (VCO) frequency
+pub const VCO_MIN: u32 = 600_000_000;
+
+/// Highest allowed PLL (VCO) frequency
+pub const VCO_MAX: u32 = 900_000_000;
+
+/// Address of the first register of a plan image
+pub const PLAN_START: u8 = 15;
+
+/// Number of registers in a plan image (registers 15-65)
+pub const PLAN_LEN: usize = 65 - 15 + 1;
+
+/// Encodes the divider `a + b / c` into the P1, P2 and P3 register values
+///
+/// ```text
+/// P1[17:0] = 128 * a + floor(128 * (b / c)) - 512
+/// P2[19:0] = 128 * b - c * floor(128 * (b / c))
+/// P3[19:0] = c
+/// ```
+pub const fn encode_divider(a: u32, b: u32, c: u32) -> (u32, u32, u32) {
+    let ratio = (128 * b as u64 / c as u64) as u32;
+    (128 * a + ratio - 512, 128 * b - c * ratio, c)
+}
+
+/// Packs P1, P2 and P3 into the 8 register layout shared by the PLL and
+/// multisynth parameters. `rdiv_bits` is or'ed into the third register,
+/// which holds the R divider for the multisynths.
+pub const fn pack_parameters(p1: u32, p2: u32, p3: u32, rdiv_bits: u8) -> [u8; 8] {
+    [
+        ((p3 & 0xff00) >> 8) as u8,
+        (p3 & 0xff) as u8,
+        ((p1 & 0x30000) >> 16) as u8 | rdiv_bits,
+        ((p1 & 0xff00) >> 8) as u8,
+        (p1 & 0xff) as u8,
+        ((p3 & 0xf0000) >> 12) as u8 | ((p2 & 0xf0000) >> 16) as u8,
+        ((p2 & 0xff00) >> 8) as u8,
+        (p2 & 0xff) as u8,
+    ]
+}
+
+/// A divider or multiplier `a + b / c`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub(crate) struct Ratio {
+    pub(crate) a: u32,
+    pub(crate) b: u32,
+    pub(crate) c: u32,
+}
+
+impl Ratio {
+    pub(crate) const fn new(a: u32, b: u32, c: u32) -> Self {
+        Self { a, b, c }
+    }
+}
+
+/// The settings `set_freq` uses for a single output: a fractional PLL
+/// feeding an even integer multisynth and an R divider
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct FreqPlan {
+    pub(crate) mult: u32,
+    pub(crate) num: u32,
+    pub(crate) denom: u32,
+    pub(crate) ms_div: u32,
+    pub(crate) r_div: RDiv,
+}
+
+impl FreqPlan {
+    /// Finds the largest even multisynth divider (and the R divider needed
+    /// for low frequencies) that keeps the PLL at or below 900MHz, then
+    /// computes the fractional PLL multiplier for it.
+    pub(crate) const fn new(crystal_freq: u32, freq: u32) -> Result<Self, Error> {
+        if freq == 0 {
+            return Err(Error::InvalidParameter);
+        }
+        let total_divider = VCO_MAX / freq;
+        let r_div = match RDiv::min_divider(total_divider / 900) {
+            Ok(r_div) => r_div,
+            Err(e) => return Err(e),
+        };
+        let mut ms_div = total_divider / (2 * r_div.denominator_u8() as u32) * 2;
+        if ms_div < 6 {
+            ms_div = 6;
+        }
+        if ms_div > 1800 {
+            return Err(Error::InvalidParameter);
+        }
+        let pll_freq = freq as u64 * ms_div as u64 * r_div.denominator_u8() as u64;
+        let mult = (pll_freq / crystal_freq as u64) as u32;
+        if mult < 15 || mult > 90 {
+            return Err(Error::InvalidParameter);
+        }
+        let num =
+            ((pll_freq % crystal_freq as u64) * FRAC_DENOM as u64 / crystal_freq as u64) as u32;
+        Ok(Self {
+            mult,
+            num,
+            denom: FRAC_DENOM,
+            ms_div,
+            r_div,
+        })
+    }
+
+    /// The PLL frequency this plan runs at
+    pub(crate) const fn vco(&self, crystal_freq: u32) -> u32 {
+        pll_freq(crystal_freq, self.mult, self.num, self.denom)
+    }
+}
+
+/// Computes `fXTAL * (a + b / c)`
+pub(crate) const fn pll_freq(crystal_freq: u32, a: u32, b: u32, c: u32) -> u32 {
+    let xtal = crystal_freq as u64;
+    (xtal * a as u64 + xtal * b as u64 / c as u64) as u32
+}
+
+/// Multisynth divider `a + b / c` and R divider that divide the PLL
+/// frequency `vco_numerator / vco_denominator` down to `freq`, all in the
+/// same unit.
+///
+/// The R dividers from 2^`first_r` to 2^`last_r` are tried in turn, the
+/// first one which brings the multisynth divider below 2048 is used. The
+/// multisynth divider has to be at least 8, or exactly 6 if the caller
+/// switches the output to integer mode (`integer_ok`). Divide by 4 needs
+/// MSx_DIVBY4 and is never returned.
+pub(crate) const fn multisynth_divider(
+    vco_numerator: u128,
+    vco_denominator: u128,
+    freq: u64,
+    (first_r, last_r): (u8, u8),
+    integer_ok: bool,
+) -> Result<(Ratio, RDiv), Error> {
+    if freq == 0 || vco_denominator == 0 || last_r > 7 {
+        return Err(Error::InvalidParameter);
+    }
+    let mut shift = first_r;
+    while shift <= last_r {
+        let divisor = vco_denominator * ((freq as u128) << shift);
+        let a = vco_numerator / divisor;
+        if a < 2048 {
+            let b = (vco_numerator % divisor) * FRAC_DENOM as u128 / divisor;
+            if a < 8 && !(integer_ok && a == 6 && b == 0) {
+                return Err(Error::InvalidParameter);
+            }
+            let r_div = match RDiv::min_divider(1 << shift) {
+                Ok(r_div) => r_div,
+                Err(e) => return Err(e),
+            };
+            return Ok((Ratio::new(a as u32, b as u32, FRAC_DENOM), r_div));
+        }
+        shift += 1;
+    }
+    Err(Error::InvalidParameter)
+}
+
+/// A precomputed configuration of both PLLs and the three outputs.
+///
+/// Build it with [`Plan::new`] and [`Plan::clk`]/[`Plan::clk_on`], or with
+/// the [`plan!`](crate::plan!) macro. The first output assigned to a PLL sets
+/// its frequency the same way [`Si5351::set_freq`](crate::Si5351::set_freq)
+/// does (fractional PLL, even integer multisynth). Further outputs on the
+/// same PLL use a fractional multisynth.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Plan {
+    crystal_freq: u32,
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_impls::byte_array"))]
+    pub(crate) image: [u8; PLAN_LEN],
+    pub(crate) pll_freq: [u32; 2],
+    pub(crate) pll_ratio: [Ratio; 2],
+    pub(crate) outputs: [Option<(PLL, Ratio, RDiv)>; 3],
+    pub(crate) rdiv_bits: [u8; 3],
+    pub(crate) enabled: u8,
+    error: Option<Error>,
+}
+
+impl Plan {
+    /// Starts an empty plan for the given crystal, with all outputs
+    /// powered down
+    pub const fn new(crystal_freq: CrystalFreq) -> Self {
+        let mut image = [0; PLAN_LEN];
+        // Power down all output drivers (registers 16-23)
+        let mut i = 1;
+        while i <= 8 {
+            image[i] = 0x80;
+            i += 1;
+        }
+        Self {
+            crystal_freq: crystal_freq as u32,
+            image,
+            pll_freq: [0; 2],
+            pll_ratio: [Ratio::new(0, 0, 1); 2],
+            outputs: [None; 3],
+            rdiv_bits: [0; 3],
+            enabled: 0,
+            error: None,
+        }
+    }
+
+    /// Adds an output, using PLL A for the first output added, PLL B for the
+    /// second and sharing PLL A for the third.
+    pub const fn clk(self, output: usize, freq: u32) -> Self {
+        let pll = if self.pll_freq[0] == 0 {
+            PLL::A
+        } else if self.pll_freq[1] == 0 {
+            PLL::B
+        } else {
+            PLL::A
+        };
+        self.clk_on(output, pll, freq)
+    }
+
+    /// Adds an output driven by the given PLL.
+    ///
+    /// output: The output channel to use (0..2)
+    ///
+    /// pll: The PLL input source to use
+    ///
+    /// freq: The output frequency in Hz
+    pub const fn clk_on(mut self, output: usize, pll: PLL, freq: u32) -> Self {
+        if self.error.is_some() {
+            return self;
+        }
+        if output > 2 || self.enabled & (1 << output) != 0 {
+            self.error = Some(Error::InvalidParameter);
+            return self;
+        }
+        let pll_index = pll as usize;
+        let (a, b, c, r_div) = if self.pll_freq[pll_index] == 0 {
+            // First output on this PLL: set the PLL up for an integer multisynth
+            let plan = match FreqPlan::new(self.crystal_freq, freq) {
+                Ok(plan) => plan,
+                Err(e) => {
+                    self.error = Some(e);
+                    return self;
+                }
+            };
+            let vco = plan.vco(self.crystal_freq);
+            if vco < VCO_MIN || vco > VCO_MAX {
+                self.error = Some(Error::InvalidParameter);
+                return self;
+            }
+            let (p1, p2, p3) = encode_divider(plan.mult, plan.num, plan.denom);
+            self.write(26 + 8 * pll_index, pack_parameters(p1, p2, p3, 0));
+            self.pll_freq[pll_index] = vco;
+            self.pll_ratio[pll_index] = Ratio::new(plan.mult, plan.num, plan.denom);
+            (plan.ms_div, 0, 1, plan.r_div)
+        } else {
+            let vco = self.pll_freq[pll_index] as u128;
+            match multisynth_divider(vco, 1, freq as u64, (0, 7), true) {
+                Ok((ratio, r_div)) => (ratio.a, ratio.b, ratio.c, r_div),
+                Err(e) => {
+                    self.error = Some(e);
+                    return self;
+                }
+            }
+        };
+        let rdiv_bits = (r_div as u8) << 4;
+        let (p1, p2, p3) = encode_divider(a, b, c);
+        self.write(42 + 8 * output, pack_parameters(p1, p2, p3, rdiv_bits));
+        // 8mA drive strength, MSx as CLKx source, clock not inverted, powered up
+        let mut clk_control = 0x0f;
+        if pll_index == 1 {
+            clk_control |= 1 << 5;
+        }
+        if b == 0 {
+            clk_control |= 1 << 6;
+        }
+        self.image[1 + output] = clk_control;
+        self.rdiv_bits[output] = rdiv_bits;
+        self.outputs[output] = Some((pll, Ratio::new(a, b, c), r_div));
+        self.enabled |= 1 << output;
+        self
+    }
+
+    /// Finishes the plan, returning the first error found while adding
+    /// outputs
+    pub const fn try_build(self) -> Result<Self, Error> {
+        match self.error {
+            Some(e) => Err(e),
+            None => Ok(self),
+        }
+    }
+
+    /// Finishes the plan, panicking (which is a compile error in `const`
+    /// context) if any output could not be planned
+    pub const fn build(self) -> Self {
+        match self.error {
+            Some(Error::InvalidParameter) => panic!("frequency plan out of range"),
+            Some(_) => panic!("invalid frequency plan"),
+            None => self,
+        }
+    }
+
+    /// The register values for registers 15-65
+    pub const fn image(&self) -> &[u8; PLAN_LEN] {
+        &self.image
+    }
+
+    /// The crystal frequency the plan was computed for
+    pub const fn crystal_freq(&self) -> u32 {
+        self.crystal_freq
+    }
+
+    /// The PLL frequency in Hz, or 0 if the PLL is unused
+    pub const fn pll_freq(&self, pll: PLL) -> u32 {
+        self.pll_freq[pll as usize]
+    }
+
+    const fn write(&mut self, reg: usize, values: [u8; 8]) {
+        let mut i = 0;
+        while i < 8 {
+            self.image[reg - PLAN_START as usize + i] = values[i];
+            i += 1;
+        }
+    }
+}
+
+/// Computes a [`Plan`] at compile time.
+///
+/// Outputs are given as `CLKn = frequency`, optionally followed by
+/// `=> A` or `=> B` to pick the PLL. The crystal defaults to 25MHz and can
+/// be set with a leading `crystal = MHZ27;`.
+///
+/// ```
+/// use si5351a_adafruit::{freqplan::Plan, plan};
+///
+/// const AUDIO: Plan = plan!(CLK0 = 12_288_000, CLK1 = 24_576_000);
+/// const RADIO: Plan = plan!(crystal = MHZ27; CLK0 = 10_000_000 => B, CLK2 = 7_000_000 => B);
+/// ```
+#[macro_export]
+macro_rules! plan {
+    (@out CLK0) => { 0 };
+    (@out CLK1) => { 1 };
+    (@out CLK2) => { 2 };
+    (@chain $plan:expr;) => { $plan };
+    (@chain $plan:expr; $clk:ident = $freq:expr => $pll:ident $(, $($rest:tt)*)?) => {
+        $crate::plan!(@chain $plan.clk_on($crate::plan!(@out $clk), $crate::PLL::$pll, $freq); $($($rest)*)?)
+    };
+    (@chain $plan:expr; $clk:ident = $freq:expr $(, $($rest:tt)*)?) => {
+        $crate::plan!(@chain $plan.clk($crate::plan!(@out $clk), $freq); $($($rest)*)?)
+    };
+    (crystal = $crystal:ident; $($rest:tt)+) => {
+        const {
+            $crate::plan!(@chain $crate::freqplan::Plan::new($crate::CrystalFreq::$crystal); $($rest)+).build()
+        }
+    };
+    ($($rest:tt)+) => {
+        $crate::plan!(crystal = MHZ25; $($rest)+)
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::RegisterFile;
+    use crate::{DeviceConfig, Si5351};
+
+    fn pll_of(plan: &Plan, output: usize) -> Option<PLL> {
+        plan.outputs[output].map(|(pll, _, _)| pll)
+    }
+
+    #[test]
+    fn clk_uses_a_then_b_then_shares_a() {
+        let plan = Plan::new(CrystalFreq::MHZ25)
+            .clk(0, 10_000_000)
+            .clk(1, 14_000_000)
+            .clk(2, 7_000_000)
+            .try_build()
+            .unwrap();
+        assert_eq!(pll_of(&plan, 0), Some(PLL::A));
+        assert_eq!(pll_of(&plan, 1), Some(PLL::B));
+        assert_eq!(pll_of(&plan, 2), Some(PLL::A));
+        assert_eq!(plan.enabled, 0b111);
+        // The first output on a PLL gets an integer multisynth, the shared
+        // one a fractional multisynth from the same VCO
+        let vco = plan.pll_freq(PLL::A);
+        let (_, ms, r_div) = plan.outputs[2].unwrap();
+        assert_eq!(plan.outputs[0].unwrap().1.b, 0);
+        assert_eq!(r_div, RDiv::Div1);
+        let freq = vco as u64 * ms.c as u64 / (ms.a as u64 * ms.c as u64 + ms.b as u64);
+        assert!(freq.abs_diff(7_000_000) <= 1, "{freq}");
+        // Integer mode for output 0, fractional for output 2
+        assert_eq!(plan.image()[1] & (1 << 6), 1 << 6);
+        assert_eq!(plan.image()[3] & (1 << 6), 0);
+    }
+
+    #[test]
+    fn clk_on_shares_the_given_pll() {
+        let plan = Plan::new(CrystalFreq::MHZ25)
+            .clk_on(0, PLL::B, 10_000_000)
+            .clk_on(2, PLL::B, 12_000_000)
+            .try_build()
+            .unwrap();
+        assert_eq!(pll_of(&plan, 0), Some(PLL::B));
+        assert_eq!(pll_of(&plan, 1), None);
+        assert_eq!(pll_of(&plan, 2), Some(PLL::B));
+        assert_eq!(plan.pll_freq(PLL::A), 0);
+        assert_ne!(plan.pll_freq(PLL::B), 0);
+        // PLL B as multisynth source
+        assert_eq!(plan.image()[1] & (1 << 5), 1 << 5);
+        assert_eq!(plan.image()[3] & (1 << 5), 1 << 5);
+    }
+
+    #[test]
+    fn records_first_error() {
+        let plan = |plan: Plan| plan.try_build().err();
+        let new = || Plan::new(CrystalFreq::MHZ25);
+        // Unreachable frequencies
+        assert_eq!(plan(new().clk(0, 1)), Some(Error::InvalidParameter));
+        assert_eq!(
+            plan(new().clk(0, 200_000_000)),
+            Some(Error::InvalidParameter)
+        );
+        // A shared PLL cannot be divided down by a fraction below 8
+        assert_eq!(
+            plan(
+                new()
+                    .clk_on(0, PLL::A, 10_000_000)
+                    .clk_on(1, PLL::A, 140_000_000)
+            ),
+            Some(Error::InvalidParameter)
+        );
+        // Outputs used twice or out of range
+        assert_eq!(
+            plan(new().clk(0, 10_000_000).clk(0, 14_000_000)),
+            Some(Error::InvalidParameter)
+        );
+        assert_eq!(
+            plan(new().clk(3, 10_000_000)),
+            Some(Error::InvalidParameter)
+        );
+        // Later outputs are not added after an error
+        let failed = new().clk(0, 1).clk(1, 10_000_000);
+        assert_eq!(failed.enabled, 0);
+        assert_eq!(failed.image(), new().image());
+    }
+
+    #[test]
+    fn image_of_known_plan() {
+        let plan = Plan::new(CrystalFreq::MHZ25).clk(0, 12_288_000).build();
+        let mut image = [0; PLAN_LEN];
+        // CLK0 on, integer mode, PLL A, 8mA; CLK1-7 powered down
+        image[16 - 15..=23 - 15].copy_from_slice(&[0x4f, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80]);
+        // PLL A: 35 + 408357 / 1048575, P1 = 4017, P2 = 889521, P3 = 1048575
+        image[26 - 15..=33 - 15].copy_from_slice(&[0xff, 0xff, 0x00, 0x0f, 0xb1, 0xfd, 0x92, 0xb1]);
+        // Multisynth 0: 72, P1 = 8704
+        image[42 - 15..=49 - 15].copy_from_slice(&[0x00, 0x01, 0x00, 0x22, 0x00, 0x00, 0x00, 0x00]);
+        assert_eq!(plan.image(), &image);
+        // 884.736MHz, less what the 20 bit fraction cannot express
+        assert_eq!(plan.pll_freq(PLL::A), 884_735_998);
+    }
+
+    #[test]
+    fn apply_plan_rejects_failed_plan_before_writing() {
+        let mut clock_gen = Si5351::new(RegisterFile::new(), DeviceConfig::default()).unwrap();
+        let before = *clock_gen.i2c_dev.registers();
+        let plan = Plan::new(CrystalFreq::MHZ25).clk(0, 10_000_000).clk(1, 1);
+        assert_eq!(clock_gen.apply_plan(&plan), Err(Error::InvalidParameter));
+        assert_eq!(clock_gen.i2c_dev.registers(), &before);
+        assert_eq!(clock_gen.output_freq_millihertz(0), None);
+    }
+
+    #[test]
+    fn apply_plan_writes_image() {
+        let mut clock_gen = Si5351::new(RegisterFile::new(), DeviceConfig::default()).unwrap();
+        let plan = Plan::new(CrystalFreq::MHZ25).clk(0, 12_288_000).build();
+        assert_eq!(clock_gen.apply_plan(&plan), Ok(()));
+        let registers = clock_gen.i2c_dev.registers();
+        assert_eq!(&registers[15..=65], plan.image());
+        assert_eq!(registers[3], !0b001);
+    }
+}
@@ -0,0 +1,781 @@
+//! FT8 and FT4 transmit encoder.
+//!
+//! A standard message (`CQ K1ABC FN42`, `K1ABC W9XYZ -11`, ...) is packed
+//! into the 77 bit [`Payload`], protected with CRC-14 and the LDPC(174,91)
+//! code and mapped to 8-FSK ([`Ft8Message`]) or 4-FSK ([`Ft4Message`]) tone
+//! numbers with their Costas synchronisation arrays. The tones are played
+//! with [`Si5351::transmit_ft8`] and [`Si5351::transmit_ft4`]:
+//!
+//! ```no_run
+//! # fn example<I2C: embedded_hal::i2c::I2c, D: embedded_hal::delay::DelayNs>(
+//! #     clock_gen: &mut si5351a_adafruit::Si5351<I2C>,
+//! #     delay: &mut D,
+//! # ) -> Result<(), si5351a_adafruit::Error> {
+//! use si5351a_adafruit::ft8::{Ft8Message, Payload};
+//!
+//! let message = Ft8Message::new(&Payload::pack("CQ K1ABC FN42")?);
+//! // Start at a 15s boundary, 1500Hz into the 20m FT8 window
+//! clock_gen.transmit_ft8(0, 14_075_500_000, &message, delay)?;
+//! # Ok(())
+//! # }
+//! ```
+
+use embedded_hal::delay::DelayNs;
+use embedded_hal::i2c::I2c;
+
+use crate::{Error, HopTable, Observer, Si5351, check};
+
+/// Number of tones of an FT8 transmission
+pub const FT8_SYMBOL_COUNT: usize = 79;
+
+/// Duration of an FT8 symbol (0.16s) in nanoseconds
+pub const FT8_SYMBOL_DURATION_NS: u32 = 160_000_000;
+
+/// FT8 tone spacing (6.25Hz) in millihertz
+pub const FT8_TONE_SPACING_MILLIHERTZ: u64 = 6_250;
+
+/// Number of tones of an FT4 transmission
+pub const FT4_SYMBOL_COUNT: usize = 105;
+
+/// Duration of an FT4 symbol (576 / 12000 s) in nanoseconds
+pub const FT4_SYMBOL_DURATION_NS: u32 = 48_000_000;
+
+/// Offset of FT4 tone `tone` (0..3) from tone 0, in millihertz
+/// (12000 / 576 = 20.833Hz per tone)
+pub const fn ft4_tone_offset_millihertz(tone: u8) -> u64 {
+    (tone as u64 * 12_000_000 + 288) / 576
+}
+
+/// Number of callsign tokens (DE, QRZ, CQ, CQ nnn, CQ aaaa) in a 28 bit
+/// callsign field
+const NTOKENS: u32 = 2_063_592;
+
+/// Number of 22 bit hashes in a 28 bit callsign field
+const MAX22: u32 = 4_194_304;
+
+/// Number of 4 character grid locators
+const MAXGRID4: u16 = 32_400;
+
+/// CRC-14 polynomial
+const CRC_POLYNOMIAL: u16 = 0x2757;
+
+/// Number of LDPC parity bits
+const LDPC_M: usize = 83;
+
+/// Number of LDPC message bits (77 bit payload + CRC-14)
+const LDPC_K: usize = 91;
+
+/// Number of LDPC codeword bits
+const LDPC_N: usize = 174;
+
+/// Generator matrix of the LDPC(174,91) code, one parity bit per row
+const LDPC_GENERATOR: [[u8; 12]; LDPC_M] = [
+    [
+        0x83, 0x29, 0xce, 0x11, 0xbf, 0x31, 0xea, 0xf5, 0x09, 0xf2, 0x7f, 0xc0,
+    ],
+    [
+        0x76, 0x1c, 0x26, 0x4e, 0x25, 0xc2, 0x59, 0x33, 0x54, 0x93, 0x13, 0x20,
+    ],
+    [
+        0xdc, 0x26, 0x59, 0x02, 0xfb, 0x27, 0x7c, 0x64, 0x10, 0xa1, 0xbd, 0xc0,
+    ],
+    [
+        0x1b, 0x3f, 0x41, 0x78, 0x58, 0xcd, 0x2d, 0xd3, 0x3e, 0xc7, 0xf6, 0x20,
+    ],
+    [
+        0x09, 0xfd, 0xa4, 0xfe, 0xe0, 0x41, 0x95, 0xfd, 0x03, 0x47, 0x83, 0xa0,
+    ],
+    [
+        0x07, 0x7c, 0xcc, 0xc1, 0x1b, 0x88, 0x73, 0xed, 0x5c, 0x3d, 0x48, 0xa0,
+    ],
+    [
+        0x29, 0xb6, 0x2a, 0xfe, 0x3c, 0xa0, 0x36, 0xf4, 0xfe, 0x1a, 0x9d, 0xa0,
+    ],
+    [
+        0x60, 0x54, 0xfa, 0xf5, 0xf3, 0x5d, 0x96, 0xd3, 0xb0, 0xc8, 0xc3, 0xe0,
+    ],
+    [
+        0xe2, 0x07, 0x98, 0xe4, 0x31, 0x0e, 0xed, 0x27, 0x88, 0x4a, 0xe9, 0x00,
+    ],
+    [
+        0x77, 0x5c, 0x9c, 0x08, 0xe8, 0x0e, 0x26, 0xdd, 0xae, 0x56, 0x31, 0x80,
+    ],
+    [
+        0xb0, 0xb8, 0x11, 0x02, 0x8c, 0x2b, 0xf9, 0x97, 0x21, 0x34, 0x87, 0xc0,
+    ],
+    [
+        0x18, 0xa0, 0xc9, 0x23, 0x1f, 0xc6, 0x0a, 0xdf, 0x5c, 0x5e, 0xa3, 0x20,
+    ],
+    [
+        0x76, 0x47, 0x1e, 0x83, 0x02, 0xa0, 0x72, 0x1e, 0x01, 0xb1, 0x2b, 0x80,
+    ],
+    [
+        0xff, 0xbc, 0xcb, 0x80, 0xca, 0x83, 0x41, 0xfa, 0xfb, 0x47, 0xb2, 0xe0,
+    ],
+    [
+        0x66, 0xa7, 0x2a, 0x15, 0x8f, 0x93, 0x25, 0xa2, 0xbf, 0x67, 0x17, 0x00,
+    ],
+    [
+        0xc4, 0x24, 0x36, 0x89, 0xfe, 0x85, 0xb1, 0xc5, 0x13, 0x63, 0xa1, 0x80,
+    ],
+    [
+        0x0d, 0xff, 0x73, 0x94, 0x14, 0xd1, 0xa1, 0xb3, 0x4b, 0x1c, 0x27, 0x00,
+    ],
+    [
+        0x15, 0xb4, 0x88, 0x30, 0x63, 0x6c, 0x8b, 0x99, 0x89, 0x49, 0x72, 0xe0,
+    ],
+    [
+        0x29, 0xa8, 0x9c, 0x0d, 0x3d, 0xe8, 0x1d, 0x66, 0x54, 0x89, 0xb0, 0xe0,
+    ],
+    [
+        0x4f, 0x12, 0x6f, 0x37, 0xfa, 0x51, 0xcb, 0xe6, 0x1b, 0xd6, 0xb9, 0x40,
+    ],
+    [
+        0x99, 0xc4, 0x72, 0x39, 0xd0, 0xd9, 0x7d, 0x3c, 0x84, 0xe0, 0x94, 0x00,
+    ],
+    [
+        0x19, 0x19, 0xb7, 0x51, 0x19, 0x76, 0x56, 0x21, 0xbb, 0x4f, 0x1e, 0x80,
+    ],
+    [
+        0x09, 0xdb, 0x12, 0xd7, 0x31, 0xfa, 0xee, 0x0b, 0x86, 0xdf, 0x6b, 0x80,
+    ],
+    [
+        0x48, 0x8f, 0xc3, 0x3d, 0xf4, 0x3f, 0xbd, 0xee, 0xa4, 0xea, 0xfb, 0x40,
+    ],
+    [
+        0x82, 0x74, 0x23, 0xee, 0x40, 0xb6, 0x75, 0xf7, 0x56, 0xeb, 0x5f, 0xe0,
+    ],
+    [
+        0xab, 0xe1, 0x97, 0xc4, 0x84, 0xcb, 0x74, 0x75, 0x71, 0x44, 0xa9, 0xa0,
+    ],
+    [
+        0x2b, 0x50, 0x0e, 0x4b, 0xc0, 0xec, 0x5a, 0x6d, 0x2b, 0xdb, 0xdd, 0x00,
+    ],
+    [
+        0xc4, 0x74, 0xaa, 0x53, 0xd7, 0x02, 0x18, 0x76, 0x16, 0x69, 0x36, 0x00,
+    ],
+    [
+        0x8e, 0xba, 0x1a, 0x13, 0xdb, 0x33, 0x90, 0xbd, 0x67, 0x18, 0xce, 0xc0,
+    ],
+    [
+        0x75, 0x38, 0x44, 0x67, 0x3a, 0x27, 0x78, 0x2c, 0xc4, 0x20, 0x12, 0xe0,
+    ],
+    [
+        0x06, 0xff, 0x83, 0xa1, 0x45, 0xc3, 0x70, 0x35, 0xa5, 0xc1, 0x26, 0x80,
+    ],
+    [
+        0x3b, 0x37, 0x41, 0x78, 0x58, 0xcc, 0x2d, 0xd3, 0x3e, 0xc3, 0xf6, 0x20,
+    ],
+    [
+        0x9a, 0x4a, 0x5a, 0x28, 0xee, 0x17, 0xca, 0x9c, 0x32, 0x48, 0x42, 0xc0,
+    ],
+    [
+        0xbc, 0x29, 0xf4, 0x65, 0x30, 0x9c, 0x97, 0x7e, 0x89, 0x61, 0x0a, 0x40,
+    ],
+    [
+        0x26, 0x63, 0xae, 0x6d, 0xdf, 0x8b, 0x5c, 0xe2, 0xbb, 0x29, 0x48, 0x80,
+    ],
+    [
+        0x46, 0xf2, 0x31, 0xef, 0xe4, 0x57, 0x03, 0x4c, 0x18, 0x14, 0x41, 0x80,
+    ],
+    [
+        0x3f, 0xb2, 0xce, 0x85, 0xab, 0xe9, 0xb0, 0xc7, 0x2e, 0x06, 0xfb, 0xe0,
+    ],
+    [
+        0xde, 0x87, 0x48, 0x1f, 0x28, 0x2c, 0x15, 0x39, 0x71, 0xa0, 0xa2, 0xe0,
+    ],
+    [
+        0xfc, 0xd7, 0xcc, 0xf2, 0x3c, 0x69, 0xfa, 0x99, 0xbb, 0xa1, 0x41, 0x20,
+    ],
+    [
+        0xf0, 0x26, 0x14, 0x47, 0xe9, 0x49, 0x0c, 0xa8, 0xe4, 0x74, 0xce, 0xc0,
+    ],
+    [
+        0x44, 0x10, 0x11, 0x58, 0x18, 0x19, 0x6f, 0x95, 0xcd, 0xd7, 0x01, 0x20,
+    ],
+    [
+        0x08, 0x8f, 0xc3, 0x1d, 0xf4, 0xbf, 0xbd, 0xe2, 0xa4, 0xea, 0xfb, 0x40,
+    ],
+    [
+        0xb8, 0xfe, 0xf1, 0xb6, 0x30, 0x77, 0x29, 0xfb, 0x0a, 0x07, 0x8c, 0x00,
+    ],
+    [
+        0x5a, 0xfe, 0xa7, 0xac, 0xcc, 0xb7, 0x7b, 0xbc, 0x9d, 0x99, 0xa9, 0x00,
+    ],
+    [
+        0x49, 0xa7, 0x01, 0x6a, 0xc6, 0x53, 0xf6, 0x5e, 0xcd, 0xc9, 0x07, 0x60,
+    ],
+    [
+        0x19, 0x44, 0xd0, 0x85, 0xbe, 0x4e, 0x7d, 0xa8, 0xd6, 0xcc, 0x7d, 0x00,
+    ],
+    [
+        0x25, 0x1f, 0x62, 0xad, 0xc4, 0x03, 0x2f, 0x0e, 0xe7, 0x14, 0x00, 0x20,
+    ],
+    [
+        0x56, 0x47, 0x1f, 0x87, 0x02, 0xa0, 0x72, 0x1e, 0x00, 0xb1, 0x2b, 0x80,
+    ],
+    [
+        0x2b, 0x8e, 0x49, 0x23, 0xf2, 0xdd, 0x51, 0xe2, 0xd5, 0x37, 0xfa, 0x00,
+    ],
+    [
+        0x6b, 0x55, 0x0a, 0x40, 0xa6, 0x6f, 0x47, 0x55, 0xde, 0x95, 0xc2, 0x60,
+    ],
+    [
+        0xa1, 0x8a, 0xd2, 0x8d, 0x4e, 0x27, 0xfe, 0x92, 0xa4, 0xf6, 0xc8, 0x40,
+    ],
+    [
+        0x10, 0xc2, 0xe5, 0x86, 0x38, 0x8c, 0xb8, 0x2a, 0x3d, 0x80, 0x75, 0x80,
+    ],
+    [
+        0xef, 0x34, 0xa4, 0x18, 0x17, 0xee, 0x02, 0x13, 0x3d, 0xb2, 0xeb, 0x00,
+    ],
+    [
+        0x7e, 0x9c, 0x0c, 0x54, 0x32, 0x5a, 0x9c, 0x15, 0x83, 0x6e, 0x00, 0x00,
+    ],
+    [
+        0x36, 0x93, 0xe5, 0x72, 0xd1, 0xfd, 0xe4, 0xcd, 0xf0, 0x79, 0xe8, 0x60,
+    ],
+    [
+        0xbf, 0xb2, 0xce, 0xc5, 0xab, 0xe1, 0xb0, 0xc7, 0x2e, 0x07, 0xfb, 0xe0,
+    ],
+    [
+        0x7e, 0xe1, 0x82, 0x30, 0xc5, 0x83, 0xcc, 0xcc, 0x57, 0xd4, 0xb0, 0x80,
+    ],
+    [
+        0xa0, 0x66, 0xcb, 0x2f, 0xed, 0xaf, 0xc9, 0xf5, 0x26, 0x64, 0x12, 0x60,
+    ],
+    [
+        0xbb, 0x23, 0x72, 0x5a, 0xbc, 0x47, 0xcc, 0x5f, 0x4c, 0xc4, 0xcd, 0x20,
+    ],
+    [
+        0xde, 0xd9, 0xdb, 0xa3, 0xbe, 0xe4, 0x0c, 0x59, 0xb5, 0x60, 0x9b, 0x40,
+    ],
+    [
+        0xd9, 0xa7, 0x01, 0x6a, 0xc6, 0x53, 0xe6, 0xde, 0xcd, 0xc9, 0x03, 0x60,
+    ],
+    [
+        0x9a, 0xd4, 0x6a, 0xed, 0x5f, 0x70, 0x7f, 0x28, 0x0a, 0xb5, 0xfc, 0x40,
+    ],
+    [
+        0xe5, 0x92, 0x1c, 0x77, 0x82, 0x25, 0x87, 0x31, 0x6d, 0x7d, 0x3c, 0x20,
+    ],
+    [
+        0x4f, 0x14, 0xda, 0x82, 0x42, 0xa8, 0xb8, 0x6d, 0xca, 0x73, 0x35, 0x20,
+    ],
+    [
+        0x8b, 0x8b, 0x50, 0x7a, 0xd4, 0x67, 0xd4, 0x44, 0x1d, 0xf7, 0x70, 0xe0,
+    ],
+    [
+        0x22, 0x83, 0x1c, 0x9c, 0xf1, 0x16, 0x94, 0x67, 0xad, 0x04, 0xb6, 0x80,
+    ],
+    [
+        0x21, 0x3b, 0x83, 0x8f, 0xe2, 0xae, 0x54, 0xc3, 0x8e, 0xe7, 0x18, 0x00,
+    ],
+    [
+        0x5d, 0x92, 0x6b, 0x6d, 0xd7, 0x1f, 0x08, 0x51, 0x81, 0xa4, 0xe1, 0x20,
+    ],
+    [
+        0x66, 0xab, 0x79, 0xd4, 0xb2, 0x9e, 0xe6, 0xe6, 0x95, 0x09, 0xe5, 0x60,
+    ],
+    [
+        0x95, 0x81, 0x48, 0x68, 0x2d, 0x74, 0x8a, 0x38, 0xdd, 0x68, 0xba, 0xa0,
+    ],
+    [
+        0xb8, 0xce, 0x02, 0x0c, 0xf0, 0x69, 0xc3, 0x2a, 0x72, 0x3a, 0xb1, 0x40,
+    ],
+    [
+        0xf4, 0x33, 0x1d, 0x6d, 0x46, 0x16, 0x07, 0xe9, 0x57, 0x52, 0x74, 0x60,
+    ],
+    [
+        0x6d, 0xa2, 0x3b, 0xa4, 0x24, 0xb9, 0x59, 0x61, 0x33, 0xcf, 0x9c, 0x80,
+    ],
+    [
+        0xa6, 0x36, 0xbc, 0xbc, 0x7b, 0x30, 0xc5, 0xfb, 0xea, 0xe6, 0x7f, 0xe0,
+    ],
+    [
+        0x5c, 0xb0, 0xd8, 0x6a, 0x07, 0xdf, 0x65, 0x4a, 0x90, 0x89, 0xa2, 0x00,
+    ],
+    [
+        0xf1, 0x1f, 0x10, 0x68, 0x48, 0x78, 0x0f, 0xc9, 0xec, 0xdd, 0x80, 0xa0,
+    ],
+    [
+        0x1f, 0xbb, 0x53, 0x64, 0xfb, 0x8d, 0x2c, 0x9d, 0x73, 0x0d, 0x5b, 0xa0,
+    ],
+    [
+        0xfc, 0xb8, 0x6b, 0xc7, 0x0a, 0x50, 0xc9, 0xd0, 0x2a, 0x5d, 0x03, 0x40,
+    ],
+    [
+        0xa5, 0x34, 0x43, 0x30, 0x29, 0xea, 0xc1, 0x5f, 0x32, 0x2e, 0x34, 0xc0,
+    ],
+    [
+        0xc9, 0x89, 0xd9, 0xc7, 0xc3, 0xd3, 0xb8, 0xc5, 0x5d, 0x75, 0x13, 0x00,
+    ],
+    [
+        0x7b, 0xb3, 0x8b, 0x2f, 0x01, 0x86, 0xd4, 0x66, 0x43, 0xae, 0x96, 0x20,
+    ],
+    [
+        0x26, 0x44, 0xeb, 0xad, 0xeb, 0x44, 0xb9, 0x46, 0x7d, 0x1f, 0x42, 0xc0,
+    ],
+    [
+        0x60, 0x8c, 0xc8, 0x57, 0x59, 0x4b, 0xfb, 0xb5, 0x5d, 0x69, 0x60, 0x00,
+    ],
+];
+
+const FT8_COSTAS: [u8; 7] = [3, 1, 4, 0, 6, 5, 2];
+
+const FT8_GRAY_MAP: [u8; 8] = [0, 1, 3, 2, 5, 6, 4, 7];
+
+const FT4_COSTAS: [[u8; 4]; 4] = [[0, 1, 3, 2], [1, 0, 2, 3], [2, 3, 1, 0], [3, 2, 0, 1]];
+
+const FT4_GRAY_MAP: [u8; 4] = [0, 1, 3, 2];
+
+/// Scrambling sequence applied to the FT4 payload
+const FT4_XOR_SEQUENCE: [u8; 10] = [0x4a, 0x5e, 0x89, 0xb4, 0xb0, 0x8a, 0x79, 0x55, 0xbe, 0x28];
+
+/// A 77 bit FT8/FT4 message payload, most significant bit first
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Payload([u8; 10]);
+
+impl Payload {
+    /// Packs a standard (type 1) message: two callsigns followed by an
+    /// optional grid locator, signal report, `RRR`, `RR73` or `73`.
+    ///
+    /// The first callsign may also be `CQ`, `QRZ` or `DE`. Examples:
+    /// `CQ K1ABC FN42`, `K1ABC W9XYZ -11`, `W9XYZ K1ABC R-09`,
+    /// `K1ABC W9XYZ RR73`. Like in WSJT-X, the case does not matter.
+    pub fn pack(message: &str) -> Result<Self, Error> {
+        // Like WSJT-X, take the message in any case
+        let (mut call_a, mut call_b, mut grid) = ([0; 6], [0; 6], [0; 6]);
+        let mut words = message.split_ascii_whitespace();
+        let word = words.next().ok_or(Error::InvalidParameter)?;
+        let n28a = pack_callsign(uppercase(word, &mut call_a)?)?;
+        let word = words.next().ok_or(Error::InvalidParameter)?;
+        let n28b = pack_callsign(uppercase(word, &mut call_b)?)?;
+        let igrid4 = pack_grid(
+            words
+                .next()
+                .map(|word| uppercase(word, &mut grid))
+                .transpose()?,
+        )?;
+        check(words.next().is_none(), Error::InvalidParameter)?;
+        let i3 = 1_u8;
+        // Append the (unused) /R flags
+        let n29a = n28a << 1;
+        let n29b = n28b << 1;
+        // (28 + 1) + (28 + 1) + (1 + 15) + 3 bits
+        Ok(Self([
+            (n29a >> 21) as u8,
+            (n29a >> 13) as u8,
+            (n29a >> 5) as u8,
+            (n29a << 3) as u8 | (n29b >> 26) as u8,
+            (n29b >> 18) as u8,
+            (n29b >> 10) as u8,
+            (n29b >> 2) as u8,
+            (n29b << 6) as u8 | (igrid4 >> 10) as u8,
+            (igrid4 >> 2) as u8,
+            (igrid4 << 6) as u8 | (i3 << 3),
+        ]))
+    }
+
+    /// Wraps 77 already packed bits (the 3 least significant bits of the
+    /// last byte are ignored)
+    pub const fn from_bits(bits: [u8; 10]) -> Self {
+        Self(bits)
+    }
+
+    /// The packed bits, most significant bit first
+    pub const fn bits(&self) -> &[u8; 10] {
+        &self.0
+    }
+}
+
+/// Copies a word of up to 6 characters (the longest callsign) to `buf` in
+/// upper case
+fn uppercase<'a>(word: &str, buf: &'a mut [u8; 6]) -> Result<&'a str, Error> {
+    check(word.len() <= buf.len(), Error::InvalidParameter)?;
+    let buf = &mut buf[..word.len()];
+    buf.copy_from_slice(word.as_bytes());
+    buf.make_ascii_uppercase();
+    core::str::from_utf8(buf).map_err(|_| Error::InvalidParameter)
+}
+
+/// Packs an upper case callsign or the CQ/QRZ/DE tokens into 28 bits
+fn pack_callsign(callsign: &str) -> Result<u32, Error> {
+    match callsign {
+        "DE" => return Ok(0),
+        "QRZ" => return Ok(1),
+        "CQ" => return Ok(2),
+        _ => {}
+    }
+    let callsign = callsign.as_bytes();
+    // Pad to 6 characters so that the third one is the digit
+    let mut c6 = [b' '; 6];
+    if callsign.len() > 2 && callsign[2].is_ascii_digit() && callsign.len() <= 6 {
+        c6[..callsign.len()].copy_from_slice(callsign);
+    } else if callsign.len() > 1 && callsign[1].is_ascii_digit() && callsign.len() <= 5 {
+        c6[1..=callsign.len()].copy_from_slice(callsign);
+    } else {
+        return Err(Error::InvalidParameter);
+    }
+    let index = |alphabet: &[u8], c: u8| {
+        alphabet
+            .iter()
+            .position(|&a| a == c)
+            .map(|i| i as u32)
+            .ok_or(Error::InvalidParameter)
+    };
+    const A1: &[u8] = b" 0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZ";
+    const A2: &[u8] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZ";
+    const A3: &[u8] = b"0123456789";
+    const A4: &[u8] = b" ABCDEFGHIJKLMNOPQRSTUVWXYZ";
+    let mut n28 = index(A1, c6[0])?;
+    n28 = n28 * 36 + index(A2, c6[1])?;
+    n28 = n28 * 10 + index(A3, c6[2])?;
+    n28 = n28 * 27 + index(A4, c6[3])?;
+    n28 = n28 * 27 + index(A4, c6[4])?;
+    n28 = n28 * 27 + index(A4, c6[5])?;
+    Ok(NTOKENS + MAX22 + n28)
+}
+
+/// Packs the R flag and the upper case 15 bit grid, report or
+/// acknowledgement
+fn pack_grid(grid: Option<&str>) -> Result<u16, Error> {
+    let Some(grid) = grid else {
+        return Ok(MAXGRID4 + 1);
+    };
+    match grid {
+        "RRR" => return Ok(MAXGRID4 + 2),
+        "RR73" => return Ok(MAXGRID4 + 3),
+        "73" => return Ok(MAXGRID4 + 4),
+        _ => {}
+    }
+    let g = grid.as_bytes();
+    if g.len() == 4
+        && (b'A'..=b'R').contains(&g[0])
+        && (b'A'..=b'R').contains(&g[1])
+        && g[2].is_ascii_digit()
+        && g[3].is_ascii_digit()
+    {
+        let igrid4 = (g[0] - b'A') as u16 * 18 * 10 * 10
+            + (g[1] - b'A') as u16 * 10 * 10
+            + (g[2] - b'0') as u16 * 10
+            + (g[3] - b'0') as u16;
+        return Ok(igrid4);
+    }
+    // Signal report, optionally acknowledged with R
+    let (report, r_flag) = match grid.strip_prefix('R') {
+        Some(report) => (report, 0x8000),
+        None => (grid, 0),
+    };
+    check(report.starts_with(['+', '-']), Error::InvalidParameter)?;
+    let dd: i16 = report.parse().map_err(|_| Error::InvalidParameter)?;
+    check((-30..=32).contains(&dd), Error::InvalidParameter)?;
+    Ok((MAXGRID4 + (35 + dd) as u16) | r_flag)
+}
+
+/// CRC-14 over the first `num_bits` bits of `message`
+fn crc14(message: &[u8], num_bits: usize) -> u16 {
+    const TOPBIT: u16 = 1 << 13;
+    let mut remainder = 0_u16;
+    for bit in 0..num_bits {
+        if bit % 8 == 0 {
+            remainder ^= (message[bit / 8] as u16) << 6;
+        }
+        remainder = if remainder & TOPBIT != 0 {
+            (remainder << 1) ^ CRC_POLYNOMIAL
+        } else {
+            remainder << 1
+        };
+    }
+    remainder & (2 * TOPBIT - 1)
+}
+
+/// Appends the CRC-14 and the LDPC parity bits to the payload
+fn encode_codeword(payload: &[u8; 10]) -> [u8; LDPC_N.div_ceil(8)] {
+    // The CRC is computed over the payload extended to 82 bits with zeros
+    let mut a91 = [0_u8; 12];
+    a91[..10].copy_from_slice(payload);
+    a91[9] &= 0xf8;
+    let crc = crc14(&a91, 82);
+    a91[9] |= (crc >> 11) as u8;
+    a91[10] = (crc >> 3) as u8;
+    a91[11] = (crc << 5) as u8;
+
+    let mut codeword = [0_u8; LDPC_N.div_ceil(8)];
+    codeword[..12].copy_from_slice(&a91);
+    for (i, row) in LDPC_GENERATOR.iter().enumerate() {
+        let parity = row
+            .iter()
+            .zip(a91.iter())
+            .fold(0, |sum, (&g, &m)| sum ^ (g & m).count_ones())
+            & 1;
+        if parity != 0 {
+            let bit = LDPC_K + i;
+            codeword[bit / 8] |= 0x80 >> (bit % 8);
+        }
+    }
+    codeword
+}
+
+/// Reads `count` bits starting at `bit`, most significant bit first
+fn read_bits(codeword: &[u8], bit: usize, count: usize) -> u8 {
+    (bit..bit + count).fold(0, |value, i| {
+        (value << 1) | ((codeword[i / 8] >> (7 - i % 8)) & 1)
+    })
+}
+
+/// The 79 tone numbers (0..7) of an FT8 transmission
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Ft8Message {
+    tones: [u8; FT8_SYMBOL_COUNT],
+}
+
+impl Ft8Message {
+    /// Encodes a payload as three Costas arrays around two blocks of 29
+    /// Gray coded 8-FSK data symbols
+    pub fn new(payload: &Payload) -> Self {
+        let codeword = encode_codeword(&payload.0);
+        let mut tones = [0; FT8_SYMBOL_COUNT];
+        for start in [0, 36, 72] {
+            tones[start..start + 7].copy_from_slice(&FT8_COSTAS);
+        }
+        for j in 0..58 {
+            let k = if j < 29 { 7 + j } else { 14 + j };
+            tones[k] = FT8_GRAY_MAP[read_bits(&codeword, 3 * j, 3) as usize];
+        }
+        Self { tones }
+    }
+
+    /// The tone numbers (0..7)
+    pub fn tones(&self) -> &[u8; FT8_SYMBOL_COUNT] {
+        &self.tones
+    }
+}
+
+/// The 105 tone numbers (0..3) of an FT4 transmission
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Ft4Message {
+    tones: [u8; FT4_SYMBOL_COUNT],
+}
+
+impl Ft4Message {
+    /// Scrambles the payload and encodes it as four Costas arrays around
+    /// three blocks of 29 Gray coded 4-FSK data symbols, with a ramp
+    /// symbol at both ends
+    pub fn new(payload: &Payload) -> Self {
+        let mut scrambled = payload.0;
+        for (byte, xor) in scrambled.iter_mut().zip(FT4_XOR_SEQUENCE) {
+            *byte ^= xor;
+        }
+        let codeword = encode_codeword(&scrambled);
+        let mut tones = [0; FT4_SYMBOL_COUNT];
+        let mut bit = 0;
+        for (i, tone) in tones.iter_mut().enumerate() {
+            *tone = match i {
+                0 | 104 => 0,
+                1..=4 => FT4_COSTAS[0][i - 1],
+                34..=37 => FT4_COSTAS[1][i - 34],
+                67..=70 => FT4_COSTAS[2][i - 67],
+                100..=103 => FT4_COSTAS[3][i - 100],
+                _ => {
+                    let bits = read_bits(&codeword, bit, 2);
+                    bit += 2;
+                    FT4_GRAY_MAP[bits as usize]
+                }
+            };
+        }
+        Self { tones }
+    }
+
+    /// The tone numbers (0..3)
+    pub fn tones(&self) -> &[u8; FT4_SYMBOL_COUNT] {
+        &self.tones
+    }
+}
+
+impl<I2C: I2c, DELAY: DelayNs, OBS: Observer> Si5351<I2C, DELAY, OBS> {
+    /// Transmits an FT8 message on an output.
+    ///
+    /// The output is first tuned to `freq` (tone 0), which may need a full
+    /// reconfiguration. The eight tones, 6.25Hz apart, are then precomputed
+    /// as a [`HopTable`] so every tone change is a single short burst
+    /// without a PLL reset. Call this 0.5s after a 15s UTC boundary.
+    ///
+    /// The output is left running on the last tone.
+    ///
+    /// output: The output channel to use (0..2)
+    ///
+    /// freq: The frequency of tone 0 in millihertz
+    ///
+    /// message: The encoded message
+    ///
+    /// delay: Used for the 0.16s symbol timing
+    pub fn transmit_ft8<D: DelayNs>(
+        &mut self,
+        output: usize,
+        freq: u64,
+        message: &Ft8Message,
+        delay: &mut D,
+    ) -> Result<(), Error> {
+        self.retune_millihertz(output, freq)?;
+        let tones = [0, 1, 2, 3, 4, 5, 6, 7].map(|tone| freq + tone * FT8_TONE_SPACING_MILLIHERTZ);
+        let table = HopTable::new(self, output, tones)?;
+        for &tone in message.tones() {
+            self.hop(&table, tone as usize)?;
+            delay.delay_ns(FT8_SYMBOL_DURATION_NS);
+        }
+        Ok(())
+    }
+
+    /// Transmits an FT4 message on an output.
+    ///
+    /// Works like [`Si5351::transmit_ft8`], with four tones 20.833Hz apart
+    /// and 48ms symbols. Call this 0.5s after a 7.5s UTC boundary.
+    pub fn transmit_ft4<D: DelayNs>(
+        &mut self,
+        output: usize,
+        freq: u64,
+        message: &Ft4Message,
+        delay: &mut D,
+    ) -> Result<(), Error> {
+        self.retune_millihertz(output, freq)?;
+        let tones = [0, 1, 2, 3].map(|tone| freq + ft4_tone_offset_millihertz(tone));
+        let table = HopTable::new(self, output, tones)?;
+        for &tone in message.tones() {
+            self.hop(&table, tone as usize)?;
+            delay.delay_ns(FT4_SYMBOL_DURATION_NS);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Reference values, cross-checked against the independent ft8core
+    // crate and the message packing of WSJT-X
+
+    /// Tone numbers written as a string of digits
+    fn tones<const N: usize>(digits: &str) -> [u8; N] {
+        let mut tones = [0; N];
+        assert_eq!(digits.len(), N);
+        for (tone, digit) in tones.iter_mut().zip(digits.bytes()) {
+            *tone = digit - b'0';
+        }
+        tones
+    }
+
+    /// The CRC-14 of a packed message, over the 77 bits extended to 82
+    fn crc(message: &str) -> u16 {
+        let mut a91 = [0_u8; 12];
+        a91[..10].copy_from_slice(Payload::pack(message).unwrap().bits());
+        a91[9] &= 0xf8;
+        crc14(&a91, 82)
+    }
+
+    #[test]
+    fn packs_standard_message() {
+        let payload = Payload::pack("CQ K1ABC FN42").unwrap();
+        assert_eq!(
+            payload.bits(),
+            &[0x00, 0x00, 0x00, 0x20, 0x4d, 0xef, 0x1a, 0x8a, 0x19, 0x88]
+        );
+        assert_eq!(Payload::from_bits(*payload.bits()), payload);
+        assert_eq!(Payload::pack("CQ k1abc fn42"), Ok(payload));
+    }
+
+    #[test]
+    fn packs_lower_case_messages() {
+        for message in [
+            "CQ K1ABC FN42",
+            "QRZ K1ABC FN42",
+            "DE K1ABC",
+            "K1ABC W9XYZ RRR",
+            "K1ABC W9XYZ RR73",
+            "K1ABC W9XYZ 73",
+            "W9XYZ K1ABC R-09",
+        ] {
+            let lower = message.to_ascii_lowercase();
+            assert_eq!(Payload::pack(&lower), Payload::pack(message), "{lower}");
+            assert!(Payload::pack(&lower).is_ok(), "{lower}");
+        }
+    }
+
+    #[test]
+    fn packs_grids_and_reports() {
+        assert_eq!(pack_grid(None), Ok(MAXGRID4 + 1));
+        assert_eq!(pack_grid(Some("RRR")), Ok(MAXGRID4 + 2));
+        // WSJT-X checks RR73 before grids, although it is one
+        assert_eq!(pack_grid(Some("RR73")), Ok(MAXGRID4 + 3));
+        assert_eq!(pack_grid(Some("73")), Ok(MAXGRID4 + 4));
+        assert_eq!(pack_grid(Some("AA00")), Ok(0));
+        assert_eq!(pack_grid(Some("-11")), Ok(MAXGRID4 + 24));
+        assert_eq!(pack_grid(Some("R+05")), Ok((MAXGRID4 + 40) | 0x8000));
+        for invalid in ["FN4", "SN42", "11", "-31", "+33", "R73"] {
+            assert_eq!(pack_grid(Some(invalid)), Err(Error::InvalidParameter));
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_messages() {
+        for message in [
+            "",
+            "CQ",
+            "CQ K1ABC FN42 73",
+            "CQ KABC",
+            "CQ K1ABCDE",
+            "CQ K1A/P",
+        ] {
+            assert_eq!(
+                Payload::pack(message),
+                Err(Error::InvalidParameter),
+                "{message}"
+            );
+        }
+    }
+
+    #[test]
+    fn computes_crc14() {
+        assert_eq!(crc("CQ K1ABC FN42"), 0x0b2e);
+        assert_eq!(crc("K1ABC W9XYZ -11"), 0x2e81);
+        assert_eq!(crc("W9XYZ K1ABC R-09"), 0x2afd);
+        assert_eq!(crc("K1ABC W9XYZ 73"), 0x2bc3);
+        assert_eq!(crc("K1ABC W9XYZ RRR"), 0x17c0);
+        assert_eq!(crc("CQ DL1ABC JO62"), 0x1abb);
+    }
+
+    #[test]
+    fn encodes_ft8_tones() {
+        let payload = Payload::pack("CQ K1ABC FN42").unwrap();
+        assert_eq!(
+            Ft8Message::new(&payload).tones(),
+            &tones(
+                "3140652000000001005476704606021533433140652736011047517007334745455133543140652"
+            )
+        );
+    }
+
+    #[test]
+    fn encodes_ft4_tones() {
+        let payload = Payload::pack("CQ K1ABC FN42").unwrap();
+        let message = Ft4Message::new(&payload);
+        assert_eq!(
+            message.tones(),
+            &tones(
+                "0013210331123303131102221131113022102312233123312102031212002330321231012123230\
+                 23000120100233321133032010"
+            )
+        );
+        // The payload is scrambled before encoding: the first data symbols
+        // of an all zero payload carry the scrambling sequence
+        let zeros = Ft4Message::new(&Payload::from_bits([0; 10]));
+        for (i, &tone) in zeros.tones()[5..34].iter().enumerate() {
+            let bits = read_bits(&FT4_XOR_SEQUENCE, 2 * i, 2);
+            assert_eq!(tone, FT4_GRAY_MAP[bits as usize], "symbol {i}");
+        }
+    }
+}
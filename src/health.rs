@@ -0,0 +1,304 @@
+//! Monitoring of the device status: loss of lock, loss of signal and
+//! device resets, e.g. after a supply glitch.
+//!
+//! ```no_run
+//! # fn example<I2C: embedded_hal::i2c::I2c>(
+//! #     clock_gen: &mut si5351a_adafruit::Si5351<I2C>,
+//! # ) -> Result<(), si5351a_adafruit::Error> {
+//! // Called periodically, e.g. once a second
+//! let health = clock_gen.poll_health()?;
+//! if health.reset {
+//!     // The device lost its configuration, health.restored tells whether
+//!     // it was written again
+//! }
+//! if !health.is_healthy() {
+//!     // Report it
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use embedded_hal::delay::DelayNs;
+use embedded_hal::i2c::I2c;
+
+use crate::transaction::{RegisterSet, write_runs};
+use crate::{Error, LAST_REGISTER, Observer, PLL, Registers, Si5351, is_volatile};
+
+/// SYS_INIT: the device is initialising after a power-up or reset
+const SYS_INIT: u8 = 1 << 7;
+/// LOL_B: PLL B is out of lock
+const LOL_B: u8 = 1 << 6;
+/// LOL_A: PLL A is out of lock
+const LOL_A: u8 = 1 << 5;
+/// LOS: the input clock signal is lost
+const LOS: u8 = 1 << 4;
+
+/// How [`Si5351::poll_health`] judges the device status and recovers,
+/// part of the [`DeviceConfig`](crate::DeviceConfig)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct HealthPolicy {
+    /// Consecutive polls a PLL has to be out of lock before it is
+    /// reported, so that relocking after a reconfiguration goes unnoticed
+    pub lol_polls: u8,
+    /// Consecutive polls the input signal has to be lost before it is
+    /// reported
+    pub los_polls: u8,
+    /// Write the last known configuration again after a device reset
+    pub restore_on_reset: bool,
+    /// Soft reset a PLL once it is reported out of lock
+    pub reset_unlocked_pll: bool,
+}
+
+impl Default for HealthPolicy {
+    /// Report loss of lock after two polls and loss of signal right away,
+    /// restore the configuration after a reset
+    fn default() -> Self {
+        Self {
+            lol_polls: 2,
+            los_polls: 1,
+            restore_on_reset: true,
+            reset_unlocked_pll: false,
+        }
+    }
+}
+
+/// What [`Si5351::poll_health`] found and did
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Health {
+    /// The device status (register 0)
+    pub status: u8,
+    /// The sticky status flags raised since the previous poll (register 1)
+    pub sticky: u8,
+    /// The device was reset since the previous poll
+    pub reset: bool,
+    /// The last known configuration was written again after the reset
+    pub restored: bool,
+    /// PLL A and PLL B have been out of lock for
+    /// [`HealthPolicy::lol_polls`] polls
+    pub lost_lock: [bool; 2],
+    /// PLL A and PLL B were soft reset because they lost lock
+    pub pll_reset: [bool; 2],
+    /// The input signal has been lost for [`HealthPolicy::los_polls`]
+    /// polls
+    pub lost_signal: bool,
+}
+
+impl Health {
+    /// Whether the device runs as configured: no reset, no loss of lock
+    /// and no loss of signal
+    pub fn is_healthy(&self) -> bool {
+        !self.reset && !self.lost_lock.contains(&true) && !self.lost_signal
+    }
+}
+
+/// Consecutive polls which found each condition
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct HealthCounters {
+    lol: [u8; 2],
+    los: u8,
+}
+
+impl<I2C: I2c, DELAY: DelayNs, OBS: Observer> Si5351<I2C, DELAY, OBS> {
+    /// Reads the device status and the sticky flags raised since the
+    /// previous poll, and handles what it finds as the
+    /// [`HealthPolicy`] of the [`DeviceConfig`](crate::DeviceConfig) says.
+    ///
+    /// A set SYS_INIT flag means the device was reset and lost its
+    /// configuration. Once the device has finished initialising, the
+    /// registers the driver has written or read are written again with the
+    /// sequence of [`Si5351::apply_register_map`], unless
+    /// [`HealthPolicy::restore_on_reset`] is off, in which case the
+    /// register cache is cleared.
+    ///
+    /// Loss of lock is only reported for the PLLs set up through the
+    /// driver.
+    pub fn poll_health(&mut self) -> Result<Health, Error> {
+        let policy = self.config.health;
+        let mut flags = [0_u8; 2];
+        self.read_n(Registers::DeviceStatus as u8, &mut flags)?;
+        let [status, sticky] = flags;
+        let seen = status | sticky;
+        let mut health = Health {
+            status,
+            sticky,
+            reset: seen & SYS_INIT != 0,
+            ..Health::default()
+        };
+        if health.reset {
+            debug!("Device reset, status {=u8:#04x}", status);
+            if status & SYS_INIT != 0 {
+                // Still initialising, restore on one of the next polls as
+                // the sticky flag stays set until then
+                return Ok(health);
+            }
+            self.health_counters = HealthCounters::default();
+            if policy.restore_on_reset {
+                self.restore_registers()?;
+                health.restored = true;
+            } else {
+                self.registers.clear();
+            }
+        } else {
+            let plls = [(PLL::A, LOL_A, 1 << 5), (PLL::B, LOL_B, 1 << 7)];
+            for (index, (pll, flag, reset)) in plls.into_iter().enumerate() {
+                let unlocked = seen & flag != 0 && self.pll_configured(pll);
+                let count = &mut self.health_counters.lol[index];
+                *count = if unlocked { count.saturating_add(1) } else { 0 };
+                if *count >= policy.lol_polls.max(1) {
+                    debug!("PLL {} out of lock for {=u8} polls", pll, *count);
+                    health.lost_lock[index] = true;
+                    if policy.reset_unlocked_pll {
+                        self.write8(Registers::PLLReset as u8, reset)?;
+                        self.health_counters.lol[index] = 0;
+                        health.pll_reset[index] = true;
+                    }
+                }
+            }
+            let count = &mut self.health_counters.los;
+            *count = if seen & LOS != 0 {
+                count.saturating_add(1)
+            } else {
+                0
+            };
+            health.lost_signal = *count >= policy.los_polls.max(1);
+        }
+        // Clear the sticky flags for the next poll
+        self.write8(Registers::InterruptStatusSticky as u8, 0)?;
+        Ok(health)
+    }
+
+    /// Writes all registers known to the driver again while the outputs
+    /// are disabled, resets the PLLs and enables the outputs as before
+    fn restore_registers(&mut self) -> Result<(), Error> {
+        let image = self.registers;
+        let enables = image
+            .get(Registers::OutputEnableControl as u8)
+            .unwrap_or(0xff);
+        let mut selected: RegisterSet = [0; (LAST_REGISTER as usize + 32) / 32];
+        for reg in 0..=LAST_REGISTER {
+            if !is_volatile(reg) && reg != Registers::OutputEnableControl as u8 {
+                selected[reg as usize / 32] |= 1 << (reg % 32);
+            }
+        }
+        self.write8(Registers::OutputEnableControl as u8, 0xff)?;
+        write_runs(self, &image, &selected)?;
+        self.write8(Registers::PLLReset as u8, (1 << 7) | (1 << 5))?;
+        self.write8(Registers::OutputEnableControl as u8, enables)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{FaultyI2c, RegisterFile};
+    use crate::{DeviceConfig, Si5351};
+
+    /// The PLL A, PLL B and multisynth 0 parameters
+    const SETUP: u8 = 26;
+    const SETUP_LEN: usize = 24;
+
+    fn config(health: HealthPolicy) -> DeviceConfig {
+        DeviceConfig {
+            health,
+            ..DeviceConfig::default()
+        }
+    }
+
+    /// A driver with CLK0 at 10MHz on a device which is reset just before
+    /// the next transaction
+    fn reset_device(health: HealthPolicy) -> Si5351<FaultyI2c> {
+        let bus = FaultyI2c::new(RegisterFile::new());
+        let mut clock_gen = Si5351::new(bus, config(health)).unwrap();
+        clock_gen.set_freq(0, PLL::A, 10_000_000).unwrap();
+        clock_gen.enable_outputs(true).unwrap();
+        let next = clock_gen.i2c_dev.transactions();
+        clock_gen.i2c_dev = clock_gen.i2c_dev.clone().reset_on_transaction(next);
+        clock_gen
+    }
+
+    /// Reads the setup registers the way the device presents them
+    fn read_setup(clock_gen: &mut Si5351<FaultyI2c>) -> [u8; SETUP_LEN] {
+        let mut values = [0; SETUP_LEN];
+        clock_gen
+            .i2c_dev
+            .write_read(0x60, &[SETUP], &mut values)
+            .unwrap();
+        values
+    }
+
+    #[test]
+    fn restores_configuration_after_reset() {
+        let mut clock_gen = reset_device(HealthPolicy::default());
+        let start = SETUP as usize;
+        let setup = clock_gen.i2c_dev.inner().registers()[start..start + SETUP_LEN].to_vec();
+        // Still initialising
+        let health = clock_gen.poll_health().unwrap();
+        assert!(health.reset && !health.restored);
+        assert_eq!(read_setup(&mut clock_gen), [0; SETUP_LEN]);
+        let health = clock_gen.poll_health().unwrap();
+        assert!(health.reset && health.restored);
+        assert!(!health.is_healthy());
+        assert_eq!(read_setup(&mut clock_gen).to_vec(), setup);
+        assert_eq!(clock_gen.i2c_dev.inner().registers()[3], 0x00);
+        // The sticky flag was cleared
+        assert!(clock_gen.poll_health().unwrap().is_healthy());
+    }
+
+    #[test]
+    fn forgets_configuration_after_reset_without_restore() {
+        let mut clock_gen = reset_device(HealthPolicy {
+            restore_on_reset: false,
+            ..HealthPolicy::default()
+        });
+        assert!(clock_gen.poll_health().unwrap().reset);
+        let health = clock_gen.poll_health().unwrap();
+        assert!(health.reset && !health.restored);
+        assert_eq!(read_setup(&mut clock_gen), [0; SETUP_LEN]);
+        let mut setup = SETUP..SETUP + SETUP_LEN as u8;
+        assert!(setup.all(|reg| clock_gen.registers.get(reg).is_none()));
+        assert!(clock_gen.poll_health().unwrap().is_healthy());
+    }
+
+    fn unlocked(health: HealthPolicy) -> Si5351<RegisterFile> {
+        let mut clock_gen = Si5351::new(RegisterFile::new(), config(health)).unwrap();
+        clock_gen.set_freq(0, PLL::A, 10_000_000).unwrap();
+        // Only PLL A is set up, its loss of lock is reported
+        clock_gen.i2c_dev.registers_mut()[0] = LOL_A | LOL_B;
+        clock_gen
+    }
+
+    #[test]
+    fn reports_loss_of_lock_after_threshold() {
+        let mut clock_gen = unlocked(HealthPolicy {
+            lol_polls: 3,
+            ..HealthPolicy::default()
+        });
+        let mut poll = || clock_gen.poll_health().unwrap().lost_lock;
+        assert_eq!(poll(), [false, false]);
+        assert_eq!(poll(), [false, false]);
+        assert_eq!(poll(), [true, false]);
+        assert_eq!(poll(), [true, false]);
+        // Relocking starts the count again
+        clock_gen.i2c_dev.registers_mut()[0] = 0;
+        assert_eq!(clock_gen.poll_health().unwrap().lost_lock, [false, false]);
+        clock_gen.i2c_dev.registers_mut()[0] = LOL_A;
+        assert_eq!(clock_gen.poll_health().unwrap().lost_lock, [false, false]);
+    }
+
+    #[test]
+    fn resets_unlocked_pll() {
+        let mut clock_gen = unlocked(HealthPolicy {
+            lol_polls: 1,
+            reset_unlocked_pll: true,
+            ..HealthPolicy::default()
+        });
+        let health = clock_gen.poll_health().unwrap();
+        assert_eq!(health.lost_lock, [true, false]);
+        assert_eq!(health.pll_reset, [true, false]);
+        assert_eq!(clock_gen.i2c_dev.registers()[177], 1 << 5);
+    }
+}
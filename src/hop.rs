@@ -0,0 +1,237 @@
+//! Precomputed frequency hopping tables for FSK and frequency hopping.
+//!
+//! All entries of a [`HopTable`] run from the same PLL setting, so hopping
+//! only rewrites the multisynth parameter registers which differ between the
+//! current and the next entry, in a single burst and without a PLL reset.
+
+use embedded_hal::delay::DelayNs;
+use embedded_hal::i2c::I2c;
+
+use crate::freqplan::{Ratio, encode_divider, multisynth_divider, pack_parameters};
+use crate::{Error, Observer, PLL, RDiv, Registers, Si5351, check};
+
+/// Register images of a multisynth for `N` frequencies sharing one PLL
+/// setting, built with [`HopTable::new`] and applied with [`Si5351::hop`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct HopTable<const N: usize> {
+    output: usize,
+    pll: PLL,
+    pll_ratio: Ratio,
+    ratios: [Ratio; N],
+    r_divs: [RDiv; N],
+    images: [[u8; 8]; N],
+    freqs: [u64; N],
+    max_hop_len: usize,
+}
+
+impl<const N: usize> HopTable<N> {
+    /// Computes the multisynth settings of an output for each frequency,
+    /// keeping the PLL it is currently running from as it is.
+    ///
+    /// Returns `Error::InvalidParameter` if the output has not been
+    /// configured yet, or if any of the frequencies cannot be reached from
+    /// the current PLL frequency (which would need a PLL change).
+    ///
+    /// si5351: The driver, with the output already configured
+    ///
+    /// output: The output channel to hop (0..2)
+    ///
+    /// freqs: The frequencies in millihertz
+    pub fn new<I2C: I2c, DELAY: DelayNs, OBS: Observer>(
+        si5351: &Si5351<I2C, DELAY, OBS>,
+        output: usize,
+        freqs: [u64; N],
+    ) -> Result<Self, Error> {
+        check(output < 3, Error::InvalidParameter)?;
+        let state = si5351.outputs[output];
+        check(state.configured, Error::InvalidParameter)?;
+        let pll_ratio = si5351.pll_ratio(state.pll);
+        let vco_numerator = si5351.crystal_millihertz()
+            * (pll_ratio.a as u128 * pll_ratio.c as u128 + pll_ratio.b as u128);
+        let mut ratios = [Ratio::new(0, 0, 1); N];
+        let mut r_divs = [RDiv::Div1; N];
+        let mut images = [[0; 8]; N];
+        for (i, &freq) in freqs.iter().enumerate() {
+            check(freq > 0, Error::InvalidParameter)?;
+            // Hops never switch to integer mode
+            let (ratio, r_div) =
+                multisynth_divider(vco_numerator, pll_ratio.c as u128, freq, (0, 7), false)?;
+            let (p1, p2, p3) = encode_divider(ratio.a, ratio.b, ratio.c);
+            ratios[i] = ratio;
+            r_divs[i] = r_div;
+            images[i] = pack_parameters(p1, p2, p3, (r_div as u8) << 4);
+        }
+        let mut max_hop_len = 0;
+        for from in images.iter() {
+            for to in images.iter() {
+                max_hop_len = max_hop_len.max(changed_span(from, to));
+            }
+        }
+        Ok(Self {
+            output,
+            pll: state.pll,
+            pll_ratio,
+            ratios,
+            r_divs,
+            images,
+            freqs,
+            max_hop_len,
+        })
+    }
+
+    /// The output this table hops
+    pub fn output(&self) -> usize {
+        self.output
+    }
+
+    /// The frequencies of the table, in millihertz
+    pub fn freqs(&self) -> &[u64; N] {
+        &self.freqs
+    }
+
+    /// The highest number of registers a hop between two entries writes.
+    /// The burst additionally carries the register address byte.
+    pub fn max_hop_len(&self) -> usize {
+        self.max_hop_len
+    }
+}
+
+/// Number of registers from the first to the last one that differ
+fn changed_span(from: &[u8; 8], to: &[u8; 8]) -> usize {
+    let first = from.iter().zip(to).position(|(a, b)| a != b);
+    let last = from.iter().zip(to).rposition(|(a, b)| a != b);
+    match (first, last) {
+        (Some(first), Some(last)) => last - first + 1,
+        _ => 0,
+    }
+}
+
+impl<I2C: I2c, DELAY: DelayNs, OBS: Observer> Si5351<I2C, DELAY, OBS> {
+    /// Switches the output of a [`HopTable`] to the entry at `index`.
+    ///
+    /// Only the multisynth registers which differ from the current ones are
+    /// written, in a single burst. The first hop also switches the output to
+    /// fractional mode. Returns the number of registers written.
+    ///
+    /// Returns `Error::InvalidParameter` if the PLL has been changed since the
+    /// table was built.
+    pub fn hop<const N: usize>(
+        &mut self,
+        table: &HopTable<N>,
+        index: usize,
+    ) -> Result<usize, Error> {
+        check(index < N, Error::InvalidParameter)?;
+        let output = table.output;
+        check(
+            self.outputs[output].configured
+                && self.outputs[output].pll == table.pll
+                && self.pll_ratio(table.pll) == table.pll_ratio,
+            Error::InvalidParameter,
+        )?;
+        let base_addr = Registers::Multisynth0Parameters1 as u8 + 8 * output as u8;
+        let written = self.write_changed(base_addr, &table.images[index])?;
+        let rdiv_bits = (table.r_divs[index] as u8) << 4;
+        self.last_rdiv_value[output] = rdiv_bits;
+        self.outputs[output].ms_ratio = table.ratios[index];
+        self.outputs[output].r_div = table.r_divs[index];
+        // All entries run in fractional mode so hops never touch the clk control
+        let control_reg = Registers::CLK0Control as u8 + output as u8;
+        if let Some(control) = self.registers.get(control_reg) {
+            self.write_changed(control_reg, &[control & !(1 << 6)])?;
+        }
+        Ok(written)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::RegisterFile;
+    use crate::{Access, DeviceConfig, NoDelay, Recorder};
+
+    type Driver = Si5351<RegisterFile, NoDelay, Recorder<16, 8>>;
+
+    /// WSPR-like tones 1.46Hz apart on 14.0971MHz
+    const TONES: [u64; 2] = [14_097_100_000, 14_097_101_465];
+
+    fn driver() -> Driver {
+        let mut clock_gen = Si5351::with_observer(
+            RegisterFile::new(),
+            DeviceConfig::default(),
+            NoDelay,
+            Recorder::new(),
+        )
+        .unwrap();
+        clock_gen.set_freq(0, PLL::A, 14_097_100).unwrap();
+        clock_gen
+    }
+
+    #[test]
+    fn rejects_unreachable_freqs() {
+        let clock_gen = driver();
+        // The multisynth cannot divide by less than 8 or, with the largest
+        // R divider, by more than 2048 * 128
+        for freq in [200_000_000_000, 1_000, 0] {
+            assert_eq!(
+                HopTable::new(&clock_gen, 0, [TONES[0], freq]),
+                Err(Error::InvalidParameter)
+            );
+        }
+        assert_eq!(
+            HopTable::new(&clock_gen, 1, TONES),
+            Err(Error::InvalidParameter)
+        );
+        assert_eq!(
+            HopTable::new(&clock_gen, 3, TONES),
+            Err(Error::InvalidParameter)
+        );
+    }
+
+    #[test]
+    fn spans_changed_registers() {
+        let from = [1, 2, 3, 4, 5, 6, 7, 8];
+        assert_eq!(changed_span(&from, &from), 0);
+        assert_eq!(changed_span(&from, &[1, 2, 3, 4, 5, 6, 7, 9]), 1);
+        assert_eq!(changed_span(&from, &[1, 0, 3, 4, 5, 6, 0, 8]), 6);
+        assert_eq!(changed_span(&from, &[0; 8]), 8);
+    }
+
+    #[test]
+    fn hops_write_differing_registers_only() {
+        let mut clock_gen = driver();
+        let table = HopTable::new(&clock_gen, 0, TONES).unwrap();
+        clock_gen.hop(&table, 0).unwrap();
+        clock_gen.observer_mut().clear();
+        let span = changed_span(&table.images[0], &table.images[1]);
+        assert!(span > 0 && span < 8 && span <= table.max_hop_len());
+        assert_eq!(clock_gen.hop(&table, 1), Ok(span));
+        let records: Vec<_> = clock_gen.observer().iter().copied().collect();
+        let first = table.images[0]
+            .iter()
+            .zip(&table.images[1])
+            .position(|(a, b)| a != b)
+            .unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].access, Access::Write);
+        assert_eq!(records[0].reg, 42 + first as u8);
+        assert_eq!(records[0].data(), &table.images[1][first..first + span]);
+        let freq = clock_gen.output_freq_millihertz(0).unwrap();
+        assert!(freq.abs_diff(TONES[1]) < 500, "{freq}");
+        // Nothing left to write
+        clock_gen.observer_mut().clear();
+        assert_eq!(clock_gen.hop(&table, 1), Ok(0));
+        assert!(clock_gen.observer().is_empty());
+    }
+
+    #[test]
+    fn refuses_hop_after_pll_change() {
+        let mut clock_gen = driver();
+        let table = HopTable::new(&clock_gen, 0, TONES).unwrap();
+        clock_gen.set_freq(1, PLL::A, 10_000_000).unwrap();
+        clock_gen.observer_mut().clear();
+        assert_eq!(clock_gen.hop(&table, 0), Err(Error::InvalidParameter));
+        assert_eq!(clock_gen.hop(&table, 2), Err(Error::InvalidParameter));
+        assert!(clock_gen.observer().is_empty());
+    }
+}
@@ -0,0 +1,513 @@
+//! CW and RTTY keyer.
+//!
+//! [`Si5351::send_cw`] sends Morse code by switching a single output on and
+//! off with [`Si5351::enable_output`], so the other outputs keep running.
+//! [`Si5351::send_rtty`] sends Baudot (ITA2) text at 45.45 baud by hopping
+//! an output between the mark and space frequencies, 170Hz apart:
+//!
+//! ```no_run
+//! # fn example<I2C: embedded_hal::i2c::I2c, D: embedded_hal::delay::DelayNs>(
+//! #     clock_gen: &mut si5351a_adafruit::Si5351<I2C>,
+//! #     delay: &mut D,
+//! # ) -> Result<(), si5351a_adafruit::Error> {
+//! use si5351a_adafruit::PLL;
+//! use si5351a_adafruit::keyer::CwTiming;
+//!
+//! clock_gen.set_freq(0, PLL::A, 7_030_000)?;
+//! // 20 WPM characters, spaced out to 12 WPM
+//! clock_gen.send_cw(0, "CQ CQ DE K1ABC", &CwTiming::farnsworth(20, 12)?, delay)?;
+//!
+//! clock_gen.send_rtty(0, 14_085_000_000, "RYRYRY DE K1ABC\n", delay)?;
+//! # Ok(())
+//! # }
+//! ```
+
+use embedded_hal::delay::DelayNs;
+use embedded_hal::i2c::I2c;
+
+use crate::{Error, HopTable, Observer, Si5351, check};
+
+/// RTTY bit duration (1 / 45.45 s) in nanoseconds
+pub const RTTY_BIT_DURATION_NS: u32 = 22_002_200;
+
+/// RTTY shift between mark and space in millihertz
+pub const RTTY_SHIFT_MILLIHERTZ: u64 = 170_000;
+
+/// Element and gap durations of Morse code
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct CwTiming {
+    dot_ns: u64,
+    char_gap_ns: u64,
+    word_gap_ns: u64,
+}
+
+impl CwTiming {
+    /// Standard timing at `wpm` words per minute (PARIS, 1.2s / wpm per dot)
+    ///
+    /// wpm: The speed in words per minute (1..100)
+    pub fn new(wpm: u32) -> Result<Self, Error> {
+        Self::farnsworth(wpm, wpm)
+    }
+
+    /// Farnsworth timing: the characters are sent at `char_wpm`, the gaps
+    /// between characters and words are stretched so the overall speed is
+    /// `effective_wpm`
+    ///
+    /// char_wpm: The character speed in words per minute (1..100)
+    ///
+    /// effective_wpm: The overall speed in words per minute (1..char_wpm)
+    pub fn farnsworth(char_wpm: u32, effective_wpm: u32) -> Result<Self, Error> {
+        check((1..=100).contains(&char_wpm), Error::InvalidParameter)?;
+        check(
+            (1..=char_wpm).contains(&effective_wpm),
+            Error::InvalidParameter,
+        )?;
+        let (char_wpm, effective_wpm) = (char_wpm as u64, effective_wpm as u64);
+        let dot_ns = 1_200_000_000 / char_wpm;
+        // PARIS is 31 units of elements and 19 units of gaps. Spread the
+        // time left for the gaps at the effective speed over the 19 units.
+        let gap_unit_ns = (60_000_000_000 * char_wpm - 37_200_000_000 * effective_wpm)
+            / (effective_wpm * char_wpm)
+            / 19;
+        Ok(Self {
+            dot_ns,
+            char_gap_ns: 3 * gap_unit_ns,
+            word_gap_ns: 7 * gap_unit_ns,
+        })
+    }
+
+    /// Duration of a dot (and of the gap between elements) in nanoseconds
+    pub fn dot_ns(&self) -> u64 {
+        self.dot_ns
+    }
+
+    /// Duration of the gap between characters in nanoseconds
+    pub fn char_gap_ns(&self) -> u64 {
+        self.char_gap_ns
+    }
+
+    /// Duration of the gap between words in nanoseconds
+    pub fn word_gap_ns(&self) -> u64 {
+        self.word_gap_ns
+    }
+}
+
+/// Morse code of a character, as dots and dashes
+fn morse(c: u8) -> Option<&'static [u8]> {
+    let code: &[u8] = match c.to_ascii_uppercase() {
+        b'A' => b".-",
+        b'B' => b"-...",
+        b'C' => b"-.-.",
+        b'D' => b"-..",
+        b'E' => b".",
+        b'F' => b"..-.",
+        b'G' => b"--.",
+        b'H' => b"....",
+        b'I' => b"..",
+        b'J' => b".---",
+        b'K' => b"-.-",
+        b'L' => b".-..",
+        b'M' => b"--",
+        b'N' => b"-.",
+        b'O' => b"---",
+        b'P' => b".--.",
+        b'Q' => b"--.-",
+        b'R' => b".-.",
+        b'S' => b"...",
+        b'T' => b"-",
+        b'U' => b"..-",
+        b'V' => b"...-",
+        b'W' => b".--",
+        b'X' => b"-..-",
+        b'Y' => b"-.--",
+        b'Z' => b"--..",
+        b'0' => b"-----",
+        b'1' => b".----",
+        b'2' => b"..---",
+        b'3' => b"...--",
+        b'4' => b"....-",
+        b'5' => b".....",
+        b'6' => b"-....",
+        b'7' => b"--...",
+        b'8' => b"---..",
+        b'9' => b"----.",
+        b'.' => b".-.-.-",
+        b',' => b"--..--",
+        b'?' => b"..--..",
+        b'/' => b"-..-.",
+        b'=' => b"-...-",
+        b'+' => b".-.-.",
+        b'-' => b"-....-",
+        b'(' => b"-.--.",
+        b')' => b"-.--.-",
+        b'\'' => b".----.",
+        b':' => b"---...",
+        b'@' => b".--.-.",
+        _ => return None,
+    };
+    Some(code)
+}
+
+/// Shift into figures
+const BAUDOT_FIGS: u8 = 0x1b;
+
+/// Shift into letters
+const BAUDOT_LTRS: u8 = 0x1f;
+
+/// Whether a Baudot character needs the letters or the figures shift
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Shift {
+    /// Valid in both shifts (space, carriage return, line feed)
+    Any,
+    Letters,
+    Figures,
+}
+
+/// ITA2 code of a character
+fn baudot(c: u8) -> Option<(Shift, u8)> {
+    let code = match c.to_ascii_uppercase() {
+        b' ' => (Shift::Any, 0x04),
+        b'\r' => (Shift::Any, 0x08),
+        b'\n' => (Shift::Any, 0x02),
+        b'A' => (Shift::Letters, 0x03),
+        b'B' => (Shift::Letters, 0x19),
+        b'C' => (Shift::Letters, 0x0e),
+        b'D' => (Shift::Letters, 0x09),
+        b'E' => (Shift::Letters, 0x01),
+        b'F' => (Shift::Letters, 0x0d),
+        b'G' => (Shift::Letters, 0x1a),
+        b'H' => (Shift::Letters, 0x14),
+        b'I' => (Shift::Letters, 0x06),
+        b'J' => (Shift::Letters, 0x0b),
+        b'K' => (Shift::Letters, 0x0f),
+        b'L' => (Shift::Letters, 0x12),
+        b'M' => (Shift::Letters, 0x1c),
+        b'N' => (Shift::Letters, 0x0c),
+        b'O' => (Shift::Letters, 0x18),
+        b'P' => (Shift::Letters, 0x16),
+        b'Q' => (Shift::Letters, 0x17),
+        b'R' => (Shift::Letters, 0x0a),
+        b'S' => (Shift::Letters, 0x05),
+        b'T' => (Shift::Letters, 0x10),
+        b'U' => (Shift::Letters, 0x07),
+        b'V' => (Shift::Letters, 0x1e),
+        b'W' => (Shift::Letters, 0x13),
+        b'X' => (Shift::Letters, 0x1d),
+        b'Y' => (Shift::Letters, 0x15),
+        b'Z' => (Shift::Letters, 0x11),
+        b'0' => (Shift::Figures, 0x16),
+        b'1' => (Shift::Figures, 0x17),
+        b'2' => (Shift::Figures, 0x13),
+        b'3' => (Shift::Figures, 0x01),
+        b'4' => (Shift::Figures, 0x0a),
+        b'5' => (Shift::Figures, 0x10),
+        b'6' => (Shift::Figures, 0x15),
+        b'7' => (Shift::Figures, 0x07),
+        b'8' => (Shift::Figures, 0x06),
+        b'9' => (Shift::Figures, 0x18),
+        b'-' => (Shift::Figures, 0x03),
+        b'?' => (Shift::Figures, 0x19),
+        b':' => (Shift::Figures, 0x0e),
+        b'\'' => (Shift::Figures, 0x0b),
+        b'(' => (Shift::Figures, 0x0f),
+        b')' => (Shift::Figures, 0x12),
+        b'.' => (Shift::Figures, 0x1c),
+        b',' => (Shift::Figures, 0x0c),
+        b'=' => (Shift::Figures, 0x1e),
+        b'/' => (Shift::Figures, 0x1d),
+        b'+' => (Shift::Figures, 0x11),
+        _ => return None,
+    };
+    Some(code)
+}
+
+/// The ITA2 codes to send for `text`: a letters shift first, then the
+/// characters with letters/figures shifts inserted as needed and a carriage
+/// return before every line feed. Characters without a code are skipped.
+fn baudot_codes(text: &str) -> impl Iterator<Item = u8> + '_ {
+    let mut shift = Shift::Letters;
+    let codes = text.bytes().filter_map(move |c| {
+        let (needed, code) = baudot(c)?;
+        let shift_code = if needed != Shift::Any && needed != shift {
+            shift = needed;
+            match needed {
+                Shift::Figures => Some(BAUDOT_FIGS),
+                _ => Some(BAUDOT_LTRS),
+            }
+        } else {
+            None
+        };
+        let carriage_return = (c == b'\n').then_some(0x08);
+        Some([shift_code, carriage_return, Some(code)])
+    });
+    core::iter::once([Some(BAUDOT_LTRS), None, None])
+        .chain(codes)
+        .flatten()
+        .flatten()
+}
+
+/// Waits `ns` nanoseconds, which may be more than `delay_ns` takes at once
+fn delay_long<D: DelayNs>(delay: &mut D, ns: u64) {
+    let ms = ns / 1_000_000;
+    if ms > 0 {
+        // Below 2^32 ms for any CwTiming
+        delay.delay_ms(ms as u32);
+    }
+    delay.delay_ns((ns % 1_000_000) as u32);
+}
+
+/// Hop table index of the mark tone
+const MARK: usize = 0;
+
+/// Hop table index of the space tone
+const SPACE: usize = 1;
+
+impl<I2C: I2c, DELAY: DelayNs, OBS: Observer> Si5351<I2C, DELAY, OBS> {
+    /// Sends Morse code by keying an already configured output.
+    ///
+    /// The output is switched off first and is left off. Only the enable
+    /// bit of this output changes, the other outputs keep running. Letters,
+    /// digits and common punctuation are supported, runs of whitespace are
+    /// sent as a single word gap.
+    ///
+    /// Returns `Error::InvalidParameter` before keying anything if the text
+    /// contains a character without a Morse code.
+    ///
+    /// output: The output channel to key (0..2)
+    ///
+    /// text: The text to send
+    ///
+    /// timing: The element and gap durations
+    ///
+    /// delay: Used for the element timing
+    pub fn send_cw<D: DelayNs>(
+        &mut self,
+        output: usize,
+        text: &str,
+        timing: &CwTiming,
+        delay: &mut D,
+    ) -> Result<(), Error> {
+        check(
+            text.bytes()
+                .all(|c| c.is_ascii_whitespace() || morse(c).is_some()),
+            Error::InvalidParameter,
+        )?;
+        self.enable_output(output, false)?;
+        for (i, word) in text.split_ascii_whitespace().enumerate() {
+            if i > 0 {
+                delay_long(delay, timing.word_gap_ns);
+            }
+            for (j, c) in word.bytes().enumerate() {
+                if j > 0 {
+                    delay_long(delay, timing.char_gap_ns);
+                }
+                for (k, &element) in morse(c).unwrap_or_default().iter().enumerate() {
+                    if k > 0 {
+                        delay_long(delay, timing.dot_ns);
+                    }
+                    self.enable_output(output, true)?;
+                    let duration = match element {
+                        b'-' => 3 * timing.dot_ns,
+                        _ => timing.dot_ns,
+                    };
+                    delay_long(delay, duration);
+                    self.enable_output(output, false)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Sends RTTY: ITA2 Baudot at 45.45 baud with 170Hz shift, one start
+    /// bit and 1.5 stop bits.
+    ///
+    /// The output is tuned to the mark frequency (which may need a full
+    /// reconfiguration) and enabled. Mark and space are precomputed as a
+    /// [`HopTable`], so every bit is a single short burst without a PLL
+    /// reset. Letters/figures shifts are inserted as needed, starting with
+    /// a letters shift. `\n` is sent as carriage return and line feed.
+    ///
+    /// Returns `Error::InvalidParameter` before transmitting anything if the
+    /// text contains a character without an ITA2 code.
+    ///
+    /// The output is left running on the mark frequency.
+    ///
+    /// output: The output channel to use (0..2)
+    ///
+    /// mark: The mark frequency in millihertz, space is 170Hz below
+    ///
+    /// text: The text to send
+    ///
+    /// delay: Used for the bit timing
+    pub fn send_rtty<D: DelayNs>(
+        &mut self,
+        output: usize,
+        mark: u64,
+        text: &str,
+        delay: &mut D,
+    ) -> Result<(), Error> {
+        check(
+            text.bytes().all(|c| baudot(c).is_some()),
+            Error::InvalidParameter,
+        )?;
+        check(mark > RTTY_SHIFT_MILLIHERTZ, Error::InvalidParameter)?;
+        self.retune_millihertz(output, mark)?;
+        let table = HopTable::new(self, output, [mark, mark - RTTY_SHIFT_MILLIHERTZ])?;
+        self.hop(&table, MARK)?;
+        self.enable_output(output, true)?;
+        // Idle on mark for a character before the first start bit
+        delay.delay_ns(7 * RTTY_BIT_DURATION_NS + RTTY_BIT_DURATION_NS / 2);
+        for code in baudot_codes(text) {
+            self.send_baudot(&table, code, delay)?;
+        }
+        Ok(())
+    }
+
+    /// Sends one start bit (space), the 5 data bits least significant bit
+    /// first (1 is mark) and 1.5 stop bits (mark)
+    fn send_baudot<D: DelayNs>(
+        &mut self,
+        table: &HopTable<2>,
+        code: u8,
+        delay: &mut D,
+    ) -> Result<(), Error> {
+        self.hop(table, SPACE)?;
+        delay.delay_ns(RTTY_BIT_DURATION_NS);
+        for bit in 0..5 {
+            self.hop(table, if code & (1 << bit) != 0 { MARK } else { SPACE })?;
+            delay.delay_ns(RTTY_BIT_DURATION_NS);
+        }
+        self.hop(table, MARK)?;
+        delay.delay_ns(RTTY_BIT_DURATION_NS + RTTY_BIT_DURATION_NS / 2);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{FaultyI2c, RegisterFile};
+    use crate::{DeviceConfig, NoDelay, PLL};
+
+    fn codes(text: &str) -> Vec<u8> {
+        baudot_codes(text).collect()
+    }
+
+    #[test]
+    fn inserts_shifts() {
+        // LTRS R Y
+        assert_eq!(codes("RY"), [BAUDOT_LTRS, 0x0a, 0x15]);
+        // LTRS D E space FIGS 7 3 LTRS K
+        assert_eq!(
+            codes("de 73k"),
+            [
+                BAUDOT_LTRS,
+                0x09,
+                0x01,
+                0x04,
+                BAUDOT_FIGS,
+                0x07,
+                0x01,
+                BAUDOT_LTRS,
+                0x0f
+            ]
+        );
+        // Space and line breaks do not change the shift
+        assert_eq!(
+            codes("1 2\n3"),
+            [BAUDOT_LTRS, BAUDOT_FIGS, 0x17, 0x04, 0x13, 0x08, 0x02, 0x01]
+        );
+        assert_eq!(codes(""), [BAUDOT_LTRS]);
+    }
+
+    #[test]
+    fn rejects_unmappable_characters_before_keying() {
+        let bus = FaultyI2c::new(RegisterFile::new());
+        let mut clock_gen = Si5351::new(bus, DeviceConfig::default()).unwrap();
+        clock_gen.set_freq(0, PLL::A, 7_030_000).unwrap();
+        let transactions = clock_gen.i2c_dev.transactions();
+        let timing = CwTiming::new(20).unwrap();
+        for text in ["CQ #", "K1ABC!", "73 ä"] {
+            assert_eq!(
+                clock_gen.send_cw(0, text, &timing, &mut NoDelay),
+                Err(Error::InvalidParameter)
+            );
+        }
+        for text in ["CQ @", "K1ABC!", "73 *"] {
+            assert_eq!(
+                clock_gen.send_rtty(0, 14_085_000_000, text, &mut NoDelay),
+                Err(Error::InvalidParameter)
+            );
+        }
+        assert_eq!(clock_gen.i2c_dev.transactions(), transactions);
+    }
+
+    #[test]
+    fn standard_timing() {
+        let timing = CwTiming::new(20).unwrap();
+        assert_eq!(timing.dot_ns(), 60_000_000);
+        assert_eq!(timing.char_gap_ns(), 180_000_000);
+        assert_eq!(timing.word_gap_ns(), 420_000_000);
+    }
+
+    #[test]
+    fn farnsworth_timing() {
+        let timing = CwTiming::farnsworth(18, 5).unwrap();
+        assert_eq!(timing.dot_ns(), 66_666_666);
+        // (60s * 18 - 37.2s * 5) / (5 * 18) / 19 per gap unit
+        assert_eq!(timing.char_gap_ns(), 3 * 522_807_017);
+        assert_eq!(timing.word_gap_ns(), 7 * 522_807_017);
+        // PARIS: 31 element units and 19 gap units take 12s at 5 WPM
+        let paris = 31 * timing.dot_ns() + 19 * timing.word_gap_ns() / 7;
+        assert!(paris.abs_diff(12_000_000_000) < 100, "{paris}");
+    }
+
+    #[test]
+    fn slowest_timing() {
+        let timing = CwTiming::new(1).unwrap();
+        assert_eq!(timing.dot_ns(), 1_200_000_000);
+        assert_eq!(timing.char_gap_ns(), 3_600_000_000);
+        assert_eq!(timing.word_gap_ns(), 8_400_000_000);
+    }
+
+    #[test]
+    fn farnsworth_timing_with_large_ratio() {
+        let timing = CwTiming::farnsworth(100, 1).unwrap();
+        assert_eq!(timing.dot_ns(), 12_000_000);
+        // (60s * 100 - 37.2s) / 100 / 19 per gap unit
+        assert_eq!(timing.char_gap_ns(), 3 * 3_138_315_789);
+        assert_eq!(timing.word_gap_ns(), 7 * 3_138_315_789);
+        assert!(CwTiming::farnsworth(18, 4).is_ok());
+    }
+
+    /// Adds up the time waited
+    #[derive(Default)]
+    struct TotalDelay(u64);
+
+    impl DelayNs for TotalDelay {
+        fn delay_ns(&mut self, ns: u32) {
+            self.0 += ns as u64;
+        }
+    }
+
+    #[test]
+    fn waits_gaps_longer_than_delay_ns_takes() {
+        let mut clock_gen = Si5351::new(RegisterFile::new(), DeviceConfig::default()).unwrap();
+        clock_gen.set_freq(0, PLL::A, 7_030_000).unwrap();
+        let timing = CwTiming::new(1).unwrap();
+        let mut delay = TotalDelay::default();
+        // Dot, word gap, dash
+        clock_gen.send_cw(0, "E T", &timing, &mut delay).unwrap();
+        assert_eq!(delay.0, 1_200_000_000 + 8_400_000_000 + 3_600_000_000);
+    }
+
+    #[test]
+    fn rejects_invalid_speeds() {
+        assert_eq!(CwTiming::new(0), Err(Error::InvalidParameter));
+        assert_eq!(CwTiming::new(101), Err(Error::InvalidParameter));
+        assert_eq!(CwTiming::farnsworth(18, 19), Err(Error::InvalidParameter));
+        assert_eq!(CwTiming::farnsworth(18, 0), Err(Error::InvalidParameter));
+    }
+}
@@ -0,0 +1,104 @@
+//! The original two step `new()` + `begin(i2c)` API, kept for existing
+//! code.
+
+use embedded_hal::i2c::I2c;
+
+use crate::{DeviceConfig, Error, MultisynthDiv, PLL, RDiv, Si5351};
+
+/// Driver which is created empty and initialised later with
+/// [`LegacySi5351::begin`], checking on every call that it has been.
+#[deprecated(note = "use `Si5351::new(i2c, config)`, which returns an initialised driver")]
+pub struct LegacySi5351<I2C: I2c> {
+    driver: Option<Si5351<I2C>>,
+}
+
+#[allow(deprecated)]
+impl<I2C: I2c> Default for LegacySi5351<I2C> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[allow(deprecated)]
+impl<I2C: I2c> LegacySi5351<I2C> {
+    pub fn new() -> Self {
+        Self { driver: None }
+    }
+
+    /// Initializes I2C and configures the breakout (call this function
+    /// before doing anything else)
+    ///
+    /// i2c: The I2C (Wire) bus to use.
+    pub fn begin(&mut self, i2c: I2C) -> Result<(), Error> {
+        self.driver = Some(Si5351::new(i2c, DeviceConfig::default())?);
+        Ok(())
+    }
+
+    /// The initialised driver, for everything beyond the original API
+    pub fn driver(&mut self) -> Result<&mut Si5351<I2C>, Error> {
+        self.driver.as_mut().ok_or(Error::DeviceNotInitialsed)
+    }
+
+    /// Releases the I2C bus, or `None` if `begin` was never called
+    pub fn release(self) -> Option<I2C> {
+        self.driver.map(Si5351::release)
+    }
+
+    /// See [`Si5351::set_clock_builder_data`]
+    pub fn set_clock_builder_data(&mut self) -> Result<(), Error> {
+        self.driver()?.set_clock_builder_data()
+    }
+
+    /// See [`Si5351::setup_pll`]
+    pub fn setup_pll(&mut self, pll: PLL, mult: u32, num: u32, denom: u32) -> Result<(), Error> {
+        self.driver()?.setup_pll(pll, mult, num, denom)
+    }
+
+    /// See [`Si5351::setup_pll_int`]
+    pub fn setup_pll_int(&mut self, pll: PLL, mult: u32) -> Result<(), Error> {
+        self.driver()?.setup_pll_int(pll, mult)
+    }
+
+    /// See [`Si5351::setup_multisynth`]
+    pub fn setup_multisynth(
+        &mut self,
+        output: usize,
+        pll_source: PLL,
+        div: u32,
+        num: u32,
+        denom: u32,
+    ) -> Result<(), Error> {
+        self.driver()?
+            .setup_multisynth(output, pll_source, div, num, denom)
+    }
+
+    /// See [`Si5351::setup_multisynth_int`]
+    pub fn setup_multisynth_int(
+        &mut self,
+        output: usize,
+        pll_source: PLL,
+        div: MultisynthDiv,
+    ) -> Result<(), Error> {
+        self.driver()?.setup_multisynth_int(output, pll_source, div)
+    }
+
+    /// See [`Si5351::enable_spread_spectrum`]
+    pub fn enable_spread_spectrum(&mut self, enabled: bool) -> Result<(), Error> {
+        self.driver()?.enable_spread_spectrum(enabled)
+    }
+
+    /// See [`Si5351::enable_outputs`]
+    pub fn enable_outputs(&mut self, enabled: bool) -> Result<(), Error> {
+        self.driver()?.enable_outputs(enabled)
+    }
+
+    /// See [`Si5351::setup_rdiv`]
+    pub fn setup_rdiv(&mut self, output: usize, div: RDiv) -> Result<(), Error> {
+        self.driver()?.setup_rdiv(output, div)
+    }
+
+    /// See [`Si5351::set_freq`]
+    pub fn set_freq(&mut self, output: usize, pll: PLL, freq: u32) -> Result<(), Error> {
+        self.driver()?.set_freq(output, pll, freq)
+    }
+}
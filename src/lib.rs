@@ -1,6 +1,70 @@
-#![no_std]
+#![cfg_attr(not(test), no_std)]
+#[cfg(feature = "alloc")]
+extern crate alloc;
+
+/// Trace level log through defmt, compiled out without the `defmt` feature
+macro_rules! trace {
+    ($($arg:tt)*) => {
+        #[cfg(feature = "defmt")]
+        defmt::trace!($($arg)*);
+    };
+}
+
+/// Debug level log through defmt, compiled out without the `defmt` feature
+macro_rules! debug {
+    ($($arg:tt)*) => {
+        #[cfg(feature = "defmt")]
+        defmt::debug!($($arg)*);
+    };
+}
+
+mod chipconfig;
+pub mod clockbuilder;
+mod error;
+mod fll;
+pub mod freqplan;
+#[cfg(feature = "ft8")]
+pub mod ft8;
+mod health;
+mod hop;
+#[cfg(feature = "keyer")]
+pub mod keyer;
+mod legacy;
+mod observer;
+pub mod quadrature;
+#[cfg(feature = "alloc")]
+pub mod replay;
+mod retune;
+#[cfg(feature = "serde")]
+mod serde_impls;
+mod split;
+mod sweep;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+mod transaction;
+#[cfg(feature = "wspr")]
+pub mod wspr;
+
+pub use chipconfig::{
+    ChipConfig, DisableState, OutputConfig, OutputFreq, PllConfig, SpreadMode, SpreadSpectrum,
+};
+pub use error::Error;
+pub use fll::{Fll, FllConfig};
+pub use health::{Health, HealthPolicy};
+pub use hop::HopTable;
+#[allow(deprecated)]
+pub use legacy::LegacySi5351;
+pub use observer::{Access, NoObserver, Observer, Record, Recorder};
+pub use retune::Retune;
+pub use split::{OutputHandle, SharedDriver};
+pub use sweep::{Sweep, SweepScale};
+pub use transaction::Transaction;
+
 use core::slice;
-use embedded_hal::i2c::I2c;
+use embedded_hal::delay::DelayNs;
+use embedded_hal::i2c::{Error as _, ErrorKind, I2c};
+use freqplan::{FreqPlan, PLAN_START, Plan, Ratio, encode_divider, pack_parameters};
+use health::HealthCounters;
 
 const ADDRESS: u8 = 0x60;
 #[allow(dead_code)]
@@ -21,19 +85,24 @@ const REGS_149_TO_170: [u8; 170 - 149 + 1] = [
     0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
 ];
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-#[repr(u16)]
-pub enum Error {
-    OperationTimeOut = 0x1,
-    AddressOutOfRange = 0x2,
-    BufferOverflow = 0x3,
-    InvalidParameter = 0x4,
-    DeviceNotInitialsed = 0x5,
-    UnexpectedValue = 0x6,
-    I2CDeviceNotFound = 0x101,
-    I2CNoACK = 0x102,
-    I2CTimeOut = 0x103,
-    I2CTransaction = 0x104,
+/// Address of the last register of the Si5351A
+const LAST_REGISTER: u8 = 183;
+
+/// Turns a contiguous register image into `(address, value)` pairs
+fn image_pairs(start: u8, image: &[u8]) -> impl Iterator<Item = (u8, u8)> + Clone + '_ {
+    image
+        .iter()
+        .enumerate()
+        .map(move |(i, &value)| (start.wrapping_add(i as u8), value))
+}
+
+/// Registers which do not read back the value written: the device status,
+/// the sticky interrupt flags (cleared by writing) and the self-clearing
+/// PLL reset
+fn is_volatile(reg: u8) -> bool {
+    reg == Registers::DeviceStatus as u8
+        || reg == Registers::InterruptStatusSticky as u8
+        || reg == Registers::PLLReset as u8
 }
 
 fn check(conditon: bool, error: Error) -> Result<(), Error> {
@@ -123,6 +192,8 @@ enum Registers {
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[repr(u8)]
 pub enum PLL {
     A = 0,
@@ -130,6 +201,8 @@ pub enum PLL {
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[repr(u8)]
 pub enum CrystalLoad {
     PF6 = 1 << 6,
@@ -138,13 +211,121 @@ pub enum CrystalLoad {
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+pub enum DriveStrength {
+    MA2 = 0,
+    MA4 = 1,
+    MA6 = 2,
+    MA8 = 3,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[repr(u32)]
 pub enum CrystalFreq {
     MHZ25 = 25_000_000,
     MHZ27 = 27_000_000,
 }
 
+/// The board specific settings of the device, see [`Si5351::new`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct DeviceConfig {
+    /// Frequency of the crystal
+    pub crystal_freq: CrystalFreq,
+    /// Internal load capacitance for the crystal
+    pub crystal_load: CrystalLoad,
+    /// How register transactions are retried after bus errors
+    pub retry: RetryPolicy,
+    /// Read every register write back and compare it, see
+    /// [`Error::UnexpectedValue`]
+    pub verify: bool,
+    /// How [`Si5351::poll_health`] judges the device status and recovers
+    pub health: HealthPolicy,
+}
+
+impl Default for DeviceConfig {
+    /// The Adafruit module: 25MHz crystal with 10pF load
+    fn default() -> Self {
+        Self {
+            crystal_freq: CrystalFreq::MHZ25,
+            crystal_load: CrystalLoad::PF10,
+            retry: RetryPolicy::default(),
+            verify: false,
+            health: HealthPolicy::default(),
+        }
+    }
+}
+
+/// Retrying of register transactions which failed with a bus error
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct RetryPolicy {
+    /// How often a failed transaction is repeated before giving up
+    pub retries: u8,
+    /// Delay before every retry, in nanoseconds, through the delay passed
+    /// to [`Si5351::with_delay`]
+    pub backoff_ns: u32,
+    /// Retry when the address or a data byte is not acknowledged
+    pub retry_nak: bool,
+    /// Retry on bus errors and lost arbitration
+    pub retry_bus_error: bool,
+    /// Retry on overruns and other errors of the bus driver
+    pub retry_other: bool,
+}
+
+impl Default for RetryPolicy {
+    /// No retries, every bus error is returned right away
+    fn default() -> Self {
+        Self {
+            retries: 0,
+            backoff_ns: 0,
+            retry_nak: true,
+            retry_bus_error: true,
+            retry_other: false,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Whether an error of this kind is worth retrying
+    pub fn is_retryable(&self, kind: ErrorKind) -> bool {
+        match kind {
+            ErrorKind::NoAcknowledge(_) => self.retry_nak,
+            ErrorKind::Bus | ErrorKind::ArbitrationLoss => self.retry_bus_error,
+            _ => self.retry_other,
+        }
+    }
+}
+
+/// Counters of the bus transactions which needed a retry or failed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct BusStats {
+    /// Transactions repeated after a retryable error
+    pub retries: u32,
+    /// Transactions which failed for good
+    pub failures: u32,
+}
+
+/// A [`DelayNs`] which does not wait, for drivers without retry backoff
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct NoDelay;
+
+impl DelayNs for NoDelay {
+    fn delay_ns(&mut self, _ns: u32) {}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[repr(u8)]
 pub enum MultisynthDiv {
     Div4 = 4,
@@ -153,6 +334,8 @@ pub enum MultisynthDiv {
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[repr(u8)]
 pub enum RDiv {
     Div1 = 0,
@@ -166,8 +349,13 @@ pub enum RDiv {
 }
 
 impl RDiv {
-    fn min_divider(desired_divider: u16) -> Result<Self, Error> {
-        match 16 - (desired_divider.max(1) - 1).leading_zeros() {
+    const fn min_divider(desired_divider: u32) -> Result<Self, Error> {
+        let desired_divider = if desired_divider == 0 {
+            1
+        } else {
+            desired_divider
+        };
+        match 32 - (desired_divider - 1).leading_zeros() {
             0 => Ok(RDiv::Div1),
             1 => Ok(RDiv::Div2),
             2 => Ok(RDiv::Div4),
@@ -180,88 +368,417 @@ impl RDiv {
         }
     }
 
-    fn denominator_u8(&self) -> u8 {
+    const fn denominator_u8(&self) -> u8 {
         1 << (*self as u8)
     }
 }
 
+/// Longest register burst written by `write_changed`
+const MAX_BURST: usize = 16;
+
+/// Registers included in a ClockBuilder register map export
+const EXPORT_REGISTERS: [(u8, u8); 4] = [(2, 3), (15, 92), (149, 170), (183, 183)];
+
+/// Copy of the register contents written to or read from the device
+#[derive(Clone, Copy)]
+struct RegisterCache {
+    values: [u8; LAST_REGISTER as usize + 1],
+    known: [u32; (LAST_REGISTER as usize + 32) / 32],
+}
+
+impl RegisterCache {
+    const fn new() -> Self {
+        Self {
+            values: [0; LAST_REGISTER as usize + 1],
+            known: [0; (LAST_REGISTER as usize + 32) / 32],
+        }
+    }
+
+    fn get(&self, reg: u8) -> Option<u8> {
+        let reg = reg as usize;
+        if reg < self.values.len() && self.known[reg / 32] & (1 << (reg % 32)) != 0 {
+            Some(self.values[reg])
+        } else {
+            None
+        }
+    }
+
+    fn set(&mut self, reg: u8, value: u8) {
+        let reg = reg as usize;
+        if reg < self.values.len() {
+            self.values[reg] = value;
+            self.known[reg / 32] |= 1 << (reg % 32);
+        }
+    }
+
+    fn set_n(&mut self, start: u8, values: &[u8]) {
+        for (reg, value) in image_pairs(start, values) {
+            self.set(reg, value);
+        }
+    }
+
+    fn clear(&mut self) {
+        self.known = [0; (LAST_REGISTER as usize + 32) / 32];
+    }
+}
+
+/// Registers written while a [`Transaction`] is open, held back from the
+/// device until it is committed
+#[derive(Clone, Copy)]
+struct Staged {
+    registers: [u32; (LAST_REGISTER as usize + 32) / 32],
+    /// PLL reset bits requested by the staged changes
+    pll_reset: u8,
+}
+
+impl Staged {
+    const fn new() -> Self {
+        Self {
+            registers: [0; (LAST_REGISTER as usize + 32) / 32],
+            pll_reset: 0,
+        }
+    }
+
+    fn contains(&self, reg: u8) -> bool {
+        let reg = reg as usize;
+        reg <= LAST_REGISTER as usize && self.registers[reg / 32] & (1 << (reg % 32)) != 0
+    }
+
+    /// Stages consecutive registers starting at `start` in the cache
+    fn stage(&mut self, cache: &mut RegisterCache, start: u8, values: &[u8]) {
+        for (reg, value) in image_pairs(start, values) {
+            if reg == Registers::PLLReset as u8 {
+                self.pll_reset |= value;
+            } else if reg <= LAST_REGISTER {
+                cache.set(reg, value);
+                self.registers[reg as usize / 32] |= 1 << (reg % 32);
+            }
+        }
+    }
+}
+
 #[allow(dead_code)]
+#[derive(Clone, Copy)]
 struct Config {
-    initialised: bool,
     crystal_freq: CrystalFreq,
     crystal_load: CrystalLoad,
     crystal_ppm: u32,
+    verify: bool,
+    health: HealthPolicy,
+    correction_ppt: i64,
+    /// The ratio `set_correction` last wrote to each PLL, with the exact
+    /// PLL frequency it was rounded from (in millihertz, as numerator and
+    /// denominator)
+    corrected_plls: [Option<(Ratio, u128, u128)>; 2],
     plla_configured: bool,
     plla_freq: u32,
+    plla_ratio: Ratio,
     pllb_configured: bool,
     pllb_freq: u32,
+    pllb_ratio: Ratio,
 }
 
-pub struct Si5351<I2C: I2c> {
+/// What the driver knows about the multisynth feeding an output
+#[derive(Debug, Clone, Copy)]
+struct OutputState {
+    configured: bool,
+    pll: PLL,
+    ms_ratio: Ratio,
+    r_div: RDiv,
+    drive: DriveStrength,
+}
+
+impl OutputState {
+    const fn new() -> Self {
+        Self {
+            configured: false,
+            pll: PLL::A,
+            ms_ratio: Ratio::new(0, 0, 1),
+            r_div: RDiv::Div1,
+            drive: DriveStrength::MA8,
+        }
+    }
+}
+
+pub struct Si5351<I2C: I2c, DELAY: DelayNs = NoDelay, OBS: Observer = NoObserver> {
     config: Config,
     last_rdiv_value: [u8; 3],
-    i2c_dev: Option<I2C>,
+    outputs: [OutputState; 3],
+    registers: RegisterCache,
+    staged: Option<Staged>,
+    retry: RetryPolicy,
+    stats: BusStats,
+    health_counters: HealthCounters,
+    /// Outputs handed out by [`Si5351::split`], one bit each
+    handles: u8,
+    i2c_dev: I2C,
+    delay: DELAY,
+    observer: OBS,
 }
 
 impl<I2C: I2c> Si5351<I2C> {
-    pub fn new() -> Self {
-        Self {
+    /// Initializes I2C and configures the breakout, returning the ready
+    /// to use driver
+    ///
+    /// The driver does not need to own the bus: anything implementing
+    /// [`I2c`] works, including `&mut I2C` and the shared bus devices of
+    /// `embedded-hal-bus` (e.g. `RefCellDevice` or `CriticalSectionDevice`)
+    /// for boards with more peripherals on the same bus.
+    ///
+    /// Retries of the [`RetryPolicy`] happen without backoff, use
+    /// [`Si5351::with_delay`] to wait between them.
+    ///
+    /// i2c: The I2C (Wire) bus to use.
+    ///
+    /// config: The crystal of the board and the retry policy
+    pub fn new(i2c: I2C, config: DeviceConfig) -> Result<Self, Error> {
+        Self::with_delay(i2c, config, NoDelay)
+    }
+}
+
+impl<I2C: I2c, DELAY: DelayNs> Si5351<I2C, DELAY> {
+    /// Like [`Si5351::new`], with a delay for the backoff between retries
+    ///
+    /// delay: Waits `config.retry.backoff_ns` before every retry
+    pub fn with_delay(i2c: I2C, config: DeviceConfig, delay: DELAY) -> Result<Self, Error> {
+        Self::with_observer(i2c, config, delay, NoObserver)
+    }
+}
+
+impl<I2C: I2c, DELAY: DelayNs, OBS: Observer> Si5351<I2C, DELAY, OBS> {
+    /// Like [`Si5351::with_delay`], reporting every register transaction
+    /// to an [`Observer`], starting with the ones of the initialisation
+    ///
+    /// observer: E.g. a [`Recorder`]
+    pub fn with_observer(
+        i2c: I2C,
+        config: DeviceConfig,
+        delay: DELAY,
+        observer: OBS,
+    ) -> Result<Self, Error> {
+        let mut driver = Self {
             config: Config {
-                initialised: false,
-                crystal_freq: CrystalFreq::MHZ25,
-                crystal_load: CrystalLoad::PF10,
+                crystal_freq: config.crystal_freq,
+                crystal_load: config.crystal_load,
                 crystal_ppm: 30,
+                verify: config.verify,
+                health: config.health,
+                correction_ppt: 0,
+                corrected_plls: [None; 2],
                 plla_configured: false,
                 plla_freq: 0,
+                plla_ratio: Ratio::new(0, 0, 1),
                 pllb_configured: false,
                 pllb_freq: 0,
+                pllb_ratio: Ratio::new(0, 0, 1),
             },
             last_rdiv_value: [0; 3],
-            i2c_dev: None,
+            outputs: [OutputState::new(); 3],
+            registers: RegisterCache::new(),
+            staged: None,
+            retry: config.retry,
+            stats: BusStats::default(),
+            health_counters: HealthCounters::default(),
+            handles: 0,
+            i2c_dev: i2c,
+            delay,
+            observer,
+        };
+        driver.init()?;
+        Ok(driver)
+    }
+
+    /// Runs a bus transaction, retrying it as the retry policy allows
+    fn transfer<F>(&mut self, mut transaction: F) -> Result<(), Error>
+    where
+        F: FnMut(&mut I2C) -> Result<(), I2C::Error>,
+    {
+        let mut attempt = 0;
+        loop {
+            let kind = match transaction(&mut self.i2c_dev) {
+                Ok(()) => return Ok(()),
+                Err(error) => error.kind(),
+            };
+            if attempt >= self.retry.retries || !self.retry.is_retryable(kind) {
+                self.stats.failures = self.stats.failures.saturating_add(1);
+                return Err(kind.into());
+            }
+            attempt += 1;
+            self.stats.retries = self.stats.retries.saturating_add(1);
+            if self.retry.backoff_ns > 0 {
+                self.delay.delay_ns(self.retry.backoff_ns);
+            }
         }
     }
 
     /// Writes a register and an 8 bit value over I2C
     fn write8(&mut self, reg: u8, value: u8) -> Result<(), Error> {
-        if let Some(i2c) = &mut self.i2c_dev {
-            match i2c.write(ADDRESS, &[reg, value]) {
-                Ok(_) => Ok(()),
-                Err(_) => Err(Error::I2CTransaction),
-            }
-        } else {
-            Err(Error::I2CTransaction)
+        if let Some(staged) = &mut self.staged {
+            staged.stage(&mut self.registers, reg, &[value]);
+            return Ok(());
         }
+        trace!("write reg {=u8}: {=u8:#04x}", reg, value);
+        let result = self.transfer(|i2c| i2c.write(ADDRESS, &[reg, value]));
+        self.observer.on_write(ADDRESS, reg, &[value], result);
+        result?;
+        self.registers.set(reg, value);
+        self.verify(reg, &[value])
     }
 
     /// Reads an 8 bit value over I2C
     fn read8(&mut self, reg: u8, value: &mut u8) -> Result<(), Error> {
-        if let Some(i2c) = &mut self.i2c_dev {
-            match i2c.write_read(ADDRESS, &[reg], slice::from_mut(value)) {
-                Ok(_) => Ok(()),
-                Err(_) => Err(Error::I2CTransaction),
+        self.read_n(reg, slice::from_mut(value))
+    }
+
+    /// Reads consecutive registers over I2C (register address auto-increases)
+    ///
+    /// Registers staged by an open transaction read as the staged value.
+    fn read_n(&mut self, reg: u8, values: &mut [u8]) -> Result<(), Error> {
+        let result = self.transfer(|i2c| i2c.write_read(ADDRESS, &[reg], values));
+        self.observer.on_read(ADDRESS, reg, values, result);
+        result?;
+        trace!("read reg {=u8}: {=[u8]:#04x}", reg, values);
+        for (i, value) in values.iter_mut().enumerate() {
+            let reg = reg.wrapping_add(i as u8);
+            match (&self.staged, self.registers.get(reg)) {
+                (Some(staged), Some(cached)) if staged.contains(reg) => *value = cached,
+                _ => self.registers.set(reg, *value),
             }
-        } else {
-            Err(Error::I2CTransaction)
         }
+        Ok(())
     }
 
     fn write_n(&mut self, data: &[u8]) -> Result<(), Error> {
-        if let Some(i2c) = &mut self.i2c_dev {
-            match i2c.write(ADDRESS, &data) {
-                Ok(_) => Ok(()),
-                Err(_) => Err(Error::I2CTransaction),
+        if let (Some(staged), [reg, values @ ..]) = (&mut self.staged, data) {
+            staged.stage(&mut self.registers, *reg, values);
+            return Ok(());
+        }
+        #[cfg(feature = "defmt")]
+        if let [reg, values @ ..] = data {
+            trace!("write reg {=u8}: {=[u8]:#04x}", reg, values);
+        }
+        let result = self.transfer(|i2c| i2c.write(ADDRESS, data));
+        if let [reg, values @ ..] = data {
+            self.observer.on_write(ADDRESS, *reg, values, result);
+        }
+        result?;
+        if let [reg, values @ ..] = data {
+            self.registers.set_n(*reg, values);
+            self.verify(*reg, values)?;
+        }
+        Ok(())
+    }
+
+    /// In verify mode, reads back registers just written starting at `reg`
+    /// and compares them with `values`.
+    ///
+    /// The status registers and the self-clearing PLL reset register do not
+    /// read back what was written and are left out.
+    fn verify(&mut self, reg: u8, values: &[u8]) -> Result<(), Error> {
+        if !self.config.verify {
+            return Ok(());
+        }
+        let mut read_buffer = [0_u8; MAX_BURST];
+        for (chunk_index, chunk) in values.chunks(MAX_BURST).enumerate() {
+            let start = reg.wrapping_add((chunk_index * MAX_BURST) as u8);
+            let pairs = image_pairs(start, chunk).filter(|&(address, _)| !is_volatile(address));
+            let Some(first) = pairs.clone().next() else {
+                continue;
+            };
+            let offset = (first.0 - start) as usize;
+            let read_back = &mut read_buffer[..chunk.len() - offset];
+            self.read_n(first.0, read_back)?;
+            for (address, expected) in pairs {
+                let read = read_back[(address - first.0) as usize];
+                check(
+                    read == expected,
+                    Error::UnexpectedValue {
+                        address,
+                        expected,
+                        read,
+                    },
+                )?;
             }
-        } else {
-            Err(Error::I2CTransaction)
         }
+        Ok(())
+    }
+
+    /// The observer receiving the register transactions
+    pub fn observer(&self) -> &OBS {
+        &self.observer
+    }
+
+    /// The observer receiving the register transactions, e.g. to clear a
+    /// [`Recorder`]
+    pub fn observer_mut(&mut self) -> &mut OBS {
+        &mut self.observer
+    }
+
+    /// The number of retried and failed bus transactions so far
+    pub fn bus_stats(&self) -> BusStats {
+        self.stats
     }
 
-    /// Initializes I2C and configures the breakout (call this function
-    /// before doing anything else)
+    /// Clears the retry and failure counters
+    pub fn reset_bus_stats(&mut self) {
+        self.stats = BusStats::default();
+    }
+
+    /// Writes consecutive registers starting at `reg`, leaving out the
+    /// leading and trailing registers which already hold the wanted value.
     ///
-    /// i2c: The I2C (Wire) bus to use.
-    pub fn begin(&mut self, i2c: I2C) -> Result<(), Error> {
-        self.i2c_dev = Some(i2c);
+    /// Returns the number of registers written.
+    fn write_changed(&mut self, reg: u8, values: &[u8]) -> Result<usize, Error> {
+        check(values.len() <= MAX_BURST, Error::BufferOverflow)?;
+        let changed = |(i, &value): (usize, &u8)| self.registers.get(reg + i as u8) != Some(value);
+        let Some(first) = values.iter().enumerate().position(changed) else {
+            return Ok(0);
+        };
+        let last = values.len()
+            - 1
+            - values
+                .iter()
+                .enumerate()
+                .rev()
+                .position(changed)
+                .unwrap_or(0);
+        let len = last - first + 1;
+        let mut send_buffer = [0_u8; MAX_BURST + 1];
+        send_buffer[0] = reg + first as u8;
+        send_buffer[1..=len].copy_from_slice(&values[first..=last]);
+        self.write_n(&send_buffer[..=len])?;
+        Ok(len)
+    }
+
+    fn pll_configured(&self, pll: PLL) -> bool {
+        match pll {
+            PLL::A => self.config.plla_configured,
+            PLL::B => self.config.pllb_configured,
+        }
+    }
+
+    fn pll_ratio(&self, pll: PLL) -> Ratio {
+        match pll {
+            PLL::A => self.config.plla_ratio,
+            PLL::B => self.config.pllb_ratio,
+        }
+    }
+
+    /// The crystal frequency with the reference correction applied
+    fn crystal_millihertz(&self) -> u128 {
+        self.crystal_millihertz_at(self.config.correction_ppt)
+    }
+
+    /// The crystal frequency with a reference correction of `ppt` applied
+    fn crystal_millihertz_at(&self, ppt: i64) -> u128 {
+        let nominal = self.config.crystal_freq as u32 as i128 * 1000;
+        (nominal + nominal * ppt as i128 / 1_000_000_000_000) as u128
+    }
+
+    /// Puts the device into a known state: all outputs off, the crystal
+    /// load set and spread spectrum disabled
+    fn init(&mut self) -> Result<(), Error> {
+        self.registers.clear();
         // Disable all outputs setting CLKx_DIS high
         self.write8(Registers::OutputEnableControl as u8, 0xff)?;
         // Power down all output drivers
@@ -280,6 +797,9 @@ impl<I2C: I2c> Si5351<I2C> {
         )?;
         // Disable spread spectrum output
         self.enable_spread_spectrum(false)?;
+        // Clear the sticky status flags of the power-up, so that
+        // poll_health only reports what happens from here on
+        self.write8(Registers::InterruptStatusSticky as u8, 0)?;
         // Set interrupt masks as required (see Register 2 description in AN619).
         // By default, ClockBuilder Desktop sets this register to 0x18.
         // Note that the least significant nibble must remain 0x8, but the most
@@ -290,11 +810,31 @@ impl<I2C: I2c> Si5351<I2C> {
         self.config.plla_freq = 0;
         self.config.pllb_configured = false;
         self.config.pllb_freq = 0;
+        self.outputs = [OutputState::new(); 3];
         // All done!
-        self.config.initialised = true;
         Ok(())
     }
 
+    /// Releases the I2C bus passed to [`Si5351::new`]. The chip keeps
+    /// running with its current settings.
+    ///
+    /// ```no_run
+    /// # fn example<I2C: embedded_hal::i2c::I2c>(i2c: &mut I2C) -> Result<(), si5351a_adafruit::Error> {
+    /// use si5351a_adafruit::{DeviceConfig, PLL, Si5351};
+    ///
+    /// // Borrow the bus for the setup only
+    /// let mut clock_gen = Si5351::new(&mut *i2c, DeviceConfig::default())?;
+    /// clock_gen.set_freq(0, PLL::A, 12_288_000)?;
+    /// clock_gen.enable_outputs(true)?;
+    /// clock_gen.release();
+    /// // The bus is free for the other peripherals again
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn release(self) -> I2C {
+        self.i2c_dev
+    }
+
     /// Configures the Si5351 with config settings generated in
     /// ClockBuilder. You can use this function to make sure that
     /// your HW is properly configure and that there are no problems
@@ -307,22 +847,116 @@ impl<I2C: I2c> Si5351<I2C> {
     ///
     /// This will overwrite all of the config registers!
     pub fn set_clock_builder_data(&mut self) -> Result<(), Error> {
-        // Make sure we've called init first
-        check(self.config.initialised, Error::DeviceNotInitialsed)?;
-        // Disable all outputs setting CLKx_DIS high
-        self.write8(Registers::OutputEnableControl as u8, 0xff)?;
         // Writes configuration data to device using the register map contents
         // generated by ClockBuilder Desktop (registers 15-92 + 149-170)
-        for (i, &value) in REGS_15_TO_92.iter().enumerate() {
-            self.write8((15 + i) as u8, value)?;
+        let regs = image_pairs(15, &REGS_15_TO_92).chain(image_pairs(149, &REGS_149_TO_170));
+        self.apply_registers(regs)
+    }
+
+    /// Applies a register map exported from ClockBuilder, given as
+    /// `(address, value)` pairs.
+    ///
+    /// The map is written using the sequence from AN619: all outputs are
+    /// disabled, the output drivers are powered down, the registers are
+    /// written, a soft reset is applied to both PLLs and finally the outputs
+    /// are enabled according to register 3 of the map (or all of them if the
+    /// map does not contain register 3).
+    ///
+    /// Registers 0 (device status) and 177 (PLL reset) are skipped, as the
+    /// sequence takes care of them.
+    ///
+    /// regs: The register map to write
+    pub fn apply_register_map(&mut self, regs: &[(u8, u8)]) -> Result<(), Error> {
+        self.apply_registers(regs.iter().copied())
+    }
+
+    /// Applies a contiguous register image exported from ClockBuilder.
+    ///
+    /// start: The address of the first register in the image
+    ///
+    /// image: The register values, starting at `start`
+    ///
+    /// See [`Si5351::apply_register_map`] for the write sequence.
+    pub fn apply_register_image(&mut self, start: u8, image: &[u8]) -> Result<(), Error> {
+        check(
+            start as usize + image.len() <= LAST_REGISTER as usize + 1,
+            Error::AddressOutOfRange,
+        )?;
+        self.apply_registers(image_pairs(start, image))
+    }
+
+    fn apply_registers<R>(&mut self, regs: R) -> Result<(), Error>
+    where
+        R: Iterator<Item = (u8, u8)> + Clone,
+    {
+        // Validate the whole map before touching the device
+        for (reg, _) in regs.clone() {
+            check(reg <= LAST_REGISTER, Error::AddressOutOfRange)?;
+        }
+        // Disable all outputs setting CLKx_DIS high
+        self.write8(Registers::OutputEnableControl as u8, 0xff)?;
+        // Power down all output drivers
+        for reg in Registers::CLK0Control as u8..=Registers::CLK7Control as u8 {
+            self.write8(reg, 0x80)?;
         }
-        for (i, &value) in REGS_149_TO_170.iter().enumerate() {
-            self.write8((149 + i) as u8, value)?;
+        // Write the new configuration
+        let mut output_enable = 0x00;
+        for (reg, value) in regs {
+            match reg {
+                r if r == Registers::DeviceStatus as u8 || r == Registers::PLLReset as u8 => {}
+                r if r == Registers::OutputEnableControl as u8 => output_enable = value,
+                _ => {
+                    self.write8(reg, value)?;
+                    // Keep the R divider cache used by setup_multisynth in sync
+                    if let Some(output) = [
+                        Registers::Multisynth0Parameters3,
+                        Registers::Multisynth1Parameters3,
+                        Registers::Multisynth2Parameters3,
+                    ]
+                    .iter()
+                    .position(|&r| r as u8 == reg)
+                    {
+                        self.last_rdiv_value[output] = value & 0x70;
+                    }
+                }
+            }
         }
+        // The PLL settings are unknown from here on
+        self.config.plla_configured = false;
+        self.config.plla_freq = 0;
+        self.config.pllb_configured = false;
+        self.config.pllb_freq = 0;
+        self.outputs = [OutputState::new(); 3];
         // Apply soft reset
         self.write8(Registers::PLLReset as u8, 0xac)?;
         // Enabled desired outputs (see Register 3)
-        self.write8(Registers::OutputEnableControl as u8, 0x00)?;
+        self.write8(Registers::OutputEnableControl as u8, output_enable)
+    }
+
+    /// Returns the register state known to the driver as `(address, value)`
+    /// pairs, covering the registers of a ClockBuilder register map export
+    /// (2-3, 15-92, 149-170 and 183).
+    ///
+    /// Only registers the driver has written or read since [`Si5351::new`]
+    /// are included. Call [`Si5351::read_back`] first to export the complete
+    /// state of the device.
+    ///
+    /// The pairs can be formatted with the writers in [`clockbuilder`] or
+    /// applied to another device with [`Si5351::apply_register_map`].
+    pub fn register_map(&self) -> impl Iterator<Item = (u8, u8)> + Clone + '_ {
+        EXPORT_REGISTERS
+            .iter()
+            .flat_map(|&(first, last)| first..=last)
+            .filter_map(|reg| self.registers.get(reg).map(|value| (reg, value)))
+    }
+
+    /// Reads the registers of a ClockBuilder register map export back from
+    /// the device, so that [`Si5351::register_map`] reflects the hardware.
+    pub fn read_back(&mut self) -> Result<(), Error> {
+        let mut buffer = [0_u8; LAST_REGISTER as usize + 1];
+        for &(first, last) in EXPORT_REGISTERS.iter() {
+            self.read_n(first, &mut buffer[first as usize..=last as usize])?;
+        }
         Ok(())
     }
 
@@ -340,7 +974,7 @@ impl<I2C: I2c> Si5351<I2C> {
     ///
     /// ## PLL Configuration
     ///
-    ///     fVCO is the PLL output, and must be between 600..900MHz, where:
+    /// fVCO is the PLL output, and must be between 600..900MHz, where:
     ///
     /// fVCO = fXTAL * (a+(b/c))
     ///
@@ -358,7 +992,6 @@ impl<I2C: I2c> Si5351<I2C> {
     ///
     /// See: http://www.silabs.com/Support%20Documents/TechnicalDocs/AN619.pdf
     pub fn setup_pll(&mut self, pll: PLL, mult: u32, num: u32, denom: u32) -> Result<(), Error> {
-        check(self.config.initialised, Error::DeviceNotInitialsed)?; // Basic validation
         check(mult > 14 && mult < 91, Error::InvalidParameter)?; // mult = 15..90
         check(denom > 0 && denom <= 0xfffff, Error::InvalidParameter)?; // Avoid divide by zero + 20-bit limit
         check(num <= 0xfffff, Error::InvalidParameter)?; // 20-bit limit
@@ -381,44 +1014,36 @@ impl<I2C: I2c> Si5351<I2C> {
          */
 
         // Set the main PLL config registers
-        let (p1, p2, p3) = if num == 0 {
-            // Integer mode
-            (128 * mult - 512, num, denom)
-        } else {
-            // Fractional mode
-            let ratio = (128.0 * num as f32 / denom as f32) as u32;
-            (128 * mult + ratio - 512, 128 * num - denom * ratio, denom)
-        };
+        let (p1, p2, p3) = encode_divider(mult, num, denom);
         // Get the appropriate starting point for the PLL registers
         let base_addr = match pll {
             PLL::A => 26_u8,
             PLL::B => 34_u8,
         };
         // The datasheet is a nightmare of typos and inconsistencies here!
-        self.write8(base_addr, ((p3 & 0xff00) >> 8) as u8)?;
-        self.write8(base_addr + 1, (p3 & 0xff) as u8)?;
-        self.write8(base_addr + 2, ((p1 & 0x30000) >> 16) as u8)?;
-        self.write8(base_addr + 3, ((p1 & 0xff00) >> 8) as u8)?;
-        self.write8(base_addr + 4, (p1 & 0xff) as u8)?;
-        self.write8(
-            base_addr + 5,
-            (((p3 & 0xf0000) >> 12) as u8) | (((p2 & 0xf0000) >> 16) as u8),
-        )?;
-        self.write8(base_addr + 6, ((p2 & 0xff00) >> 8) as u8)?;
-        self.write8(base_addr + 7, (p2 & 0xff) as u8)?;
+        // Burst mode: register address auto-increases
+        let params = pack_parameters(p1, p2, p3, 0);
+        let mut send_buffer = [base_addr; 9];
+        send_buffer[1..].copy_from_slice(&params);
+        self.write_n(&send_buffer)?;
         // Reset both PLLs
         self.write8(Registers::PLLReset as u8, (1 << 7) | (1 << 5))?;
         // Store the frequency settings for use with the Multisynth helper
-        let ratio = mult as f32 + num as f32 / denom as f32;
-        let fvco = (self.config.crystal_freq as u32 as f32 * ratio) as u32;
+        let fvco = freqplan::pll_freq(self.config.crystal_freq as u32, mult, num, denom);
+        debug!(
+            "PLL {}: {=u32} + {=u32}/{=u32}, VCO {=u32}Hz, P1 {=u32} P2 {=u32} P3 {=u32}",
+            pll, mult, num, denom, fvco, p1, p2, p3
+        );
         match pll {
             PLL::A => {
                 self.config.plla_configured = true;
                 self.config.plla_freq = fvco;
+                self.config.plla_ratio = Ratio::new(mult, num, denom);
             }
             PLL::B => {
                 self.config.pllb_configured = true;
                 self.config.pllb_freq = fvco;
+                self.config.pllb_ratio = Ratio::new(mult, num, denom);
             }
         }
         Ok(())
@@ -464,17 +1089,21 @@ impl<I2C: I2c> Si5351<I2C> {
     /// fVCO is the PLL output frequency and MSx is the multisynth
     /// divider:
     ///
-    ///     fOUT = fVCO / MSx
+    /// ```text
+    /// fOUT = fVCO / MSx
+    /// ```
     ///
     /// Valid multisynth dividers are 4, 6, or 8 when using integers,
     /// or any fractional values between 8 + 1/1,048,575 and 900 + 0/1
     ///
     /// The following formula is used for the fractional mode divider:
     ///
-    ///     a + b / c
+    /// ```text
+    /// a + b / c
+    /// ```
     ///
     /// a = The integer value, which must be 4, 6 or 8 in integer mode (MSx_INT=1)
-    ///     or 8..900 in fractional mode (MSx_INT=0).
+    /// or 8..900 in fractional mode (MSx_INT=0).
     ///
     /// b = The fractional numerator (0..1,048,575)
     ///
@@ -487,7 +1116,6 @@ impl<I2C: I2c> Si5351<I2C> {
         num: u32,
         denom: u32,
     ) -> Result<(), Error> {
-        check(self.config.initialised, Error::DeviceNotInitialsed)?; // Basic validation 
         check(output < 3, Error::InvalidParameter)?; // Channel range
         check(div > 3 && div < 2049, Error::InvalidParameter)?; // Divider integer value
         check(denom > 0 && denom <= 0xfffff, Error::InvalidParameter)?; // Avoid divide by zero + 20-bit limit
@@ -516,17 +1144,7 @@ impl<I2C: I2c> Si5351<I2C> {
          */
 
         // Set the main PLL config registers
-        let (p1, p2, p3) = if num == 0 {
-            // Integer mode
-            (128 * div - 512, 0_u32, denom)
-        } else if denom == 1 {
-            // Fractional mode, simplified calculations
-            (128 * div + 128 * num - 512, 128 * num - 128, 1)
-        } else {
-            // Fractional mode
-            let ratio = (128.0 * num as f32 / denom as f32) as u32;
-            (128 * div + ratio - 512, 128 * num - denom * ratio, denom)
-        };
+        let (p1, p2, p3) = encode_divider(div, num, denom);
         // Get the appropriate starting point for the PLL registers
         let base_addr = match output {
             0 => Registers::Multisynth0Parameters1,
@@ -534,23 +1152,20 @@ impl<I2C: I2c> Si5351<I2C> {
             2 => Registers::Multisynth2Parameters1,
             _ => unreachable!(),
         } as u8;
+        debug!(
+            "MS{=usize} from PLL {}: {=u32} + {=u32}/{=u32}, P1 {=u32} P2 {=u32} P3 {=u32}",
+            output, pll_source, div, num, denom, p1, p2, p3
+        );
         // Set the MSx config registers
         // Burst mode: register address auto-increases
-        let send_buffer = [
-            base_addr,
-            ((p3 & 0xff00) >> 8) as u8,
-            (p3 * 0xff) as u8,
-            ((p1 & 0x30000) >> 16) as u8 | self.last_rdiv_value[output],
-            ((p1 & 0xff00) >> 8) as u8,
-            (p1 & 0xff) as u8,
-            ((p3 & 0xf0000) >> 12) as u8 | ((p2 & 0xf0000) >> 16) as u8,
-            ((p2 & 0xff00) >> 8) as u8,
-            (p2 & 0xff) as u8,
-        ];
+        let params = pack_parameters(p1, p2, p3, self.last_rdiv_value[output]);
+        let mut send_buffer = [base_addr; 9];
+        send_buffer[1..].copy_from_slice(&params);
         self.write_n(&send_buffer)?;
         // Configure the clk control and enable the output
         // TODO: Check if the clk control byte needs to be updated.
-        let mut clk_control_reg = 0x0f_u8; // 8mA drive strength, MS0 as CLK0 source, Clock not inverted, powered up
+        // MS0 as CLK0 source, Clock not inverted, powered up
+        let mut clk_control_reg = 0x0c_u8 | self.outputs[output].drive as u8;
         if pll_source == PLL::B {
             clk_control_reg |= 1 << 5; // Uses PLLB
         }
@@ -563,14 +1178,20 @@ impl<I2C: I2c> Si5351<I2C> {
             2 => Registers::CLK2Control,
             _ => unreachable!(),
         } as u8;
-        self.write8(reg, clk_control_reg)
+        self.write8(reg, clk_control_reg)?;
+        // Store the divider settings for use with the fast retune helpers
+        let state = &mut self.outputs[output];
+        state.configured = true;
+        state.pll = pll_source;
+        state.ms_ratio = Ratio::new(div, num, denom);
+        Ok(())
     }
 
     /// Configures the Multisynth divider using integer output.
     ///
     /// output: The output channel to use (0..2)
     ///
-    /// pllSource	The PLL input source to use
+    /// pllSource: The PLL input source to use
     ///
     /// div: The integer divider for the Multisynth output
     pub fn setup_multisynth_int(
@@ -582,7 +1203,8 @@ impl<I2C: I2c> Si5351<I2C> {
         self.setup_multisynth(output, pll_source, div as u32, 0, 1)
     }
 
-    /// Enables or disables spread spectrum
+    /// Enables or disables spread spectrum, with the parameters last set
+    /// up with [`Si5351::setup_spread_spectrum`]
     ///
     /// enabled: Whether spread spectrum output is enabled
     pub fn enable_spread_spectrum(&mut self, enabled: bool) -> Result<(), Error> {
@@ -596,12 +1218,72 @@ impl<I2C: I2c> Si5351<I2C> {
         self.write8(Registers::SpreadSpectrumParameters as u8, regval)
     }
 
+    /// Sets up and enables spread spectrum modulation of PLL A, as
+    /// described in AN619.
+    ///
+    /// The modulation parameters depend on the multiplier of PLL A, so call
+    /// this again after PLL A has been set up for another frequency.
+    /// Modulation only works with a fractional PLL A.
+    ///
+    /// Returns `Error::InvalidParameter` if the amplitude is out of range or
+    /// PLL A has not been configured.
+    ///
+    /// spread: The amplitude and mode of the modulation
+    pub fn setup_spread_spectrum(&mut self, spread: SpreadSpectrum) -> Result<(), Error> {
+        check(
+            (10..=150).contains(&spread.amplitude),
+            Error::InvalidParameter,
+        )?;
+        check(self.pll_configured(PLL::A), Error::InvalidParameter)?;
+        let ratio = self.config.plla_ratio;
+        // Up/down step count for a modulation rate of about 31.5kHz
+        let ssudp = self.config.crystal_freq as u32 / (4 * 31_500);
+        let amplitude = spread.amplitude as u128;
+        // 64 (down) or 128 (center) * a * amplitude / (1 +- amplitude) / SSUDP,
+        // split into P1 + P2 / P3 with P3 = 32767
+        let step = |scale: u128, one_plus: bool| {
+            let numerator =
+                scale * (ratio.a as u128 * ratio.c as u128 + ratio.b as u128) * amplitude;
+            let one = if one_plus {
+                10_000 + amplitude
+            } else {
+                10_000 - amplitude
+            };
+            let denominator = ratio.c as u128 * one * ssudp as u128;
+            let p1 = (numerator / denominator) as u32;
+            let p2 = ((numerator % denominator) * 32_767 / denominator) as u32;
+            (p1, p2, 32_767_u32)
+        };
+        let ((dn_p1, dn_p2, dn_p3), (up_p1, up_p2, up_p3), mode) = match spread.mode {
+            SpreadMode::Down => (step(64, true), (0, 0, 1), 0),
+            SpreadMode::Center => (step(128, true), step(128, false), 1 << 7),
+        };
+        debug!(
+            "Spread spectrum: {}, SSUDP {=u32}, down {=u32} {=u32}, up {=u32} {=u32}",
+            spread, ssudp, dn_p1, dn_p2, up_p1, up_p2
+        );
+        self.write_n(&[
+            Registers::SpreadSpectrumParameters as u8,
+            0x80 | (dn_p2 >> 8) as u8 & 0x7f,
+            dn_p2 as u8,
+            mode | (dn_p3 >> 8) as u8 & 0x7f,
+            dn_p3 as u8,
+            dn_p1 as u8,
+            ((ssudp >> 8) as u8 & 0x0f) << 4 | (dn_p1 >> 8) as u8 & 0x0f,
+            ssudp as u8,
+            (up_p2 >> 8) as u8 & 0x7f,
+            up_p2 as u8,
+            (up_p3 >> 8) as u8 & 0x7f,
+            up_p3 as u8,
+            up_p1 as u8,
+            (up_p1 >> 8) as u8 & 0x0f,
+        ])
+    }
+
     /// Enables or disables all clock outputs
     ///
     /// enabled: Whether output is enabled
     pub fn enable_outputs(&mut self, enabled: bool) -> Result<(), Error> {
-        // Make sure we've called init first
-        check(self.config.initialised, Error::DeviceNotInitialsed)?;
         // Enabled desired outputs (see Register 3)
         self.write8(
             Registers::OutputEnableControl as u8,
@@ -609,6 +1291,72 @@ impl<I2C: I2c> Si5351<I2C> {
         )
     }
 
+    /// Enables or disables a single clock output, leaving the others as they
+    /// are
+    ///
+    /// output: The output channel (0..2)
+    ///
+    /// enabled: Whether the output is enabled
+    pub fn enable_output(&mut self, output: usize, enabled: bool) -> Result<(), Error> {
+        check(output < 3, Error::InvalidParameter)?;
+        let reg = Registers::OutputEnableControl as u8;
+        let mut regval = match self.registers.get(reg) {
+            Some(regval) => regval,
+            None => {
+                let mut regval = 0;
+                self.read8(reg, &mut regval)?;
+                regval
+            }
+        };
+        // A set bit disables the output
+        if enabled {
+            regval &= !(1 << output);
+        } else {
+            regval |= 1 << output;
+        }
+        self.write8(reg, regval)
+    }
+
+    /// Sets the output driver strength of a clock output
+    ///
+    /// output: The output channel (0..2)
+    ///
+    /// strength: The drive current
+    pub fn set_drive_strength(
+        &mut self,
+        output: usize,
+        strength: DriveStrength,
+    ) -> Result<(), Error> {
+        check(output < 3, Error::InvalidParameter)?;
+        self.outputs[output].drive = strength;
+        let reg = Registers::CLK0Control as u8 + output as u8;
+        let mut regval = match self.registers.get(reg) {
+            Some(regval) => regval,
+            None => {
+                let mut regval = 0;
+                self.read8(reg, &mut regval)?;
+                regval
+            }
+        };
+        regval = (regval & !0x03) | strength as u8;
+        self.write8(reg, regval)
+    }
+
+    /// Sets the initial phase offset of a clock output, in quarter periods
+    /// of its PLL. The offset takes effect at the next PLL reset.
+    ///
+    /// output: The output channel (0..2)
+    ///
+    /// offset: The phase offset (0..127)
+    pub fn set_phase_offset(&mut self, output: usize, offset: u8) -> Result<(), Error> {
+        check(output < 3, Error::InvalidParameter)?;
+        check(offset < 0x80, Error::InvalidParameter)?;
+        self.write8(
+            Registers::CLK0InitialPhaseOffset as u8 + output as u8,
+            offset,
+        )
+    }
+
     pub fn setup_rdiv(&mut self, output: usize, div: RDiv) -> Result<(), Error> {
         let r_reg = match output {
             0 => Registers::Multisynth0Parameters3,
@@ -624,26 +1372,206 @@ impl<I2C: I2c> Si5351<I2C> {
         divider <<= 4;
         regval |= divider;
         self.last_rdiv_value[output] = divider;
+        self.outputs[output].r_div = div;
+        debug!("R{=usize} divider: {}", output, div);
         self.write8(r_reg, regval)
     }
 
     pub fn set_freq(&mut self, output: usize, pll: PLL, freq: u32) -> Result<(), Error> {
-        let denom: u32 = 1048575;
-        let crystal_freq = self.config.crystal_freq as u32;
-        let total_divider = (900_000_000 / freq) as u16;
-        let r_div = RDiv::min_divider(total_divider / 900)?;
-        let ms_div = (total_divider / (2 * r_div.denominator_u8() as u16) * 2).max(6);
-        if ms_div > 1800 {
-            return Err(Error::InvalidParameter);
-        }
-        let total_div = ms_div as u32 * r_div.denominator_u8() as u32;
-        let pll_freq = freq * total_div;
-
-        let mult = pll_freq / crystal_freq;
-        let num = ((pll_freq % crystal_freq) as u64 * denom as u64 / crystal_freq as u64) as u32;
-
-        self.setup_pll(pll, mult, num, denom)?;
-        self.setup_multisynth(output, pll, ms_div as u32, 0, 1)?;
-        self.setup_rdiv(output, r_div)
+        let plan = FreqPlan::new(self.config.crystal_freq as u32, freq)?;
+        self.setup_pll(pll, plan.mult, plan.num, plan.denom)?;
+        self.setup_multisynth(output, pll, plan.ms_div, 0, 1)?;
+        self.setup_rdiv(output, plan.r_div)
+    }
+
+    /// Applies a precomputed [`Plan`], e.g. one built at compile time with
+    /// [`plan!`].
+    ///
+    /// All outputs are disabled, registers 15-65 are written in a single
+    /// burst, both PLLs are reset and the outputs of the plan are enabled.
+    ///
+    /// Returns the first error found while building the plan (see
+    /// [`Plan::try_build`]) before writing anything.
+    pub fn apply_plan(&mut self, plan: &Plan) -> Result<(), Error> {
+        // Plans built at runtime or deserialized carry their error along
+        plan.try_build()?;
+        check(
+            plan.crystal_freq() == self.config.crystal_freq as u32,
+            Error::InvalidParameter,
+        )?;
+        // Disable all outputs setting CLKx_DIS high
+        self.write8(Registers::OutputEnableControl as u8, 0xff)?;
+        let mut send_buffer = [PLAN_START; freqplan::PLAN_LEN + 1];
+        send_buffer[1..].copy_from_slice(plan.image());
+        self.write_n(&send_buffer)?;
+        // Reset both PLLs
+        self.write8(Registers::PLLReset as u8, (1 << 7) | (1 << 5))?;
+        // Store the frequency settings for use with the Multisynth helper
+        self.config.plla_configured = plan.pll_freq(PLL::A) != 0;
+        self.config.plla_freq = plan.pll_freq(PLL::A);
+        self.config.plla_ratio = plan.pll_ratio[0];
+        self.config.pllb_configured = plan.pll_freq(PLL::B) != 0;
+        self.config.pllb_freq = plan.pll_freq(PLL::B);
+        self.config.pllb_ratio = plan.pll_ratio[1];
+        self.last_rdiv_value = plan.rdiv_bits;
+        debug!("Applied plan: {}", plan);
+        for (state, output) in self.outputs.iter_mut().zip(plan.outputs) {
+            *state = match output {
+                Some((pll, ms_ratio, r_div)) => OutputState {
+                    configured: true,
+                    pll,
+                    ms_ratio,
+                    r_div,
+                    drive: DriveStrength::MA8,
+                },
+                None => OutputState::new(),
+            };
+        }
+        // Enabled desired outputs (see Register 3)
+        self.write8(Registers::OutputEnableControl as u8, !plan.enabled)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{FaultyI2c, RegisterFile};
+
+    /// Counts the time waited
+    #[derive(Default)]
+    struct CountingDelay {
+        waits: u32,
+        total_ns: u64,
+    }
+
+    impl DelayNs for CountingDelay {
+        fn delay_ns(&mut self, ns: u32) {
+            self.waits += 1;
+            self.total_ns += ns as u64;
+        }
+    }
+
+    fn retrying(retries: u8) -> DeviceConfig {
+        DeviceConfig {
+            retry: RetryPolicy {
+                retries,
+                backoff_ns: 1000,
+                ..RetryPolicy::default()
+            },
+            ..DeviceConfig::default()
+        }
+    }
+
+    /// An initialised driver whose bus injects `faults` from now on
+    fn faulty<F>(config: DeviceConfig, faults: F) -> Si5351<FaultyI2c, CountingDelay>
+    where
+        F: FnOnce(FaultyI2c, u32) -> FaultyI2c,
+    {
+        let bus = FaultyI2c::new(RegisterFile::new());
+        let mut clock_gen = Si5351::with_delay(bus, config, CountingDelay::default()).unwrap();
+        let transactions = clock_gen.i2c_dev.transactions();
+        clock_gen.i2c_dev = faults(clock_gen.i2c_dev.clone(), transactions);
+        clock_gen
+    }
+
+    #[test]
+    fn succeeds_after_retried_naks() {
+        let mut clock_gen = faulty(retrying(3), |bus, next| bus.nak_on_transaction(next, 2));
+        assert_eq!(clock_gen.enable_outputs(true), Ok(()));
+        assert_eq!(
+            clock_gen.bus_stats(),
+            BusStats {
+                retries: 2,
+                failures: 0
+            }
+        );
+        assert_eq!((clock_gen.delay.waits, clock_gen.delay.total_ns), (2, 2000));
+        assert_eq!(clock_gen.i2c_dev.inner().registers()[3], 0x00);
+    }
+
+    #[test]
+    fn gives_up_after_retries() {
+        let mut clock_gen = faulty(retrying(2), |bus, next| bus.nak_on_transaction(next, 5));
+        let before = clock_gen.i2c_dev.transactions();
+        assert_eq!(clock_gen.enable_outputs(true), Err(Error::I2CNoACK));
+        assert_eq!(clock_gen.i2c_dev.transactions() - before, 3);
+        assert_eq!(
+            clock_gen.bus_stats(),
+            BusStats {
+                retries: 2,
+                failures: 1
+            }
+        );
+        assert_eq!(clock_gen.i2c_dev.inner().registers()[3], 0xff);
+        // The cache still holds what the device has
+        assert_eq!(clock_gen.registers.get(3), Some(0xff));
+    }
+
+    #[test]
+    fn does_not_retry_other_errors() {
+        let mut clock_gen = faulty(retrying(3), |bus, next| bus.timeout_on_transaction(next, 1));
+        assert_eq!(
+            clock_gen.enable_outputs(true),
+            Err(Error::I2CTransaction(ErrorKind::Other))
+        );
+        assert_eq!(
+            clock_gen.bus_stats(),
+            BusStats {
+                retries: 0,
+                failures: 1
+            }
+        );
+        assert_eq!(clock_gen.delay.waits, 0);
+    }
+
+    fn verifying() -> DeviceConfig {
+        DeviceConfig {
+            verify: true,
+            ..DeviceConfig::default()
+        }
+    }
+
+    #[test]
+    fn verify_reads_back_every_write() {
+        let mut clock_gen = faulty(verifying(), |bus, _| bus);
+        let before = clock_gen.i2c_dev.transactions();
+        assert_eq!(clock_gen.enable_outputs(true), Ok(()));
+        assert_eq!(clock_gen.i2c_dev.transactions() - before, 2);
+        assert_eq!(clock_gen.set_freq(0, PLL::A, 10_000_000), Ok(()));
+    }
+
+    #[test]
+    fn verify_reports_mismatch() {
+        let mut clock_gen = faulty(verifying(), |bus, _| bus.flip_read_bits(3, 0x04));
+        assert_eq!(
+            clock_gen.enable_outputs(true),
+            Err(Error::UnexpectedValue {
+                address: 3,
+                expected: 0x00,
+                read: 0x04
+            })
+        );
+    }
+
+    #[test]
+    fn verify_reports_register_within_burst() {
+        let mut clock_gen = faulty(verifying(), |bus, _| bus.flip_read_bits(29, 0x80));
+        let result = clock_gen.set_freq(0, PLL::A, 10_000_000);
+        let expected = clock_gen.i2c_dev.inner().registers()[29];
+        assert_eq!(
+            result,
+            Err(Error::UnexpectedValue {
+                address: 29,
+                expected,
+                read: expected ^ 0x80
+            })
+        );
+    }
+
+    #[test]
+    fn verify_skips_volatile_registers() {
+        // Register 177 clears itself on the device
+        let mut clock_gen = faulty(verifying(), |bus, _| bus.flip_read_bits(177, 0xff));
+        assert_eq!(clock_gen.set_freq(0, PLL::A, 10_000_000), Ok(()));
     }
 }
@@ -0,0 +1,302 @@
+//! Hooks into every register transaction of the driver, for recording, bus
+//! analysis and tests.
+//!
+//! ```no_run
+//! # fn example<I2C: embedded_hal::i2c::I2c>(i2c: I2C) -> Result<(), si5351a_adafruit::Error> {
+//! use si5351a_adafruit::{Access, DeviceConfig, NoDelay, PLL, Recorder, Si5351};
+//!
+//! let mut clock_gen =
+//!     Si5351::with_observer(i2c, DeviceConfig::default(), NoDelay, Recorder::<32>::new())?;
+//! clock_gen.set_freq(0, PLL::A, 10_000_000)?;
+//! for record in clock_gen.observer().iter() {
+//!     if record.access == Access::Write {
+//!         // register record.reg was written with record.data()
+//!     }
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::Error;
+
+/// Receives every register transaction the driver makes on the bus, with
+/// its outcome after any retries. Writes staged by a
+/// [`Transaction`](crate::Transaction) are reported when they are
+/// committed.
+///
+/// Both methods do nothing by default.
+pub trait Observer {
+    /// A write of consecutive registers
+    ///
+    /// address: The I2C address
+    ///
+    /// reg: The first register written
+    ///
+    /// data: The values written
+    ///
+    /// result: The outcome of the write
+    fn on_write(&mut self, address: u8, reg: u8, data: &[u8], result: Result<(), Error>) {
+        let _ = (address, reg, data, result);
+    }
+
+    /// A read of consecutive registers, same parameters as
+    /// [`Observer::on_write`]. `data` is only meaningful if the read
+    /// succeeded.
+    fn on_read(&mut self, address: u8, reg: u8, data: &[u8], result: Result<(), Error>) {
+        let _ = (address, reg, data, result);
+    }
+}
+
+/// The default [`Observer`], which compiles to nothing
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct NoObserver;
+
+impl Observer for NoObserver {}
+
+/// Whether a recorded transaction wrote or read
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Access {
+    Write,
+    Read,
+}
+
+/// A transaction stored by a [`Recorder`], keeping the first `DATA` bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Record<const DATA: usize> {
+    /// Write or read
+    pub access: Access,
+    /// The I2C address
+    pub address: u8,
+    /// The first register
+    pub reg: u8,
+    /// The number of registers transferred, which may exceed `DATA`
+    pub len: usize,
+    /// The outcome of the transaction
+    pub result: Result<(), Error>,
+    bytes: [u8; DATA],
+}
+
+impl<const DATA: usize> Record<DATA> {
+    /// The stored register values, at most `DATA` of them
+    pub fn data(&self) -> &[u8] {
+        &self.bytes[..self.len.min(DATA)]
+    }
+
+    /// Whether the transaction transferred more bytes than were stored
+    pub fn is_truncated(&self) -> bool {
+        self.len > DATA
+    }
+}
+
+/// An [`Observer`] keeping the last `N` transactions in a ring buffer,
+/// with up to `DATA` bytes each
+#[derive(Debug, Clone)]
+pub struct Recorder<const N: usize, const DATA: usize = 16> {
+    records: [Option<Record<DATA>>; N],
+    /// Index of the oldest record
+    start: usize,
+    len: usize,
+    total: u32,
+}
+
+impl<const N: usize, const DATA: usize> Default for Recorder<N, DATA> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize, const DATA: usize> Recorder<N, DATA> {
+    pub const fn new() -> Self {
+        Self {
+            records: [None; N],
+            start: 0,
+            len: 0,
+            total: 0,
+        }
+    }
+
+    /// The stored transactions, oldest first
+    pub fn iter(&self) -> impl Iterator<Item = &Record<DATA>> + '_ {
+        (0..self.len).filter_map(move |i| self.records[(self.start + i) % N].as_ref())
+    }
+
+    /// The number of stored transactions
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The number of transactions seen, including the ones which no longer
+    /// fit in the buffer
+    pub fn total(&self) -> u32 {
+        self.total
+    }
+
+    /// Forgets all stored transactions
+    pub fn clear(&mut self) {
+        self.start = 0;
+        self.len = 0;
+        self.total = 0;
+    }
+
+    fn record(
+        &mut self,
+        access: Access,
+        address: u8,
+        reg: u8,
+        data: &[u8],
+        result: Result<(), Error>,
+    ) {
+        self.total = self.total.wrapping_add(1);
+        if N == 0 {
+            return;
+        }
+        let mut bytes = [0; DATA];
+        let stored = data.len().min(DATA);
+        bytes[..stored].copy_from_slice(&data[..stored]);
+        let record = Record {
+            access,
+            address,
+            reg,
+            len: data.len(),
+            result,
+            bytes,
+        };
+        if self.len < N {
+            self.records[(self.start + self.len) % N] = Some(record);
+            self.len += 1;
+        } else {
+            // Overwrite the oldest one
+            self.records[self.start] = Some(record);
+            self.start = (self.start + 1) % N;
+        }
+    }
+}
+
+impl<const N: usize, const DATA: usize> Observer for Recorder<N, DATA> {
+    fn on_write(&mut self, address: u8, reg: u8, data: &[u8], result: Result<(), Error>) {
+        self.record(Access::Write, address, reg, data, result);
+    }
+
+    fn on_read(&mut self, address: u8, reg: u8, data: &[u8], result: Result<(), Error>) {
+        self.record(Access::Read, address, reg, data, result);
+    }
+}
+
+impl<O: Observer + ?Sized> Observer for &mut O {
+    fn on_write(&mut self, address: u8, reg: u8, data: &[u8], result: Result<(), Error>) {
+        O::on_write(self, address, reg, data, result);
+    }
+
+    fn on_read(&mut self, address: u8, reg: u8, data: &[u8], result: Result<(), Error>) {
+        O::on_read(self, address, reg, data, result);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{FaultyI2c, RegisterFile};
+    use crate::{DeviceConfig, NoDelay, Si5351};
+
+    fn write(recorder: &mut Recorder<3, 2>, reg: u8, data: &[u8]) {
+        recorder.on_write(0x60, reg, data, Ok(()));
+    }
+
+    fn regs(recorder: &Recorder<3, 2>) -> Vec<u8> {
+        recorder.iter().map(|record| record.reg).collect()
+    }
+
+    #[test]
+    fn keeps_newest_records_in_order() {
+        let mut recorder = Recorder::<3, 2>::new();
+        assert!(recorder.is_empty());
+        for reg in 1..=2 {
+            write(&mut recorder, reg, &[reg]);
+        }
+        assert_eq!(regs(&recorder), [1, 2]);
+        for reg in 3..=7 {
+            write(&mut recorder, reg, &[reg]);
+        }
+        assert_eq!(regs(&recorder), [5, 6, 7]);
+        assert_eq!(recorder.len(), 3);
+        assert_eq!(recorder.total(), 7);
+    }
+
+    #[test]
+    fn truncates_long_transfers() {
+        let mut recorder = Recorder::<3, 2>::new();
+        write(&mut recorder, 26, &[1, 2, 3, 4]);
+        write(&mut recorder, 3, &[0xff]);
+        let records: Vec<_> = recorder.iter().collect();
+        assert_eq!((records[0].len, records[0].data()), (4, &[1, 2][..]));
+        assert!(records[0].is_truncated());
+        assert_eq!((records[1].len, records[1].data()), (1, &[0xff][..]));
+        assert!(!records[1].is_truncated());
+    }
+
+    #[test]
+    fn clear_forgets_everything() {
+        let mut recorder = Recorder::<3, 2>::new();
+        for reg in 1..=5 {
+            write(&mut recorder, reg, &[reg]);
+        }
+        recorder.clear();
+        assert!(recorder.is_empty());
+        assert_eq!(recorder.total(), 0);
+        assert_eq!(recorder.iter().count(), 0);
+        // Starts over at the front of the buffer
+        for reg in 6..=9 {
+            write(&mut recorder, reg, &[reg]);
+        }
+        assert_eq!(regs(&recorder), [7, 8, 9]);
+    }
+
+    #[test]
+    fn records_driver_reads_and_writes() {
+        let mut clock_gen = Si5351::with_observer(
+            RegisterFile::new(),
+            DeviceConfig::default(),
+            NoDelay,
+            Recorder::<8>::new(),
+        )
+        .unwrap();
+        clock_gen.observer_mut().clear();
+        // Reads register 149 and sets SSC_EN
+        clock_gen.enable_spread_spectrum(true).unwrap();
+        let records: Vec<_> = clock_gen.observer().iter().copied().collect();
+        assert_eq!(records.len(), 2);
+        assert_eq!(
+            (records[0].access, records[0].reg, records[0].data()),
+            (Access::Read, 149, &[0x00][..])
+        );
+        assert_eq!(
+            (records[1].access, records[1].reg, records[1].data()),
+            (Access::Write, 149, &[0x80][..])
+        );
+        assert!(records.iter().all(|record| record.address == 0x60));
+        assert!(records.iter().all(|record| record.result.is_ok()));
+    }
+
+    #[test]
+    fn records_failed_writes() {
+        let bus = FaultyI2c::new(RegisterFile::new());
+        let mut clock_gen =
+            Si5351::with_observer(bus, DeviceConfig::default(), NoDelay, Recorder::<8>::new())
+                .unwrap();
+        let next = clock_gen.i2c_dev.transactions();
+        clock_gen.i2c_dev = clock_gen.i2c_dev.clone().nak_on_transaction(next, 1);
+        clock_gen.observer_mut().clear();
+        assert_eq!(clock_gen.enable_outputs(true), Err(Error::I2CNoACK));
+        let record = clock_gen.observer().iter().next().copied().unwrap();
+        assert_eq!((record.access, record.reg), (Access::Write, 3));
+        assert_eq!(record.result, Err(Error::I2CNoACK));
+    }
+}
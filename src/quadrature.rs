@@ -0,0 +1,284 @@
+//! Quadrature local oscillators for direct conversion and SDR receivers.
+//!
+//! Two outputs run 90° apart when they share a PLL, both use the same even
+//! integer multisynth divider and the phase offset of the second one equals
+//! that divider (the offset counts quarter periods of the PLL). The offset
+//! register has 7 bits, which limits the divider to 126 and the lowest I/Q
+//! frequency to 600MHz / 126 = 4.76MHz. Running the PLL below its 600MHz
+//! minimum, as some designs do, reaches down to about 3.2MHz, but this
+//! driver keeps the PLL within the specification. The highest I/Q
+//! frequency is 900MHz / 8 = 112.5MHz.
+//!
+//! Softrock style mixers generate the quadrature signals themselves from a
+//! single local oscillator at four times the receive frequency, see
+//! [`Quadrature::Lo4x`].
+//!
+//! ```no_run
+//! # fn example<I2C: embedded_hal::i2c::I2c>(
+//! #     clock_gen: &mut si5351a_adafruit::Si5351<I2C>,
+//! # ) -> Result<(), si5351a_adafruit::Error> {
+//! use si5351a_adafruit::PLL;
+//! use si5351a_adafruit::quadrature::Quadrature;
+//!
+//! // I on CLK0, Q on CLK1, 90° behind
+//! clock_gen.set_quadrature(7_100_000, Quadrature::Phase { i: 0, q: 1 }, PLL::A)?;
+//! # Ok(())
+//! # }
+//! ```
+
+use embedded_hal::delay::DelayNs;
+use embedded_hal::i2c::I2c;
+
+use crate::freqplan::{FRAC_DENOM, Ratio, VCO_MAX, VCO_MIN};
+use crate::{Error, Observer, PLL, RDiv, Registers, Si5351, check};
+
+/// Largest divider usable as a 90° phase offset (7 bit register, even)
+const MAX_PHASE_DIVIDER: u32 = 126;
+
+/// Largest even multisynth divider
+const MAX_DIVIDER: u32 = 2048;
+
+/// Smallest even multisynth divider used (4 and 6 are special modes)
+const MIN_DIVIDER: u32 = 8;
+
+/// Lowest frequency in Hz of two outputs in quadrature
+pub const QUADRATURE_MIN_FREQ: u32 = VCO_MIN.div_ceil(MAX_PHASE_DIVIDER);
+
+/// Highest frequency in Hz of two outputs in quadrature
+pub const QUADRATURE_MAX_FREQ: u32 = VCO_MAX / MIN_DIVIDER;
+
+/// Lowest receive frequency in Hz of the 4x local oscillator mode
+pub const LO_4X_MIN_FREQ: u32 = VCO_MIN.div_ceil(4 * MAX_DIVIDER);
+
+/// Highest receive frequency in Hz of the 4x local oscillator mode
+pub const LO_4X_MAX_FREQ: u32 = VCO_MAX / (4 * MIN_DIVIDER);
+
+/// The outputs of a quadrature oscillator
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Quadrature {
+    /// Two outputs at the receive frequency, `q` 90° behind `i`
+    Phase { i: usize, q: usize },
+    /// One output at four times the receive frequency, for mixers which
+    /// divide by four to generate I and Q
+    Lo4x(usize),
+}
+
+impl Quadrature {
+    /// The lowest and highest receive frequency in Hz of this mode
+    pub const fn freq_range(&self) -> (u32, u32) {
+        match self {
+            Quadrature::Phase { .. } => (QUADRATURE_MIN_FREQ, QUADRATURE_MAX_FREQ),
+            Quadrature::Lo4x(_) => (LO_4X_MIN_FREQ, LO_4X_MAX_FREQ),
+        }
+    }
+}
+
+/// Largest even divider up to `max_div` keeping the PLL in range for
+/// `freq`, if any
+fn even_divider(freq: u32, max_div: u32) -> Option<u32> {
+    let div = (VCO_MAX / freq).min(max_div) & !1;
+    (div >= MIN_DIVIDER && freq as u64 * div as u64 >= VCO_MIN as u64).then_some(div)
+}
+
+impl<I2C: I2c, DELAY: DelayNs, OBS: Observer> Si5351<I2C, DELAY, OBS> {
+    /// Sets up a quadrature local oscillator.
+    ///
+    /// The outputs get an even integer multisynth divider and the
+    /// fractional part of the frequency goes into the PLL, which the outputs
+    /// must not share with anything else. In [`Quadrature::Phase`] mode the
+    /// phase offset registers of both outputs are written (0 for I, the
+    /// divider for Q). Finally only this PLL is reset, which aligns the
+    /// phases; the outputs on the other PLL keep running undisturbed.
+    ///
+    /// Returns `Error::InvalidParameter` if the frequency is outside
+    /// [`Quadrature::freq_range`] or the outputs are out of range or equal,
+    /// and `Error::PllConflict` if another configured output uses the PLL.
+    ///
+    /// Retuning the outputs later with the fast retune helpers keeps the
+    /// phase relation only as long as the PLL alone is moved and the
+    /// divider stays the same.
+    ///
+    /// freq: The receive frequency in Hz
+    ///
+    /// outputs: The outputs and the mode
+    ///
+    /// pll: The PLL to use
+    pub fn set_quadrature(
+        &mut self,
+        freq: u32,
+        outputs: Quadrature,
+        pll: PLL,
+    ) -> Result<(), Error> {
+        let (min, max) = outputs.freq_range();
+        check(freq >= min && freq <= max, Error::InvalidParameter)?;
+        let (output_freq, max_div) = match outputs {
+            Quadrature::Phase { i, q } => {
+                check(i < 3 && q < 3 && i != q, Error::InvalidParameter)?;
+                (freq, MAX_PHASE_DIVIDER)
+            }
+            Quadrature::Lo4x(output) => {
+                check(output < 3, Error::InvalidParameter)?;
+                (4 * freq, MAX_DIVIDER)
+            }
+        };
+        // Retuning or resetting the PLL would move any other output on it
+        let own = |output: usize| match outputs {
+            Quadrature::Phase { i, q } => output == i || output == q,
+            Quadrature::Lo4x(lo) => output == lo,
+        };
+        check(
+            !self
+                .outputs
+                .iter()
+                .enumerate()
+                .any(|(output, state)| !own(output) && state.configured && state.pll == pll),
+            Error::PllConflict,
+        )?;
+        let div = even_divider(output_freq, max_div).ok_or(Error::InvalidParameter)?;
+        let xtal = self.crystal_millihertz();
+        let vco = output_freq as u128 * div as u128 * 1000;
+        let mult = (vco / xtal) as u32;
+        let num = ((vco % xtal) * FRAC_DENOM as u128 / xtal) as u32;
+        check((15..=90).contains(&mult), Error::InvalidParameter)?;
+        // Unlike setup_pll, this does not reset the other PLL
+        self.write_pll_ratio(pll, Ratio::new(mult, num, FRAC_DENOM))?;
+        match pll {
+            PLL::A => self.config.plla_configured = true,
+            PLL::B => self.config.pllb_configured = true,
+        }
+        match outputs {
+            Quadrature::Phase { i, q } => {
+                for (output, phase) in [(i, 0), (q, div as u8)] {
+                    self.setup_multisynth(output, pll, div, 0, 1)?;
+                    self.setup_rdiv(output, RDiv::Div1)?;
+                    self.write8(
+                        Registers::CLK0InitialPhaseOffset as u8 + output as u8,
+                        phase,
+                    )?;
+                }
+            }
+            Quadrature::Lo4x(output) => {
+                self.setup_multisynth(output, pll, div, 0, 1)?;
+                self.setup_rdiv(output, RDiv::Div1)?;
+                self.write8(Registers::CLK0InitialPhaseOffset as u8 + output as u8, 0)?;
+            }
+        }
+        // Reset this PLL only, which also starts its multisynths in phase
+        let reset = match pll {
+            PLL::A => 1 << 5,
+            PLL::B => 1 << 7,
+        };
+        self.write8(Registers::PLLReset as u8, reset)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::RegisterFile;
+    use crate::{Access, DeviceConfig, NoDelay, Recorder};
+
+    fn driver() -> Si5351<RegisterFile> {
+        Si5351::new(RegisterFile::new(), DeviceConfig::default()).unwrap()
+    }
+
+    #[test]
+    fn reports_freq_range() {
+        assert_eq!(
+            Quadrature::Phase { i: 0, q: 1 }.freq_range(),
+            (4_761_905, 112_500_000)
+        );
+        assert_eq!(Quadrature::Lo4x(0).freq_range(), (73_243, 28_125_000));
+    }
+
+    #[test]
+    fn sets_phase_offset_to_divider() {
+        let mut clock_gen = driver();
+        clock_gen
+            .set_quadrature(7_100_000, Quadrature::Phase { i: 0, q: 1 }, PLL::B)
+            .unwrap();
+        // 900MHz / 7.1MHz rounded down to an even divider
+        let registers = clock_gen.i2c_dev.registers();
+        assert_eq!(registers[165], 0);
+        assert_eq!(registers[166], 126);
+        for output in [0, 1] {
+            assert_eq!(clock_gen.outputs[output].pll, PLL::B);
+            let freq = clock_gen.output_freq_millihertz(output).unwrap();
+            assert!(freq.abs_diff(7_100_000_000) < 1000, "{freq}");
+        }
+    }
+
+    #[test]
+    fn rejects_freqs_out_of_range() {
+        let mut clock_gen = driver();
+        let phase = Quadrature::Phase { i: 0, q: 1 };
+        for freq in [3_200_000, QUADRATURE_MIN_FREQ - 1, QUADRATURE_MAX_FREQ + 1] {
+            assert_eq!(
+                clock_gen.set_quadrature(freq, phase, PLL::A),
+                Err(Error::InvalidParameter)
+            );
+        }
+        assert_eq!(
+            clock_gen.set_quadrature(7_100_000, Quadrature::Phase { i: 1, q: 1 }, PLL::A),
+            Err(Error::InvalidParameter)
+        );
+    }
+
+    #[test]
+    fn refuses_shared_pll() {
+        let mut clock_gen = driver();
+        clock_gen.set_freq(2, PLL::A, 10_000_000).unwrap();
+        let before = clock_gen.i2c_dev.registers().to_owned();
+        assert_eq!(
+            clock_gen.set_quadrature(7_100_000, Quadrature::Phase { i: 0, q: 1 }, PLL::A),
+            Err(Error::PllConflict)
+        );
+        assert_eq!(
+            clock_gen.set_quadrature(3_550_000, Quadrature::Lo4x(0), PLL::A),
+            Err(Error::PllConflict)
+        );
+        assert_eq!(clock_gen.i2c_dev.registers(), &before);
+        // The other PLL is free, and the outputs may move between PLLs
+        clock_gen
+            .set_quadrature(7_100_000, Quadrature::Phase { i: 0, q: 1 }, PLL::B)
+            .unwrap();
+        clock_gen
+            .set_quadrature(14_200_000, Quadrature::Phase { i: 1, q: 0 }, PLL::B)
+            .unwrap();
+    }
+
+    #[test]
+    fn resets_only_its_own_pll() {
+        let mut clock_gen = Si5351::with_observer(
+            RegisterFile::new(),
+            DeviceConfig::default(),
+            NoDelay,
+            Recorder::<64, 1>::new(),
+        )
+        .unwrap();
+        clock_gen.set_freq(2, PLL::A, 10_000_000).unwrap();
+        clock_gen.observer_mut().clear();
+        clock_gen
+            .set_quadrature(7_100_000, Quadrature::Phase { i: 0, q: 1 }, PLL::B)
+            .unwrap();
+        let resets: Vec<u8> = clock_gen
+            .observer()
+            .iter()
+            .filter(|record| {
+                record.access == Access::Write && record.reg == Registers::PLLReset as u8
+            })
+            .map(|record| record.data()[0])
+            .collect();
+        assert_eq!(resets, [1 << 7]);
+        // PLL A and CLK2 are untouched
+        assert!(
+            clock_gen
+                .observer()
+                .iter()
+                .all(|record| !(26..=33).contains(&record.reg) && record.reg != 18)
+        );
+        assert_eq!(clock_gen.output_freq_millihertz(2), Some(10_000_000_000));
+    }
+}
@@ -0,0 +1,513 @@
+//! Recording I2C sessions and replaying them as test fixtures.
+//!
+//! [`RecordingI2c`] wraps a bus and captures every transaction into a
+//! [`Trace`], which can be stored (with the `serde` feature) together with
+//! test results or field logs. [`ReplayI2c`] plays the part of the device
+//! for a stored trace: it answers reads with the recorded data, fails where
+//! the recording failed, and reports the first write which differs from
+//! the trace.
+//!
+//! ```
+//! use si5351a_adafruit::replay::{RecordingI2c, ReplayI2c};
+//! use si5351a_adafruit::{DeviceConfig, PLL, Si5351};
+//! # use embedded_hal::i2c::{ErrorKind, ErrorType, I2c, Operation};
+//! # struct Device([u8; 256]);
+//! # impl ErrorType for Device { type Error = ErrorKind; }
+//! # impl I2c for Device {
+//! #     fn transaction(&mut self, _: u8, ops: &mut [Operation<'_>]) -> Result<(), ErrorKind> {
+//! #         let mut reg = 0;
+//! #         for op in ops {
+//! #             match op {
+//! #                 Operation::Write(data) => {
+//! #                     reg = data[0] as usize;
+//! #                     for &byte in &data[1..] { self.0[reg] = byte; reg += 1; }
+//! #                 }
+//! #                 Operation::Read(data) => {
+//! #                     for byte in data.iter_mut() { *byte = self.0[reg]; reg += 1; }
+//! #                 }
+//! #             }
+//! #         }
+//! #         Ok(())
+//! #     }
+//! # }
+//! # let device = Device([0; 256]);
+//!
+//! // Record a session against the real device
+//! let mut clock_gen = Si5351::new(RecordingI2c::new(device), DeviceConfig::default()).unwrap();
+//! clock_gen.set_freq(0, PLL::A, 12_288_000).unwrap();
+//! let (_device, trace) = clock_gen.release().into_parts();
+//!
+//! // Check later sessions against it
+//! let mut clock_gen = Si5351::new(ReplayI2c::new(trace), DeviceConfig::default()).unwrap();
+//! clock_gen.set_freq(0, PLL::A, 12_288_000).unwrap();
+//! assert_eq!(clock_gen.release().finish(), Ok(()));
+//! ```
+
+use alloc::vec::Vec;
+use core::fmt;
+
+use embedded_hal::i2c::{ErrorKind, ErrorType, I2c, Operation};
+
+/// A recorded I2C session
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Trace {
+    /// The transactions in the order they happened
+    pub transactions: Vec<TraceTransaction>,
+}
+
+impl Trace {
+    /// An empty trace, e.g. to be filled by a [`RecordingI2c`] or built
+    /// by hand as a test fixture
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+/// One recorded I2C transaction
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct TraceTransaction {
+    /// The I2C address
+    pub address: u8,
+    /// The operations, reads holding the data which was read
+    pub operations: Vec<TraceOperation>,
+    /// The error the transaction failed with, if it did
+    #[cfg_attr(
+        feature = "serde",
+        serde(with = "crate::serde_impls::option_error_kind")
+    )]
+    pub error: Option<ErrorKind>,
+}
+
+/// One operation of a recorded transaction
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum TraceOperation {
+    Write(Vec<u8>),
+    Read(Vec<u8>),
+}
+
+/// An I2C bus which records every transaction made through it
+pub struct RecordingI2c<I2C> {
+    i2c: I2C,
+    trace: Trace,
+}
+
+impl<I2C: I2c> RecordingI2c<I2C> {
+    /// Starts recording an empty trace
+    pub fn new(i2c: I2C) -> Self {
+        Self {
+            i2c,
+            trace: Trace::new(),
+        }
+    }
+
+    /// The transactions recorded so far
+    pub fn trace(&self) -> &Trace {
+        &self.trace
+    }
+
+    /// Returns the bus and the recorded trace
+    pub fn into_parts(self) -> (I2C, Trace) {
+        (self.i2c, self.trace)
+    }
+}
+
+impl<I2C: I2c> ErrorType for RecordingI2c<I2C> {
+    type Error = I2C::Error;
+}
+
+impl<I2C: I2c> I2c for RecordingI2c<I2C> {
+    fn transaction(
+        &mut self,
+        address: u8,
+        operations: &mut [Operation<'_>],
+    ) -> Result<(), Self::Error> {
+        use embedded_hal::i2c::Error as _;
+
+        let result = self.i2c.transaction(address, operations);
+        let operations = operations
+            .iter()
+            .map(|operation| match operation {
+                Operation::Write(data) => TraceOperation::Write(data.to_vec()),
+                Operation::Read(data) => TraceOperation::Read(data.to_vec()),
+            })
+            .collect();
+        self.trace.transactions.push(TraceTransaction {
+            address,
+            operations,
+            error: result.as_ref().err().map(|error| error.kind()),
+        });
+        result
+    }
+}
+
+/// Where a replayed session first differs from its trace
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Divergence {
+    /// Index of the transaction in the trace
+    pub transaction: usize,
+    /// Index of the operation within the transaction
+    pub operation: usize,
+    /// Index of the byte within the operation, the register address being
+    /// byte 0 of a write
+    pub byte: usize,
+    /// The register the diverging byte is written to, if known
+    pub register: Option<u8>,
+    /// The byte of the trace, `None` if the trace has nothing there
+    pub expected: Option<u8>,
+    /// The byte of the session, `None` if the session has nothing there
+    pub found: Option<u8>,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "transaction {}, operation {}, byte {}",
+            self.transaction, self.operation, self.byte
+        )?;
+        if let Some(register) = self.register {
+            write!(f, " (register {register})")?;
+        }
+        match self.expected {
+            Some(expected) => write!(f, ": expected {expected:#04x}")?,
+            None => f.write_str(": expected nothing")?,
+        }
+        match self.found {
+            Some(found) => write!(f, ", found {found:#04x}"),
+            None => f.write_str(", found nothing"),
+        }
+    }
+}
+
+/// An I2C bus which plays back a [`Trace`] and checks that the session
+/// makes the same transactions.
+///
+/// Reads return the recorded data and transactions which failed in the
+/// recording fail again with the same kind. Once the session diverges from
+/// the trace, every transaction fails with `ErrorKind::Other` and the first
+/// divergence is kept, see [`ReplayI2c::divergence`].
+pub struct ReplayI2c {
+    trace: Trace,
+    position: usize,
+    divergence: Option<Divergence>,
+}
+
+impl ReplayI2c {
+    /// Plays back `trace` from its first transaction
+    ///
+    /// trace: The session to expect, e.g. one recorded with
+    /// [`RecordingI2c`]
+    pub fn new(trace: Trace) -> Self {
+        Self {
+            trace,
+            position: 0,
+            divergence: None,
+        }
+    }
+
+    /// The first divergence from the trace, if there was one
+    pub fn divergence(&self) -> Option<Divergence> {
+        self.divergence
+    }
+
+    /// The number of transactions replayed so far
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    /// Ends the session, returning the first divergence, or the first
+    /// transaction of the trace which was never made
+    pub fn finish(self) -> Result<(), Divergence> {
+        if let Some(divergence) = self.divergence {
+            return Err(divergence);
+        }
+        match self.trace.transactions.get(self.position) {
+            Some(missing) => Err(Divergence {
+                transaction: self.position,
+                operation: 0,
+                byte: 0,
+                register: first_byte(missing),
+                expected: first_byte(missing),
+                found: None,
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /// Compares a transaction of the session with the next one of the
+    /// trace
+    fn check(&self, address: u8, operations: &[Operation<'_>]) -> Result<(), Divergence> {
+        let diverged = |operation, byte, register, expected, found| Divergence {
+            transaction: self.position,
+            operation,
+            byte,
+            register,
+            expected,
+            found,
+        };
+        let Some(expected) = self.trace.transactions.get(self.position) else {
+            let found = operations.iter().find_map(|operation| match operation {
+                Operation::Write(data) => data.first().copied(),
+                Operation::Read(_) => None,
+            });
+            return Err(diverged(0, 0, found, None, found));
+        };
+        if expected.address != address {
+            return Err(diverged(0, 0, None, Some(expected.address), Some(address)));
+        }
+        // The register pointer after the previous operation
+        let mut pointer = None;
+        for index in 0..expected.operations.len().max(operations.len()) {
+            match (expected.operations.get(index), operations.get(index)) {
+                (Some(TraceOperation::Write(expected)), Some(Operation::Write(found))) => {
+                    let byte = (0..expected.len().max(found.len()))
+                        .find(|&byte| expected.get(byte) != found.get(byte));
+                    if let Some(byte) = byte {
+                        // Byte 0 selects the register, the others are written
+                        // to consecutive registers from there
+                        let register = match byte {
+                            0 => expected.first().copied(),
+                            _ => Some(expected[0].wrapping_add(byte as u8 - 1)),
+                        };
+                        let (expected, found) = (expected.get(byte), found.get(byte));
+                        return Err(diverged(
+                            index,
+                            byte,
+                            register,
+                            expected.copied(),
+                            found.copied(),
+                        ));
+                    }
+                    pointer = expected
+                        .first()
+                        .map(|&reg| reg.wrapping_add(expected.len() as u8 - 1));
+                }
+                (Some(TraceOperation::Read(expected)), Some(Operation::Read(found))) => {
+                    if expected.len() != found.len() {
+                        let byte = expected.len().min(found.len());
+                        let register = pointer.map(|reg| reg.wrapping_add(byte as u8));
+                        return Err(diverged(index, byte, register, None, None));
+                    }
+                    pointer = pointer.map(|reg| reg.wrapping_add(expected.len() as u8));
+                }
+                (expected, found) => {
+                    let expected = match expected {
+                        Some(TraceOperation::Write(data)) => data.first().copied(),
+                        _ => None,
+                    };
+                    let found = match found {
+                        Some(Operation::Write(data)) => data.first().copied(),
+                        _ => None,
+                    };
+                    return Err(diverged(index, 0, pointer, expected, found));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The first written byte of a transaction, the register address for the
+/// Si5351
+fn first_byte(transaction: &TraceTransaction) -> Option<u8> {
+    transaction
+        .operations
+        .iter()
+        .find_map(|operation| match operation {
+            TraceOperation::Write(data) => data.first().copied(),
+            TraceOperation::Read(_) => None,
+        })
+}
+
+impl ErrorType for ReplayI2c {
+    type Error = ErrorKind;
+}
+
+impl I2c for ReplayI2c {
+    fn transaction(
+        &mut self,
+        address: u8,
+        operations: &mut [Operation<'_>],
+    ) -> Result<(), Self::Error> {
+        if self.divergence.is_some() {
+            return Err(ErrorKind::Other);
+        }
+        if let Err(divergence) = self.check(address, operations) {
+            self.divergence = Some(divergence);
+            return Err(ErrorKind::Other);
+        }
+        let expected = &self.trace.transactions[self.position];
+        self.position += 1;
+        for (operation, recorded) in operations.iter_mut().zip(&expected.operations) {
+            if let (Operation::Read(data), TraceOperation::Read(recorded)) = (operation, recorded) {
+                data.copy_from_slice(recorded);
+            }
+        }
+        match expected.error {
+            Some(kind) => Err(kind),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::RegisterFile;
+    use crate::{ADDRESS, DeviceConfig, Error, PLL, Si5351};
+
+    fn write(data: &[u8]) -> TraceTransaction {
+        TraceTransaction {
+            address: ADDRESS,
+            operations: vec![TraceOperation::Write(data.to_vec())],
+            error: None,
+        }
+    }
+
+    fn write_read(reg: u8, data: &[u8]) -> TraceTransaction {
+        TraceTransaction {
+            address: ADDRESS,
+            operations: vec![
+                TraceOperation::Write(vec![reg]),
+                TraceOperation::Read(data.to_vec()),
+            ],
+            error: None,
+        }
+    }
+
+    /// `Si5351::new` and `set_freq(0, PLL::A, 12_288_000)` on a device
+    /// holding 0 in all registers. 12.288MHz is PLL A at 35 + 408357 /
+    /// 1048575 (884.736MHz) divided by 72.
+    fn stored_trace() -> Trace {
+        Trace {
+            transactions: vec![
+                // Outputs disabled and powered down
+                write(&[3, 0xff]),
+                write(&[16, 0x80]),
+                write(&[17, 0x80]),
+                write(&[18, 0x80]),
+                write(&[19, 0x80]),
+                write(&[20, 0x80]),
+                write(&[21, 0x80]),
+                write(&[22, 0x80]),
+                write(&[23, 0x80]),
+                // 10pF crystal load
+                write(&[183, 0xc0]),
+                // Spread spectrum off
+                write_read(149, &[0x00]),
+                write(&[149, 0x00]),
+                // Sticky status cleared
+                write(&[1, 0x00]),
+                // PLL A: P3 = 1048575, P1 = 4017, P2 = 889521
+                write(&[26, 0xff, 0xff, 0x00, 0x0f, 0xb1, 0xfd, 0x92, 0xb1]),
+                write(&[177, 0xa0]),
+                // Multisynth 0: P3 = 1, P1 = 8704, P2 = 0
+                write(&[42, 0x00, 0x01, 0x00, 0x22, 0x00, 0x00, 0x00, 0x00]),
+                // CLK0 on, integer mode, PLL A, 8mA
+                write(&[16, 0x4f]),
+                // R0 divider 1
+                write_read(44, &[0x00]),
+                write(&[44, 0x00]),
+            ],
+        }
+    }
+
+    /// Runs the session of [`stored_trace`] against `trace`
+    fn replay(trace: Trace) -> (Result<(), Error>, Result<(), Divergence>) {
+        let mut clock_gen = Si5351::new(ReplayI2c::new(trace), DeviceConfig::default()).unwrap();
+        let result = clock_gen.set_freq(0, PLL::A, 12_288_000);
+        (result, clock_gen.release().finish())
+    }
+
+    #[test]
+    fn records_stored_trace() {
+        let mut clock_gen = Si5351::new(
+            RecordingI2c::new(RegisterFile::new()),
+            DeviceConfig::default(),
+        )
+        .unwrap();
+        clock_gen.set_freq(0, PLL::A, 12_288_000).unwrap();
+        let (_, trace) = clock_gen.release().into_parts();
+        assert_eq!(trace, stored_trace());
+    }
+
+    #[test]
+    fn replays_stored_trace() {
+        assert_eq!(replay(stored_trace()), (Ok(()), Ok(())));
+    }
+
+    #[test]
+    fn reports_changed_byte() {
+        let mut trace = stored_trace();
+        // P1 bits 7..0 of PLL A
+        trace.transactions[13].operations[0] =
+            TraceOperation::Write(vec![26, 0xff, 0xff, 0x00, 0x0f, 0xb0, 0xfd, 0x92, 0xb1]);
+        let divergence = Divergence {
+            transaction: 13,
+            operation: 0,
+            byte: 5,
+            register: Some(30),
+            expected: Some(0xb0),
+            found: Some(0xb1),
+        };
+        assert_eq!(
+            replay(trace),
+            (
+                Err(Error::I2CTransaction(ErrorKind::Other)),
+                Err(divergence)
+            )
+        );
+        assert_eq!(
+            divergence.to_string(),
+            "transaction 13, operation 0, byte 5 (register 30): expected 0xb0, found 0xb1"
+        );
+    }
+
+    #[test]
+    fn reports_missing_transaction() {
+        let mut trace = stored_trace();
+        trace.transactions.push(write(&[3, 0xfe]));
+        assert_eq!(
+            replay(trace),
+            (
+                Ok(()),
+                Err(Divergence {
+                    transaction: 19,
+                    operation: 0,
+                    byte: 0,
+                    register: Some(3),
+                    expected: Some(3),
+                    found: None,
+                })
+            )
+        );
+    }
+
+    #[test]
+    fn reports_extra_transaction() {
+        let mut trace = stored_trace();
+        trace.transactions.truncate(17);
+        assert_eq!(
+            replay(trace),
+            (
+                Err(Error::I2CTransaction(ErrorKind::Other)),
+                Err(Divergence {
+                    transaction: 17,
+                    operation: 0,
+                    byte: 0,
+                    register: Some(44),
+                    expected: None,
+                    found: Some(44),
+                })
+            )
+        );
+    }
+}
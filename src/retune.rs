@@ -0,0 +1,346 @@
+//! Fast, glitch-free retuning of outputs without resetting the PLLs.
+
+use embedded_hal::delay::DelayNs;
+use embedded_hal::i2c::I2c;
+
+use crate::freqplan::{
+    FRAC_DENOM, Ratio, VCO_MAX, VCO_MIN, encode_divider, multisynth_divider, pack_parameters,
+};
+use crate::{Error, Observer, PLL, Registers, Si5351, check};
+
+/// How an output was moved to a new frequency
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Retune {
+    /// Only the PLL multiplier was updated, without a PLL reset
+    Pll,
+    /// Only the multisynth divider was updated, without a PLL reset
+    Multisynth,
+    /// The move needed a full reconfiguration with
+    /// [`Si5351::set_freq`], including a PLL reset
+    Full,
+}
+
+/// A fast retune worked out by `plan_retune`
+enum FastRetune {
+    Pll(Ratio),
+    Multisynth(Ratio),
+}
+
+impl<I2C: I2c, DELAY: DelayNs, OBS: Observer> Si5351<I2C, DELAY, OBS> {
+    /// Returns the frequency an output actually generates, in millihertz,
+    /// or `None` if the output has not been configured
+    ///
+    /// output: The output channel (0..2)
+    pub fn output_freq_millihertz(&self, output: usize) -> Option<u64> {
+        let state = self.outputs.get(output).filter(|state| state.configured)?;
+        let pll = self.pll_ratio(state.pll);
+        let ms = state.ms_ratio;
+        let numerator = self.crystal_millihertz()
+            * (pll.a as u128 * pll.c as u128 + pll.b as u128)
+            * ms.c as u128;
+        let denominator = pll.c as u128
+            * (ms.a as u128 * ms.c as u128 + ms.b as u128)
+            * state.r_div.denominator_u8() as u128;
+        numerator.checked_div(denominator).map(|freq| freq as u64)
+    }
+
+    /// Returns whether an output can be moved to `freq` (in millihertz)
+    /// without a full reconfiguration
+    pub fn can_retune_fast(&self, output: usize, freq: u64) -> bool {
+        self.plan_retune(output, freq).is_some()
+    }
+
+    /// Moves an output to a new frequency without resetting the PLL, if
+    /// possible.
+    ///
+    /// If no other output shares the PLL, the PLL multiplier is changed
+    /// and the multisynth is left alone. Otherwise only the multisynth
+    /// divider is changed. In both cases only the registers which change
+    /// are written, in a single burst.
+    ///
+    /// If the new frequency is out of reach of both (or the output was never
+    /// configured), the output is set up again with [`Si5351::set_freq`],
+    /// which resets the PLLs and briefly drops the clock. It keeps its own
+    /// PLL if no other output uses it, or else moves to the PLL no other
+    /// output uses.
+    ///
+    /// Returns `Error::PllConflict` if that setup is needed but both PLLs
+    /// are used by other outputs, as it would change their frequencies.
+    ///
+    /// output: The output channel to use (0..2)
+    ///
+    /// freq: The new output frequency in Hz
+    pub fn retune(&mut self, output: usize, freq: u32) -> Result<Retune, Error> {
+        self.retune_millihertz(output, freq as u64 * 1000)
+    }
+
+    /// Like [`Si5351::retune`], with the frequency given in millihertz for
+    /// sub-Hz resolution
+    pub fn retune_millihertz(&mut self, output: usize, freq: u64) -> Result<Retune, Error> {
+        check(output < 3, Error::InvalidParameter)?;
+        check(freq > 0, Error::InvalidParameter)?;
+        match self.plan_retune(output, freq) {
+            Some(FastRetune::Pll(ratio)) => {
+                self.write_pll_ratio(self.outputs[output].pll, ratio)?;
+                Ok(Retune::Pll)
+            }
+            Some(FastRetune::Multisynth(ratio)) => {
+                self.write_ms_ratio(output, ratio)?;
+                Ok(Retune::Multisynth)
+            }
+            None => {
+                let pll = self.free_pll(output).ok_or(Error::PllConflict)?;
+                let hz = u32::try_from(freq / 1000).map_err(|_| Error::InvalidParameter)?;
+                self.set_freq(output, pll, hz)?;
+                // set_freq only has 1Hz resolution, fine tune the rest
+                if let Some(fine) = self.plan_retune(output, freq) {
+                    match fine {
+                        FastRetune::Pll(ratio) => self.write_pll_ratio(pll, ratio)?,
+                        FastRetune::Multisynth(ratio) => self.write_ms_ratio(output, ratio)?,
+                    }
+                }
+                Ok(Retune::Full)
+            }
+        }
+    }
+
+    /// Moves an output by `delta` Hz relative to its current frequency, e.g.
+    /// for each step of a rotary encoder
+    ///
+    /// output: The output channel to use (0..2)
+    ///
+    /// delta: The frequency change in Hz
+    pub fn nudge(&mut self, output: usize, delta: i32) -> Result<Retune, Error> {
+        let current = self
+            .output_freq_millihertz(output)
+            .ok_or(Error::InvalidParameter)?;
+        let freq = current
+            .checked_add_signed(delta as i64 * 1000)
+            .ok_or(Error::InvalidParameter)?;
+        self.retune_millihertz(output, freq)
+    }
+
+    /// The PLL an output can be set up on without changing the frequency
+    /// of another configured output: its own PLL if no other output uses
+    /// it, or else the PLL no other output uses
+    pub(crate) fn free_pll(&self, output: usize) -> Option<PLL> {
+        let state = self.outputs[output];
+        let used_by_others = |pll: PLL| {
+            self.outputs
+                .iter()
+                .enumerate()
+                .any(|(i, other)| i != output && other.configured && other.pll == pll)
+        };
+        let own = state.configured.then_some(state.pll);
+        [own, Some(PLL::A), Some(PLL::B)]
+            .into_iter()
+            .flatten()
+            .find(|&pll| !used_by_others(pll))
+    }
+
+    fn plan_retune(&self, output: usize, freq: u64) -> Option<FastRetune> {
+        let state = self.outputs.get(output).filter(|state| state.configured)?;
+        let shared = self
+            .outputs
+            .iter()
+            .enumerate()
+            .any(|(i, other)| i != output && other.configured && other.pll == state.pll);
+        let r = state.r_div.denominator_u8() as u128;
+        let xtal = self.crystal_millihertz();
+        if !shared {
+            // Keep the multisynth, move the PLL
+            let ms = state.ms_ratio;
+            let vco =
+                freq as u128 * (ms.a as u128 * ms.c as u128 + ms.b as u128) * r / ms.c as u128;
+            if vco >= VCO_MIN as u128 * 1000 && vco <= VCO_MAX as u128 * 1000 {
+                let mult = (vco / xtal) as u32;
+                let num = ((vco % xtal) * FRAC_DENOM as u128 / xtal) as u32;
+                if (15..=90).contains(&mult) {
+                    return Some(FastRetune::Pll(Ratio::new(mult, num, FRAC_DENOM)));
+                }
+            }
+        }
+        // Keep the PLL and the R divider, move the multisynth
+        let pll = self.pll_ratio(state.pll);
+        let vco_numerator = xtal * (pll.a as u128 * pll.c as u128 + pll.b as u128);
+        let r_shift = state.r_div as u8;
+        let (ratio, _) =
+            multisynth_divider(vco_numerator, pll.c as u128, freq, (r_shift, r_shift), true)
+                .ok()?;
+        Some(FastRetune::Multisynth(ratio))
+    }
+
+    /// Writes the changed PLL parameter registers, without a PLL reset
+    pub(crate) fn write_pll_ratio(&mut self, pll: PLL, ratio: Ratio) -> Result<(), Error> {
+        let (p1, p2, p3) = encode_divider(ratio.a, ratio.b, ratio.c);
+        let base_addr = match pll {
+            PLL::A => 26_u8,
+            PLL::B => 34_u8,
+        };
+        self.write_changed(base_addr, &pack_parameters(p1, p2, p3, 0))?;
+        let fvco =
+            crate::freqplan::pll_freq(self.config.crystal_freq as u32, ratio.a, ratio.b, ratio.c);
+        debug!(
+            "PLL {} retuned: {}, VCO {=u32}Hz, P1 {=u32} P2 {=u32} P3 {=u32}",
+            pll, ratio, fvco, p1, p2, p3
+        );
+        match pll {
+            PLL::A => {
+                self.config.plla_freq = fvco;
+                self.config.plla_ratio = ratio;
+            }
+            PLL::B => {
+                self.config.pllb_freq = fvco;
+                self.config.pllb_ratio = ratio;
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the changed multisynth parameter registers and switches the
+    /// output between integer and fractional mode if needed
+    fn write_ms_ratio(&mut self, output: usize, ratio: Ratio) -> Result<(), Error> {
+        let (p1, p2, p3) = encode_divider(ratio.a, ratio.b, ratio.c);
+        let base_addr = Registers::Multisynth0Parameters1 as u8 + 8 * output as u8;
+        let params = pack_parameters(p1, p2, p3, self.last_rdiv_value[output]);
+        self.write_changed(base_addr, &params)?;
+        self.outputs[output].ms_ratio = ratio;
+        debug!(
+            "MS{=usize} retuned: {}, P1 {=u32} P2 {=u32} P3 {=u32}",
+            output, ratio, p1, p2, p3
+        );
+        let control_reg = Registers::CLK0Control as u8 + output as u8;
+        let mut control = self.registers.get(control_reg).unwrap_or_else(|| {
+            // MS0 as CLK0 source, Clock not inverted, powered up
+            let control = 0x0c | self.outputs[output].drive as u8;
+            match self.outputs[output].pll {
+                PLL::A => control,
+                PLL::B => control | (1 << 5),
+            }
+        });
+        if ratio.b == 0 {
+            control |= 1 << 6; // Integer mode
+        } else {
+            control &= !(1 << 6);
+        }
+        self.write_changed(control_reg, &[control])?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::RegisterFile;
+    use crate::{DeviceConfig, Error, PLL, Retune, Si5351};
+
+    fn driver(outputs: &[(PLL, u32)]) -> Si5351<RegisterFile> {
+        let mut clock_gen = Si5351::new(RegisterFile::new(), DeviceConfig::default()).unwrap();
+        for (output, &(pll, freq)) in outputs.iter().enumerate() {
+            clock_gen.set_freq(output, pll, freq).unwrap();
+        }
+        clock_gen
+    }
+
+    #[test]
+    fn moves_exclusive_pll() {
+        let mut clock_gen = driver(&[(PLL::A, 10_000_000)]);
+        assert_eq!(
+            clock_gen.retune_millihertz(0, 9_999_500_250),
+            Ok(Retune::Pll)
+        );
+        let freq = clock_gen.output_freq_millihertz(0).unwrap();
+        assert!(freq.abs_diff(9_999_500_250) < 1000, "{freq}");
+    }
+
+    #[test]
+    fn moves_multisynth_of_shared_pll() {
+        let mut clock_gen = driver(&[(PLL::A, 10_000_000), (PLL::A, 14_000_000)]);
+        let other = clock_gen.output_freq_millihertz(1);
+        assert_eq!(clock_gen.retune(0, 10_100_000), Ok(Retune::Multisynth));
+        let freq = clock_gen.output_freq_millihertz(0).unwrap();
+        assert!(freq.abs_diff(10_100_000_000) < 1000, "{freq}");
+        assert_eq!(clock_gen.output_freq_millihertz(1), other);
+    }
+
+    #[test]
+    fn sets_up_own_pll_again() {
+        let mut clock_gen = driver(&[(PLL::A, 10_000_000), (PLL::B, 14_000_000)]);
+        let other = clock_gen.output_freq_millihertz(1);
+        assert!(!clock_gen.can_retune_fast(0, 120_000_000_000));
+        assert_eq!(clock_gen.retune(0, 120_000_000), Ok(Retune::Full));
+        assert_eq!(clock_gen.output_freq_millihertz(0), Some(120_000_000_000));
+        assert_eq!(clock_gen.outputs[0].pll, PLL::A);
+        assert_eq!(clock_gen.output_freq_millihertz(1), other);
+    }
+
+    #[test]
+    fn moves_to_free_pll() {
+        let mut clock_gen = driver(&[(PLL::A, 10_000_000), (PLL::A, 14_000_000)]);
+        let other = clock_gen.output_freq_millihertz(1);
+        assert_eq!(clock_gen.retune(0, 120_000_000), Ok(Retune::Full));
+        assert_eq!(clock_gen.output_freq_millihertz(0), Some(120_000_000_000));
+        assert_eq!(clock_gen.outputs[0].pll, PLL::B);
+        assert_eq!(clock_gen.output_freq_millihertz(1), other);
+    }
+
+    #[test]
+    fn sets_up_unconfigured_output_on_free_pll() {
+        let mut clock_gen = driver(&[(PLL::A, 10_000_000)]);
+        assert_eq!(clock_gen.retune(1, 14_000_000), Ok(Retune::Full));
+        assert_eq!(clock_gen.outputs[1].pll, PLL::B);
+        assert_eq!(clock_gen.output_freq_millihertz(0), Some(10_000_000_000));
+    }
+
+    #[test]
+    fn refuses_pll_used_by_others() {
+        let mut clock_gen = driver(&[
+            (PLL::A, 10_000_000),
+            (PLL::B, 14_000_000),
+            (PLL::A, 12_000_000),
+        ]);
+        let before = clock_gen.i2c_dev.registers().to_owned();
+        let freqs = [0, 1, 2].map(|output| clock_gen.output_freq_millihertz(output));
+        assert_eq!(clock_gen.retune(0, 120_000_000), Err(Error::PllConflict));
+        assert_eq!(clock_gen.i2c_dev.registers(), &before);
+        assert_eq!(
+            [0, 1, 2].map(|output| clock_gen.output_freq_millihertz(output)),
+            freqs
+        );
+    }
+
+    #[test]
+    fn nudges_relative_to_current_freq() {
+        let mut clock_gen = driver(&[(PLL::A, 7_074_000)]);
+        assert_eq!(clock_gen.nudge(0, -1500), Ok(Retune::Pll));
+        let freq = clock_gen.output_freq_millihertz(0).unwrap();
+        assert!(freq.abs_diff(7_072_500_000) < 1000, "{freq}");
+        assert_eq!(clock_gen.nudge(1, 100), Err(Error::InvalidParameter));
+    }
+
+    /// The exact PLL A frequency in millihertz
+    fn vco_a(clock_gen: &Si5351<RegisterFile>) -> u128 {
+        let pll = clock_gen.pll_ratio(PLL::A);
+        clock_gen.crystal_millihertz() * (pll.a as u128 * pll.c as u128 + pll.b as u128)
+            / pll.c as u128
+    }
+
+    #[test]
+    fn does_not_divide_shared_pll_by_4() {
+        let clock_gen = driver(&[(PLL::A, 10_000_000), (PLL::A, 14_000_000)]);
+        let vco = vco_a(&clock_gen);
+        // MSx_DIVBY4 is not supported, 6 is an ordinary integer divider
+        assert!(!clock_gen.can_retune_fast(0, (vco / 4) as u64));
+        assert!(clock_gen.can_retune_fast(0, (vco / 6) as u64));
+        assert!(clock_gen.can_retune_fast(0, (vco / 8) as u64));
+    }
+
+    #[test]
+    fn rejects_dividers_beyond_u32() {
+        let clock_gen = driver(&[(PLL::A, 10_000_000), (PLL::A, 14_000_000)]);
+        // A divider of vco / 1mHz, which does not fit in 32 bits
+        assert!(vco_a(&clock_gen) > u32::MAX as u128);
+        assert!(!clock_gen.can_retune_fast(0, 1));
+    }
+}
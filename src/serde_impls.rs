@@ -0,0 +1,252 @@
+//! serde support for the field types serde has no implementation for.
+
+/// [`ErrorKind`](embedded_hal::i2c::ErrorKind) is defined by embedded-hal
+/// and non exhaustive, so it is mirrored here. Kinds added to embedded-hal
+/// later are serialized as `Other`.
+pub(crate) mod error_kind {
+    use embedded_hal::i2c::{ErrorKind, NoAcknowledgeSource};
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    #[derive(Serialize, Deserialize)]
+    enum Kind {
+        Bus,
+        ArbitrationLoss,
+        NoAcknowledge(Source),
+        Overrun,
+        Other,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    enum Source {
+        Address,
+        Data,
+        Unknown,
+    }
+
+    pub(crate) fn serialize<S: Serializer>(
+        kind: &ErrorKind,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        let kind = match *kind {
+            ErrorKind::Bus => Kind::Bus,
+            ErrorKind::ArbitrationLoss => Kind::ArbitrationLoss,
+            ErrorKind::NoAcknowledge(source) => Kind::NoAcknowledge(match source {
+                NoAcknowledgeSource::Address => Source::Address,
+                NoAcknowledgeSource::Data => Source::Data,
+                NoAcknowledgeSource::Unknown => Source::Unknown,
+            }),
+            ErrorKind::Overrun => Kind::Overrun,
+            _ => Kind::Other,
+        };
+        kind.serialize(serializer)
+    }
+
+    pub(crate) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<ErrorKind, D::Error> {
+        Ok(match Kind::deserialize(deserializer)? {
+            Kind::Bus => ErrorKind::Bus,
+            Kind::ArbitrationLoss => ErrorKind::ArbitrationLoss,
+            Kind::NoAcknowledge(source) => ErrorKind::NoAcknowledge(match source {
+                Source::Address => NoAcknowledgeSource::Address,
+                Source::Data => NoAcknowledgeSource::Data,
+                Source::Unknown => NoAcknowledgeSource::Unknown,
+            }),
+            Kind::Overrun => ErrorKind::Overrun,
+            Kind::Other => ErrorKind::Other,
+        })
+    }
+}
+
+/// `Option<ErrorKind>`, see [`error_kind`]
+#[cfg(feature = "alloc")]
+pub(crate) mod option_error_kind {
+    use embedded_hal::i2c::ErrorKind;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    #[derive(Serialize, Deserialize)]
+    struct Kind(#[serde(with = "super::error_kind")] ErrorKind);
+
+    pub(crate) fn serialize<S: Serializer>(
+        kind: &Option<ErrorKind>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        kind.map(Kind).serialize(serializer)
+    }
+
+    pub(crate) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<ErrorKind>, D::Error> {
+        Ok(Option::<Kind>::deserialize(deserializer)?.map(|Kind(kind)| kind))
+    }
+}
+
+/// Byte arrays of any length as tuples, serde itself stops at 32
+pub(crate) mod byte_array {
+    use core::fmt;
+
+    use serde::de::{Error, SeqAccess, Visitor};
+    use serde::ser::SerializeTuple;
+    use serde::{Deserializer, Serializer};
+
+    pub(crate) fn serialize<S: Serializer, const N: usize>(
+        bytes: &[u8; N],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        let mut tuple = serializer.serialize_tuple(N)?;
+        for byte in bytes {
+            tuple.serialize_element(byte)?;
+        }
+        tuple.end()
+    }
+
+    pub(crate) fn deserialize<'de, D: Deserializer<'de>, const N: usize>(
+        deserializer: D,
+    ) -> Result<[u8; N], D::Error> {
+        struct BytesVisitor<const N: usize>;
+
+        impl<'de, const N: usize> Visitor<'de> for BytesVisitor<N> {
+            type Value = [u8; N];
+
+            fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                write!(f, "{N} bytes")
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<[u8; N], A::Error> {
+                let mut bytes = [0; N];
+                for (i, byte) in bytes.iter_mut().enumerate() {
+                    *byte = seq
+                        .next_element()?
+                        .ok_or_else(|| A::Error::invalid_length(i, &self))?;
+                }
+                Ok(bytes)
+            }
+        }
+
+        deserializer.deserialize_tuple(N, BytesVisitor::<N>)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embedded_hal::i2c::{ErrorKind, NoAcknowledgeSource};
+    use serde::de::DeserializeOwned;
+    use serde::{Deserialize, Serialize};
+
+    use crate::freqplan::Plan;
+    use crate::{
+        ChipConfig, CrystalFreq, CrystalLoad, DisableState, DriveStrength, Error, HealthPolicy,
+        OutputConfig, OutputFreq, PLL, PllConfig, RDiv, SpreadMode, SpreadSpectrum,
+    };
+
+    const KINDS: [ErrorKind; 7] = [
+        ErrorKind::Bus,
+        ErrorKind::ArbitrationLoss,
+        ErrorKind::NoAcknowledge(NoAcknowledgeSource::Address),
+        ErrorKind::NoAcknowledge(NoAcknowledgeSource::Data),
+        ErrorKind::NoAcknowledge(NoAcknowledgeSource::Unknown),
+        ErrorKind::Overrun,
+        ErrorKind::Other,
+    ];
+
+    fn round_trip<T: Serialize + DeserializeOwned>(value: &T) -> T {
+        serde_json::from_str(&serde_json::to_string(value).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn error_kinds_round_trip() {
+        for kind in KINDS {
+            let error = Error::I2CTransaction(kind);
+            assert_eq!(round_trip(&error), error);
+        }
+        assert_eq!(
+            serde_json::to_string(&Error::I2CTransaction(ErrorKind::NoAcknowledge(
+                NoAcknowledgeSource::Data
+            )))
+            .unwrap(),
+            r#"{"I2CTransaction":{"NoAcknowledge":"Data"}}"#
+        );
+    }
+
+    #[cfg(feature = "alloc")]
+    #[test]
+    fn optional_error_kinds_round_trip() {
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        struct Failed(#[serde(with = "super::option_error_kind")] Option<ErrorKind>);
+
+        for kind in KINDS.map(Some).into_iter().chain([None]) {
+            assert_eq!(round_trip(&Failed(kind)), Failed(kind));
+        }
+        assert_eq!(serde_json::to_string(&Failed(None)).unwrap(), "null");
+    }
+
+    #[test]
+    fn plan_image_round_trips() {
+        let plan = Plan::new(CrystalFreq::MHZ25)
+            .clk(0, 10_000_000)
+            .clk(1, 12_288_000)
+            .try_build()
+            .unwrap();
+        assert_eq!(round_trip(&plan), plan);
+        // The image is longer than the 32 bytes serde handles itself
+        #[derive(Serialize, Deserialize)]
+        struct Image(#[serde(with = "super::byte_array")] [u8; 40]);
+
+        let image: [u8; 40] = core::array::from_fn(|i| i as u8);
+        assert_eq!(round_trip(&Image(image)).0, image);
+        let short = serde_json::to_string(&[0u8; 39][..]).unwrap();
+        assert!(serde_json::from_str::<Image>(&short).is_err());
+    }
+
+    #[test]
+    fn configs_round_trip() {
+        let mut config = ChipConfig {
+            crystal_freq: CrystalFreq::MHZ27,
+            crystal_load: CrystalLoad::PF8,
+            plls: [
+                Some(PllConfig {
+                    mult: 32,
+                    num: 1,
+                    denom: 3,
+                }),
+                None,
+            ],
+            spread_spectrum: Some(SpreadSpectrum {
+                amplitude: 50,
+                mode: SpreadMode::Center,
+            }),
+            ..ChipConfig::default()
+        };
+        config.outputs[0] = OutputConfig {
+            freq: Some(OutputFreq::Hz(10_000_000)),
+            enabled: true,
+            ..OutputConfig::default()
+        };
+        config.outputs[2] = OutputConfig {
+            freq: Some(OutputFreq::Dividers {
+                div: 36,
+                num: 0,
+                denom: 1,
+                r_div: RDiv::Div4,
+            }),
+            pll: PLL::B,
+            drive: DriveStrength::MA2,
+            invert: true,
+            disable_state: DisableState::HighImpedance,
+            phase: 12,
+            enabled: false,
+        };
+        assert_eq!(round_trip(&config), config);
+        let policy = HealthPolicy {
+            lol_polls: 5,
+            los_polls: 3,
+            restore_on_reset: false,
+            reset_unlocked_pll: true,
+        };
+        assert_eq!(round_trip(&policy), policy);
+        assert_eq!(
+            round_trip(&HealthPolicy::default()),
+            HealthPolicy::default()
+        );
+    }
+}
@@ -0,0 +1,265 @@
+//! Per-output handles for firmware where different tasks own different
+//! clocks.
+//!
+//! [`Si5351::split`] hands out one [`OutputHandle`] per output, all sharing
+//! the driver through a [`RefCell`] or, with the `critical-section`
+//! feature, a `critical_section::Mutex<RefCell<_>>` for tasks running at
+//! different interrupt priorities. A handle only touches its own output;
+//! which PLL it may use is decided centrally, so a handle never changes the
+//! frequency of an output owned by another one. Each output is handed out
+//! once, until its handle is dropped.
+//!
+//! ```no_run
+//! # fn example<I2C: embedded_hal::i2c::I2c>(
+//! #     clock_gen: si5351a_adafruit::Si5351<I2C>,
+//! # ) -> Result<(), si5351a_adafruit::Error> {
+//! use core::cell::RefCell;
+//! use si5351a_adafruit::Si5351;
+//!
+//! let shared = RefCell::new(clock_gen);
+//! let [mut audio_clock, mut radio_clock, _] = Si5351::split(&shared)?;
+//! audio_clock.set_freq(12_288_000)?;
+//! audio_clock.enable(true)?;
+//! radio_clock.set_freq(7_074_000)?;
+//! radio_clock.enable(true)?;
+//! # Ok(())
+//! # }
+//! ```
+
+use core::cell::RefCell;
+
+use embedded_hal::delay::DelayNs;
+use embedded_hal::i2c::I2c;
+
+use crate::freqplan::multisynth_divider;
+use crate::{DriveStrength, Error, Observer, PLL, Retune, Si5351, check};
+
+/// A driver shared between the handles of [`Si5351::split`]
+pub trait SharedDriver {
+    type I2c: I2c;
+    type Delay: DelayNs;
+    type Observer: Observer;
+
+    /// Runs `f` with exclusive access to the driver
+    fn lock<R>(
+        &self,
+        f: impl FnOnce(&mut Si5351<Self::I2c, Self::Delay, Self::Observer>) -> R,
+    ) -> R;
+}
+
+impl<I2C: I2c, DELAY: DelayNs, OBS: Observer> SharedDriver for RefCell<Si5351<I2C, DELAY, OBS>> {
+    type I2c = I2C;
+    type Delay = DELAY;
+    type Observer = OBS;
+
+    /// Panics if the driver is already borrowed
+    fn lock<R>(&self, f: impl FnOnce(&mut Si5351<I2C, DELAY, OBS>) -> R) -> R {
+        f(&mut self.borrow_mut())
+    }
+}
+
+#[cfg(feature = "critical-section")]
+impl<I2C: I2c, DELAY: DelayNs, OBS: Observer> SharedDriver
+    for critical_section::Mutex<RefCell<Si5351<I2C, DELAY, OBS>>>
+{
+    type I2c = I2C;
+    type Delay = DELAY;
+    type Observer = OBS;
+
+    /// Accesses the driver in a critical section
+    fn lock<R>(&self, f: impl FnOnce(&mut Si5351<I2C, DELAY, OBS>) -> R) -> R {
+        critical_section::with(|cs| f(&mut self.borrow_ref_mut(cs)))
+    }
+}
+
+/// Handle to a single output of a shared driver, see [`Si5351::split`]
+pub struct OutputHandle<'a, S: SharedDriver> {
+    driver: &'a S,
+    output: usize,
+}
+
+impl<I2C: I2c, DELAY: DelayNs, OBS: Observer> Si5351<I2C, DELAY, OBS> {
+    /// Splits a shared driver into one handle per output (CLK0..CLK2).
+    ///
+    /// The handles lock the driver only for the duration of each call,
+    /// so they can be given to different tasks. The driver itself stays
+    /// usable through the `RefCell` or `Mutex` for setup that affects all
+    /// outputs.
+    ///
+    /// Returns `Error::OutputTaken` if a handle of an earlier split is
+    /// still alive. Dropping a handle gives its output back.
+    pub fn split<S>(driver: &S) -> Result<[OutputHandle<'_, S>; 3], Error>
+    where
+        S: SharedDriver<I2c = I2C, Delay = DELAY, Observer = OBS>,
+    {
+        let free = driver.lock(|si5351| {
+            let free = si5351.handles == 0;
+            if free {
+                si5351.handles = 0b111;
+            }
+            free
+        });
+        check(free, Error::OutputTaken)?;
+        Ok([0, 1, 2].map(|output| OutputHandle { driver, output }))
+    }
+
+    /// Moves an output to a new frequency without changing the frequency
+    /// of any other configured output.
+    ///
+    /// A fast retune is used where possible. Otherwise the output is set up
+    /// again on its own PLL if no other output uses it, or else on the PLL
+    /// no other output uses. The PLL reset of that setup briefly
+    /// interrupts the other outputs, but leaves their frequencies alone.
+    /// With both PLLs taken, the output gets a fractional multisynth on one
+    /// of them, leaving the PLL as it is.
+    ///
+    /// Returns `Error::PllConflict` if both PLLs are in use by other
+    /// outputs and the new frequency cannot be divided down from either.
+    fn tune_exclusive(&mut self, output: usize, freq: u64) -> Result<Retune, Error> {
+        check(output < 3, Error::InvalidParameter)?;
+        check(freq > 0, Error::InvalidParameter)?;
+        if self.can_retune_fast(output, freq) {
+            return self.retune_millihertz(output, freq);
+        }
+        if let Some(pll) = self.free_pll(output) {
+            let hz = u32::try_from(freq / 1000).map_err(|_| Error::InvalidParameter)?;
+            self.set_freq(output, pll, hz)?;
+            // set_freq only has 1Hz resolution, the PLL is now exclusive so
+            // the rest is a fast retune
+            self.retune_millihertz(output, freq)?;
+            return Ok(Retune::Full);
+        }
+        // Both PLLs are taken, divide one of them down
+        let xtal = self.crystal_millihertz();
+        for pll in [PLL::A, PLL::B] {
+            let ratio = self.pll_ratio(pll);
+            let vco_numerator = xtal * (ratio.a as u128 * ratio.c as u128 + ratio.b as u128);
+            if let Ok((ms, r_div)) =
+                multisynth_divider(vco_numerator, ratio.c as u128, freq, (0, 7), true)
+            {
+                self.setup_multisynth(output, pll, ms.a, ms.b, ms.c)?;
+                self.setup_rdiv(output, r_div)?;
+                return Ok(Retune::Multisynth);
+            }
+        }
+        Err(Error::PllConflict)
+    }
+}
+
+impl<S: SharedDriver> OutputHandle<'_, S> {
+    /// The output channel of this handle (0..2)
+    pub fn output(&self) -> usize {
+        self.output
+    }
+
+    /// Sets the output frequency in Hz, without changing any other output.
+    ///
+    /// Returns `Error::PllConflict` if this would need a PLL used by the
+    /// other outputs.
+    ///
+    /// freq: The output frequency in Hz
+    pub fn set_freq(&mut self, freq: u32) -> Result<Retune, Error> {
+        self.set_freq_millihertz(freq as u64 * 1000)
+    }
+
+    /// Like [`OutputHandle::set_freq`], with the frequency given in
+    /// millihertz
+    pub fn set_freq_millihertz(&mut self, freq: u64) -> Result<Retune, Error> {
+        self.driver
+            .lock(|si5351| si5351.tune_exclusive(self.output, freq))
+    }
+
+    /// The frequency the output generates, in millihertz, or `None` if it
+    /// has not been configured
+    pub fn freq_millihertz(&self) -> Option<u64> {
+        self.driver
+            .lock(|si5351| si5351.output_freq_millihertz(self.output))
+    }
+
+    /// Enables or disables the output
+    ///
+    /// enabled: Whether the output is enabled
+    pub fn enable(&mut self, enabled: bool) -> Result<(), Error> {
+        self.driver
+            .lock(|si5351| si5351.enable_output(self.output, enabled))
+    }
+
+    /// Sets the output driver strength
+    ///
+    /// strength: The drive current
+    pub fn set_drive_strength(&mut self, strength: DriveStrength) -> Result<(), Error> {
+        self.driver
+            .lock(|si5351| si5351.set_drive_strength(self.output, strength))
+    }
+
+    /// Sets the initial phase offset in quarter periods of the PLL. It
+    /// takes effect at the next PLL reset.
+    ///
+    /// offset: The phase offset (0..127)
+    pub fn set_phase_offset(&mut self, offset: u8) -> Result<(), Error> {
+        self.driver
+            .lock(|si5351| si5351.set_phase_offset(self.output, offset))
+    }
+}
+
+impl<S: SharedDriver> Drop for OutputHandle<'_, S> {
+    /// Gives the output back for the next [`Si5351::split`]
+    fn drop(&mut self) {
+        self.driver
+            .lock(|si5351| si5351.handles &= !(1 << self.output));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::DeviceConfig;
+    use crate::testing::RegisterFile;
+
+    fn driver() -> Si5351<RegisterFile> {
+        Si5351::new(RegisterFile::new(), DeviceConfig::default()).unwrap()
+    }
+
+    #[test]
+    fn hands_out_outputs_once() {
+        let shared = RefCell::new(driver());
+        let [clk0, clk1, clk2] = Si5351::split(&shared).unwrap();
+        assert_eq!([clk0.output(), clk1.output(), clk2.output()], [0, 1, 2]);
+        assert!(matches!(Si5351::split(&shared), Err(Error::OutputTaken)));
+        drop(clk0);
+        drop(clk1);
+        assert!(matches!(Si5351::split(&shared), Err(Error::OutputTaken)));
+        drop(clk2);
+        assert!(Si5351::split(&shared).is_ok());
+    }
+
+    #[test]
+    fn keeps_other_outputs() {
+        let shared = RefCell::new(driver());
+        let [mut clk0, mut clk1, mut clk2] = Si5351::split(&shared).unwrap();
+        assert_eq!(clk0.set_freq(12_288_000), Ok(Retune::Full));
+        assert_eq!(clk1.set_freq(7_074_000), Ok(Retune::Full));
+        let before = [clk0.freq_millihertz(), clk1.freq_millihertz()];
+        // Both PLLs are taken, CLK2 divides one of them down
+        assert_eq!(clk2.set_freq(10_000_000), Ok(Retune::Multisynth));
+        assert_eq!(clk2.freq_millihertz(), Some(10_000_000_000));
+        assert_eq!([clk0.freq_millihertz(), clk1.freq_millihertz()], before);
+        assert_eq!(clk1.set_freq(7_074_500), Ok(Retune::Pll));
+        assert_eq!(clk0.freq_millihertz(), before[0]);
+        assert_eq!(clk2.freq_millihertz(), Some(10_000_000_000));
+        clk2.enable(true).unwrap();
+        let enabled = shared.borrow().i2c_dev.registers()[3];
+        assert_eq!(enabled & 0b111, 0b011);
+    }
+
+    #[cfg(feature = "critical-section")]
+    #[test]
+    fn shares_through_critical_section_mutex() {
+        let shared = critical_section::Mutex::new(RefCell::new(driver()));
+        let [mut clk0, _, _] = Si5351::split(&shared).unwrap();
+        assert_eq!(clk0.set_freq(12_288_000), Ok(Retune::Full));
+        assert!(matches!(Si5351::split(&shared), Err(Error::OutputTaken)));
+        let freq = critical_section::with(|cs| shared.borrow_ref(cs).output_freq_millihertz(0));
+        assert_eq!(freq, clk0.freq_millihertz());
+    }
+}
@@ -0,0 +1,268 @@
+//! Frequency sweeps with a measurement at every step, e.g. for scalar
+//! network and antenna analysers.
+
+use embedded_hal::delay::DelayNs;
+use embedded_hal::i2c::I2c;
+
+use crate::{Error, Observer, Si5351, check};
+
+/// How the points of a [`Sweep`] are spaced
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum SweepScale {
+    /// Equal frequency steps
+    Linear,
+    /// Equal frequency ratios, i.e. the same number of points per decade
+    Log,
+}
+
+/// A sweep of one output from a start to a stop frequency in a number of
+/// points, run with [`Si5351::sweep`]
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Sweep {
+    start: u64,
+    stop: u64,
+    points: usize,
+    scale: SweepScale,
+    settle_ns: u32,
+    /// Frequency ratio between two points of a log sweep
+    ratio: f64,
+}
+
+impl Sweep {
+    /// A sweep with equal frequency steps
+    ///
+    /// start: The first frequency in millihertz
+    ///
+    /// stop: The last frequency in millihertz, may be below `start`
+    ///
+    /// points: The number of points, including start and stop
+    ///
+    /// settle_ns: Time to wait after every retune before measuring
+    pub fn linear(start: u64, stop: u64, points: usize, settle_ns: u32) -> Result<Self, Error> {
+        Self::new(start, stop, points, SweepScale::Linear, settle_ns)
+    }
+
+    /// A sweep with equal frequency ratios between the points
+    ///
+    /// Same parameters as [`Sweep::linear`].
+    pub fn log(start: u64, stop: u64, points: usize, settle_ns: u32) -> Result<Self, Error> {
+        Self::new(start, stop, points, SweepScale::Log, settle_ns)
+    }
+
+    fn new(
+        start: u64,
+        stop: u64,
+        points: usize,
+        scale: SweepScale,
+        settle_ns: u32,
+    ) -> Result<Self, Error> {
+        check(start > 0 && stop > 0 && points > 0, Error::InvalidParameter)?;
+        let ratio = if points > 1 {
+            nth_root(stop as f64 / start as f64, points as u32 - 1)
+        } else {
+            1.0
+        };
+        Ok(Self {
+            start,
+            stop,
+            points,
+            scale,
+            settle_ns,
+            ratio,
+        })
+    }
+
+    /// The number of points
+    pub fn points(&self) -> usize {
+        self.points
+    }
+
+    /// The spacing of the points
+    pub fn scale(&self) -> SweepScale {
+        self.scale
+    }
+
+    /// The requested frequency of point `index`, in millihertz
+    pub fn freq(&self, index: usize) -> u64 {
+        if index == 0 || self.points == 1 {
+            return self.start;
+        }
+        if index >= self.points - 1 {
+            return self.stop;
+        }
+        match self.scale {
+            SweepScale::Linear => {
+                let span = self.stop as i128 - self.start as i128;
+                (self.start as i128 + span * index as i128 / (self.points as i128 - 1)) as u64
+            }
+            SweepScale::Log => (self.start as f64 * powi(self.ratio, index as u32) + 0.5) as u64,
+        }
+    }
+}
+
+/// `x` to the power of `n`, by squaring
+fn powi(mut x: f64, mut n: u32) -> f64 {
+    let mut result = 1.0;
+    while n > 0 {
+        if n & 1 != 0 {
+            result *= x;
+        }
+        x *= x;
+        n >>= 1;
+    }
+    result
+}
+
+/// The `n`th root of `x` (`x` > 0) with Newton's method, as `core` has no
+/// `powf`
+fn nth_root(x: f64, n: u32) -> f64 {
+    if x < 1.0 {
+        return 1.0 / nth_root(1.0 / x, n);
+    }
+    // By Bernoulli's inequality this starts above the root, so the
+    // iteration falls monotonically towards it
+    let mut y = 1.0 + (x - 1.0) / n as f64;
+    for _ in 0..200 {
+        let next = ((n - 1) as f64 * y + x / powi(y, n - 1)) / n as f64;
+        if next >= y {
+            break;
+        }
+        y = next;
+    }
+    y
+}
+
+impl<I2C: I2c, DELAY: DelayNs, OBS: Observer> Si5351<I2C, DELAY, OBS> {
+    /// Sweeps an output over the points of a [`Sweep`], calling `measure`
+    /// at every point.
+    ///
+    /// Every point is set with [`Si5351::retune_millihertz`]. Points within
+    /// reach of the current PLL and multisynth setup are retuned without a
+    /// glitch, any other point is set up again with a PLL reset, which
+    /// briefly stops the output. That may be the first point, but also any
+    /// later one of a wide sweep, e.g. whenever it crosses a step of the R
+    /// divider below 500 kHz. After waiting the settle time, `measure` is
+    /// called with the frequency the output actually generates, and that
+    /// frequency and the measurement are stored in `results`. Returns the
+    /// number of points measured.
+    ///
+    /// Returns `Error::BufferOverflow` before retuning anything if `results`
+    /// is shorter than the number of points, and `Error::PllConflict` at a
+    /// point which needs a PLL reset while both PLLs drive other outputs.
+    ///
+    /// output: The output channel to sweep (0..2)
+    ///
+    /// sweep: The frequencies to step through
+    ///
+    /// delay: Used for the settle time
+    ///
+    /// measure: Reads the measurement (e.g. an ADC) at the given frequency
+    /// in millihertz
+    ///
+    /// results: Receives the achieved frequency in millihertz and the
+    /// measurement of every point
+    pub fn sweep<D, F, M>(
+        &mut self,
+        output: usize,
+        sweep: &Sweep,
+        delay: &mut D,
+        mut measure: F,
+        results: &mut [(u64, M)],
+    ) -> Result<usize, Error>
+    where
+        D: DelayNs,
+        F: FnMut(u64) -> M,
+    {
+        check(results.len() >= sweep.points, Error::BufferOverflow)?;
+        for (index, result) in results[..sweep.points].iter_mut().enumerate() {
+            self.retune_millihertz(output, sweep.freq(index))?;
+            delay.delay_ns(sweep.settle_ns);
+            let achieved = self
+                .output_freq_millihertz(output)
+                .ok_or(Error::InvalidParameter)?;
+            *result = (achieved, measure(achieved));
+        }
+        Ok(sweep.points)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::RegisterFile;
+    use crate::{Access, DeviceConfig, NoDelay, PLL, Recorder, Registers};
+
+    type Driver = Si5351<RegisterFile, NoDelay, Recorder<512, 1>>;
+
+    fn driver(freq: u32) -> Driver {
+        let mut clock_gen = Si5351::with_observer(
+            RegisterFile::new(),
+            DeviceConfig::default(),
+            NoDelay,
+            Recorder::new(),
+        )
+        .unwrap();
+        clock_gen.set_freq(0, PLL::A, freq).unwrap();
+        clock_gen.observer_mut().clear();
+        clock_gen
+    }
+
+    fn pll_resets(clock_gen: &Driver) -> usize {
+        clock_gen
+            .observer()
+            .iter()
+            .filter(|record| {
+                record.access == Access::Write && record.reg == Registers::PLLReset as u8
+            })
+            .count()
+    }
+
+    #[test]
+    fn narrow_sweep_does_not_reset_pll() {
+        let mut clock_gen = driver(7_000_000);
+        let sweep = Sweep::linear(7_000_000_000, 7_200_000_000, 21, 0).unwrap();
+        let mut results = [(0, ()); 21];
+        assert_eq!(
+            clock_gen.sweep(0, &sweep, &mut NoDelay, |_| (), &mut results),
+            Ok(21)
+        );
+        assert_eq!(pll_resets(&clock_gen), 0);
+        for (index, (freq, ())) in results.into_iter().enumerate() {
+            assert!(freq.abs_diff(sweep.freq(index)) < 1000, "{index}: {freq}");
+        }
+    }
+
+    #[test]
+    fn wide_sweep_resets_pll_after_first_point() {
+        // The output already runs at the first point, so every PLL reset
+        // comes from a later one
+        let mut clock_gen = driver(1_000_000);
+        let sweep = Sweep::log(1_000_000_000, 10_000_000, 12, 0).unwrap();
+        let mut results = [(0, 0); 12];
+        assert_eq!(
+            clock_gen.sweep(0, &sweep, &mut NoDelay, |freq| freq, &mut results),
+            Ok(12)
+        );
+        assert!(pll_resets(&clock_gen) > 0);
+        for (index, (freq, measured)) in results.into_iter().enumerate() {
+            assert_eq!(freq, measured);
+            assert!(freq.abs_diff(sweep.freq(index)) < 1000, "{index}: {freq}");
+        }
+    }
+
+    #[test]
+    fn rejects_short_results() {
+        let mut clock_gen = driver(7_000_000);
+        let sweep = Sweep::linear(7_000_000_000, 7_200_000_000, 3, 0).unwrap();
+        let mut results = [(0, ()); 2];
+        assert_eq!(
+            clock_gen.sweep(0, &sweep, &mut NoDelay, |_| (), &mut results),
+            Err(Error::BufferOverflow)
+        );
+        assert!(clock_gen.observer().is_empty());
+    }
+}
@@ -0,0 +1,460 @@
+//! I2C test doubles for exercising retries, verification and rollback.
+//!
+//! [`RegisterFile`] is a bare Si5351 stand-in: 256 registers behind an
+//! auto-increasing register pointer. [`FaultyI2c`] wraps it, or any other
+//! bus such as a more complete simulator, and misbehaves on purpose.
+//!
+//! ```
+//! use si5351a_adafruit::testing::{FaultyI2c, RegisterFile};
+//! use si5351a_adafruit::{DeviceConfig, Error, PLL, RetryPolicy, Si5351};
+//!
+//! // The 16th transaction, the write of the CLK0 multisynth, is not
+//! // acknowledged twice in a row
+//! let bus = FaultyI2c::new(RegisterFile::new()).nak_on_transaction(15, 2);
+//! let config = DeviceConfig {
+//!     retry: RetryPolicy { retries: 2, ..RetryPolicy::default() },
+//!     ..DeviceConfig::default()
+//! };
+//! let mut clock_gen = Si5351::new(bus, config)?;
+//! clock_gen.set_freq(0, PLL::A, 10_000_000)?;
+//! assert_eq!(clock_gen.bus_stats().retries, 2);
+//! # Ok::<(), Error>(())
+//! ```
+
+use embedded_hal::i2c::{Error as _, ErrorKind, ErrorType, I2c, NoAcknowledgeSource, Operation};
+
+use crate::{LAST_REGISTER, Registers};
+
+/// Registers of a device without any behaviour: writes store bytes, reads
+/// return them, the register pointer increases with every byte
+#[derive(Debug, Clone)]
+pub struct RegisterFile {
+    registers: [u8; 256],
+    pointer: u8,
+}
+
+impl Default for RegisterFile {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RegisterFile {
+    /// All registers cleared
+    pub const fn new() -> Self {
+        Self {
+            registers: [0; 256],
+            pointer: 0,
+        }
+    }
+
+    /// The contents of all registers
+    pub fn registers(&self) -> &[u8; 256] {
+        &self.registers
+    }
+
+    /// The contents of all registers, e.g. to set up status bits
+    pub fn registers_mut(&mut self) -> &mut [u8; 256] {
+        &mut self.registers
+    }
+}
+
+impl ErrorType for RegisterFile {
+    type Error = ErrorKind;
+}
+
+impl I2c for RegisterFile {
+    fn transaction(
+        &mut self,
+        _address: u8,
+        operations: &mut [Operation<'_>],
+    ) -> Result<(), Self::Error> {
+        for operation in operations {
+            match operation {
+                Operation::Write(data) => {
+                    if let [reg, values @ ..] = &**data {
+                        self.pointer = *reg;
+                        for &value in values {
+                            self.registers[self.pointer as usize] = value;
+                            self.pointer = self.pointer.wrapping_add(1);
+                        }
+                    }
+                }
+                Operation::Read(data) => {
+                    for value in data.iter_mut() {
+                        *value = self.registers[self.pointer as usize];
+                        self.pointer = self.pointer.wrapping_add(1);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Transactions a fault applies to: `count` transactions from index
+/// `start` on
+#[derive(Debug, Clone, Copy)]
+struct Window {
+    start: u32,
+    count: u32,
+}
+
+impl Window {
+    fn contains(&self, transaction: u32) -> bool {
+        transaction >= self.start && transaction - self.start < self.count
+    }
+}
+
+/// A bus which injects faults into the transactions passed to another
+/// bus.
+///
+/// Transactions are counted from 0, including the failing ones. A
+/// transaction which fails is not passed on, so the device does not see
+/// it. embedded-hal has no error kind for timeouts, a simulated timeout
+/// fails with `ErrorKind::Other`, which the driver reports as
+/// `Error::I2CTransaction(ErrorKind::Other)`.
+#[derive(Debug, Clone)]
+pub struct FaultyI2c<I2C = RegisterFile> {
+    i2c: I2C,
+    transactions: u32,
+    injected: u32,
+    nak: Option<Window>,
+    timeout: Option<Window>,
+    /// Register and remaining number of NAKs
+    nak_register: Option<(u8, u32)>,
+    /// xorshift state and the chance of a NAK as one in n
+    random: Option<(u64, u32)>,
+    reset_on: Option<u32>,
+    /// XOR masks applied to the data read from each register
+    flips: [u8; 256],
+    /// Registers reading as 0 since the last simulated reset
+    cleared: [u32; 8],
+    sys_init: bool,
+    sys_init_sticky: bool,
+}
+
+impl<I2C: I2c> FaultyI2c<I2C> {
+    /// Passes all transactions on until faults are added
+    pub fn new(i2c: I2C) -> Self {
+        Self {
+            i2c,
+            transactions: 0,
+            injected: 0,
+            nak: None,
+            timeout: None,
+            nak_register: None,
+            random: None,
+            reset_on: None,
+            flips: [0; 256],
+            cleared: [0; 8],
+            sys_init: false,
+            sys_init_sticky: false,
+        }
+    }
+
+    /// NAKs (data byte) `count` transactions from transaction `n` on
+    pub fn nak_on_transaction(mut self, n: u32, count: u32) -> Self {
+        self.nak = Some(Window { start: n, count });
+        self
+    }
+
+    /// NAKs (data byte) the next `count` transactions which write or read
+    /// register `reg`
+    pub fn nak_on_register(mut self, reg: u8, count: u32) -> Self {
+        self.nak_register = Some((reg, count));
+        self
+    }
+
+    /// NAKs (data byte) transactions at random, about one in `one_in`,
+    /// reproducibly for the same seed
+    pub fn random_naks(mut self, seed: u64, one_in: u32) -> Self {
+        // xorshift gets stuck at 0
+        self.random = Some((seed.max(1), one_in.max(1)));
+        self
+    }
+
+    /// Times out `count` transactions from transaction `n` on
+    pub fn timeout_on_transaction(mut self, n: u32, count: u32) -> Self {
+        self.timeout = Some(Window { start: n, count });
+        self
+    }
+
+    /// Flips the bits of `mask` in every byte read from register `reg`
+    pub fn flip_read_bits(mut self, reg: u8, mask: u8) -> Self {
+        self.flips[reg as usize] ^= mask;
+        self
+    }
+
+    /// Simulates a device reset just before transaction `n`, see
+    /// [`FaultyI2c::reset_device`]
+    pub fn reset_on_transaction(mut self, n: u32) -> Self {
+        self.reset_on = Some(n);
+        self
+    }
+
+    /// Simulates a device reset (e.g. a brown-out): all registers read as
+    /// 0 until they are written again, SYS_INIT is set in the device
+    /// status until it has been read once and SYS_INIT_STKY stays set in
+    /// the sticky status until register 1 is written.
+    pub fn reset_device(&mut self) {
+        self.cleared = [u32::MAX; 8];
+        self.sys_init = true;
+        self.sys_init_sticky = true;
+    }
+
+    /// The number of transactions so far
+    pub fn transactions(&self) -> u32 {
+        self.transactions
+    }
+
+    /// The number of faults injected so far
+    pub fn injected(&self) -> u32 {
+        self.injected
+    }
+
+    /// The wrapped bus
+    pub fn inner(&self) -> &I2C {
+        &self.i2c
+    }
+
+    /// The wrapped bus
+    pub fn inner_mut(&mut self) -> &mut I2C {
+        &mut self.i2c
+    }
+
+    /// Returns the wrapped bus
+    pub fn release(self) -> I2C {
+        self.i2c
+    }
+
+    /// The fault to inject into the current transaction, if any
+    fn fault(&mut self, operations: &[Operation<'_>]) -> Option<ErrorKind> {
+        let transaction = self.transactions;
+        if self
+            .timeout
+            .is_some_and(|window| window.contains(transaction))
+        {
+            return Some(ErrorKind::Other);
+        }
+        let nak = ErrorKind::NoAcknowledge(NoAcknowledgeSource::Data);
+        if self.nak.is_some_and(|window| window.contains(transaction)) {
+            return Some(nak);
+        }
+        if let Some((reg, count)) = &mut self.nak_register
+            && *count > 0
+            && touches(operations, *reg)
+        {
+            *count -= 1;
+            return Some(nak);
+        }
+        if let Some((state, one_in)) = &mut self.random {
+            // xorshift64
+            *state ^= *state << 13;
+            *state ^= *state >> 7;
+            *state ^= *state << 17;
+            if *state % *one_in as u64 == 0 {
+                return Some(nak);
+            }
+        }
+        None
+    }
+
+    fn is_cleared(&self, reg: u8) -> bool {
+        self.cleared[reg as usize / 32] & (1 << (reg % 32)) != 0
+    }
+}
+
+impl<I2C: I2c> ErrorType for FaultyI2c<I2C> {
+    type Error = ErrorKind;
+}
+
+impl<I2C: I2c> I2c for FaultyI2c<I2C> {
+    fn transaction(
+        &mut self,
+        address: u8,
+        operations: &mut [Operation<'_>],
+    ) -> Result<(), Self::Error> {
+        if self.reset_on == Some(self.transactions) {
+            self.reset_device();
+        }
+        let fault = self.fault(operations);
+        self.transactions = self.transactions.wrapping_add(1);
+        if let Some(kind) = fault {
+            self.injected = self.injected.wrapping_add(1);
+            return Err(kind);
+        }
+        self.i2c
+            .transaction(address, operations)
+            .map_err(|error| error.kind())?;
+        let mut pointer = 0_u8;
+        for operation in operations {
+            match operation {
+                Operation::Write(data) => {
+                    if let [reg, values @ ..] = &**data {
+                        pointer = *reg;
+                        for _ in values {
+                            // Written registers hold their value again
+                            self.cleared[pointer as usize / 32] &= !(1 << (pointer % 32));
+                            if pointer == Registers::InterruptStatusSticky as u8 {
+                                self.sys_init_sticky = false;
+                            }
+                            pointer = pointer.wrapping_add(1);
+                        }
+                    }
+                }
+                Operation::Read(data) => {
+                    for value in data.iter_mut() {
+                        if self.is_cleared(pointer) && pointer <= LAST_REGISTER {
+                            *value = 0;
+                        }
+                        if pointer == Registers::DeviceStatus as u8 && self.sys_init {
+                            *value |= 1 << 7;
+                            self.sys_init = false;
+                        }
+                        if pointer == Registers::InterruptStatusSticky as u8 && self.sys_init_sticky
+                        {
+                            *value |= 1 << 7;
+                        }
+                        *value ^= self.flips[pointer as usize];
+                        pointer = pointer.wrapping_add(1);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Whether a transaction writes or reads register `reg`
+fn touches(operations: &[Operation<'_>], reg: u8) -> bool {
+    let mut pointer = 0_u8;
+    for operation in operations {
+        let (start, len) = match operation {
+            Operation::Write(data) => match &**data {
+                [first, values @ ..] => {
+                    pointer = *first;
+                    (pointer, values.len())
+                }
+                [] => continue,
+            },
+            Operation::Read(data) => (pointer, data.len()),
+        };
+        if (0..len).any(|i| start.wrapping_add(i as u8) == reg) {
+            return true;
+        }
+        pointer = start.wrapping_add(len as u8);
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{DeviceConfig, Error, RetryPolicy, Si5351};
+
+    fn write(bus: &mut FaultyI2c, data: &[u8]) -> Result<(), ErrorKind> {
+        bus.write(0x60, data)
+    }
+
+    fn read(bus: &mut FaultyI2c, reg: u8) -> Result<u8, ErrorKind> {
+        let mut value = [0];
+        bus.write_read(0x60, &[reg], &mut value)?;
+        Ok(value[0])
+    }
+
+    #[test]
+    fn register_file_increments_pointer() {
+        let mut bus = RegisterFile::new();
+        bus.write(0x60, &[42, 1, 2, 3]).unwrap();
+        let mut values = [0; 3];
+        bus.write_read(0x60, &[42], &mut values).unwrap();
+        assert_eq!(values, [1, 2, 3]);
+        assert_eq!(bus.registers()[44], 3);
+    }
+
+    #[test]
+    fn naks_window_of_transactions() {
+        let mut bus = FaultyI2c::new(RegisterFile::new()).nak_on_transaction(1, 2);
+        let nak = Err(ErrorKind::NoAcknowledge(NoAcknowledgeSource::Data));
+        assert_eq!(write(&mut bus, &[16, 1]), Ok(()));
+        assert_eq!(write(&mut bus, &[17, 1]), nak);
+        assert_eq!(write(&mut bus, &[17, 1]), nak);
+        assert_eq!(write(&mut bus, &[17, 1]), Ok(()));
+        assert_eq!((bus.transactions(), bus.injected()), (4, 2));
+        assert_eq!(bus.inner().registers()[17], 1);
+    }
+
+    #[test]
+    fn naks_register_in_burst() {
+        let mut bus = FaultyI2c::new(RegisterFile::new()).nak_on_register(44, 1);
+        assert_eq!(write(&mut bus, &[40, 1, 2]), Ok(()));
+        assert_eq!(
+            write(&mut bus, &[42, 1, 2, 3]),
+            Err(ErrorKind::NoAcknowledge(NoAcknowledgeSource::Data))
+        );
+        // Not passed on
+        assert_eq!(bus.inner().registers()[42], 0);
+        assert_eq!(write(&mut bus, &[42, 1, 2, 3]), Ok(()));
+    }
+
+    #[test]
+    fn random_naks_are_reproducible() {
+        let run = |seed| {
+            let mut bus = FaultyI2c::new(RegisterFile::new()).random_naks(seed, 4);
+            (0..64)
+                .map(|_| write(&mut bus, &[16, 0]).is_err())
+                .fold(0_u64, |bits, nak| (bits << 1) | nak as u64)
+        };
+        assert_eq!(run(7), run(7));
+        assert_ne!(run(7), 0);
+    }
+
+    #[test]
+    fn times_out_as_other() {
+        let mut bus = FaultyI2c::new(RegisterFile::new()).timeout_on_transaction(0, 1);
+        assert_eq!(write(&mut bus, &[16, 0]), Err(ErrorKind::Other));
+        assert_eq!(write(&mut bus, &[16, 0]), Ok(()));
+    }
+
+    #[test]
+    fn driver_reports_timeout_as_transaction_error() {
+        let bus = FaultyI2c::new(RegisterFile::new()).timeout_on_transaction(0, u32::MAX);
+        let config = DeviceConfig {
+            retry: RetryPolicy {
+                retries: 0,
+                ..RetryPolicy::default()
+            },
+            ..DeviceConfig::default()
+        };
+        assert_eq!(
+            Si5351::new(bus, config).err(),
+            Some(Error::I2CTransaction(ErrorKind::Other))
+        );
+    }
+
+    #[test]
+    fn flips_read_bits() {
+        let mut bus = FaultyI2c::new(RegisterFile::new()).flip_read_bits(16, 0x81);
+        write(&mut bus, &[16, 0x4f]).unwrap();
+        assert_eq!(read(&mut bus, 16), Ok(0xce));
+        assert_eq!(bus.inner().registers()[16], 0x4f);
+    }
+
+    #[test]
+    fn reset_clears_registers_until_written() {
+        let mut bus = FaultyI2c::new(RegisterFile::new()).reset_on_transaction(2);
+        write(&mut bus, &[16, 0x4f, 0x4f]).unwrap();
+        write(&mut bus, &[1, 0]).unwrap();
+        // Reset before this one
+        assert_eq!(read(&mut bus, 0), Ok(0x80));
+        assert_eq!(read(&mut bus, 0), Ok(0x00));
+        assert_eq!(read(&mut bus, 1), Ok(0x80));
+        assert_eq!(read(&mut bus, 16), Ok(0));
+        write(&mut bus, &[16, 0x6f]).unwrap();
+        assert_eq!(read(&mut bus, 16), Ok(0x6f));
+        assert_eq!(read(&mut bus, 17), Ok(0));
+        write(&mut bus, &[1, 0]).unwrap();
+        assert_eq!(read(&mut bus, 1), Ok(0));
+    }
+}
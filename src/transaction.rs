@@ -0,0 +1,399 @@
+//! Configuration changes staged and committed as a whole, with rollback.
+//!
+//! A sequence like `setup_pll`, `setup_multisynth`, `setup_rdiv` that fails
+//! midway leaves the chip half configured. In a [`Transaction`] those calls
+//! only stage register values; [`Transaction::commit`] writes the registers
+//! that actually change in as few bursts as possible while the outputs are
+//! gated, and puts the previous values back if any write fails.
+//!
+//! ```no_run
+//! # fn example<I2C: embedded_hal::i2c::I2c>(
+//! #     clock_gen: &mut si5351a_adafruit::Si5351<I2C>,
+//! # ) -> Result<(), si5351a_adafruit::Error> {
+//! use si5351a_adafruit::{PLL, RDiv};
+//!
+//! let mut transaction = clock_gen.transaction();
+//! transaction.setup_pll(PLL::A, 36, 0, 1)?;
+//! transaction.setup_multisynth(0, PLL::A, 90, 0, 1)?;
+//! transaction.setup_rdiv(0, RDiv::Div1)?;
+//! transaction.enable_output(0, true)?;
+//! transaction.commit()?;
+//! # Ok(())
+//! # }
+//! ```
+
+use core::ops::RangeInclusive;
+
+use embedded_hal::delay::DelayNs;
+use embedded_hal::i2c::I2c;
+
+use crate::{
+    Config, DriveStrength, Error, LAST_REGISTER, MAX_BURST, MultisynthDiv, Observer, OutputState,
+    PLL, RDiv, RegisterCache, Registers, Si5351, Staged,
+};
+
+/// Staged configuration changes of a driver, see [`Si5351::transaction`].
+///
+/// Dropping a transaction without committing it discards the staged
+/// changes.
+pub struct Transaction<'a, I2C: I2c, DELAY: DelayNs, OBS: Observer> {
+    driver: &'a mut Si5351<I2C, DELAY, OBS>,
+    /// The driver state before the transaction, restored on rollback
+    config: Config,
+    last_rdiv_value: [u8; 3],
+    outputs: [OutputState; 3],
+    registers: RegisterCache,
+    done: bool,
+}
+
+impl<I2C: I2c, DELAY: DelayNs, OBS: Observer> Si5351<I2C, DELAY, OBS> {
+    /// Starts a transaction. Until it is committed, configuration calls
+    /// through the transaction only stage register values and nothing is
+    /// written to the device.
+    pub fn transaction(&mut self) -> Transaction<'_, I2C, DELAY, OBS> {
+        self.staged = Some(Staged::new());
+        Transaction {
+            config: self.config,
+            last_rdiv_value: self.last_rdiv_value,
+            outputs: self.outputs,
+            registers: self.registers,
+            driver: self,
+            done: false,
+        }
+    }
+}
+
+impl<I2C: I2c, DELAY: DelayNs, OBS: Observer> Transaction<'_, I2C, DELAY, OBS> {
+    /// See [`Si5351::setup_pll`]
+    pub fn setup_pll(&mut self, pll: PLL, mult: u32, num: u32, denom: u32) -> Result<(), Error> {
+        self.driver.setup_pll(pll, mult, num, denom)
+    }
+
+    /// See [`Si5351::setup_pll_int`]
+    pub fn setup_pll_int(&mut self, pll: PLL, mult: u32) -> Result<(), Error> {
+        self.driver.setup_pll_int(pll, mult)
+    }
+
+    /// See [`Si5351::setup_multisynth`]
+    pub fn setup_multisynth(
+        &mut self,
+        output: usize,
+        pll_source: PLL,
+        div: u32,
+        num: u32,
+        denom: u32,
+    ) -> Result<(), Error> {
+        self.driver
+            .setup_multisynth(output, pll_source, div, num, denom)
+    }
+
+    /// See [`Si5351::setup_multisynth_int`]
+    pub fn setup_multisynth_int(
+        &mut self,
+        output: usize,
+        pll_source: PLL,
+        div: MultisynthDiv,
+    ) -> Result<(), Error> {
+        self.driver.setup_multisynth_int(output, pll_source, div)
+    }
+
+    /// See [`Si5351::setup_rdiv`]
+    pub fn setup_rdiv(&mut self, output: usize, div: RDiv) -> Result<(), Error> {
+        self.driver.setup_rdiv(output, div)
+    }
+
+    /// See [`Si5351::set_freq`]
+    pub fn set_freq(&mut self, output: usize, pll: PLL, freq: u32) -> Result<(), Error> {
+        self.driver.set_freq(output, pll, freq)
+    }
+
+    /// See [`Si5351::enable_outputs`]
+    pub fn enable_outputs(&mut self, enabled: bool) -> Result<(), Error> {
+        self.driver.enable_outputs(enabled)
+    }
+
+    /// See [`Si5351::enable_output`]
+    pub fn enable_output(&mut self, output: usize, enabled: bool) -> Result<(), Error> {
+        self.driver.enable_output(output, enabled)
+    }
+
+    /// See [`Si5351::set_drive_strength`]
+    pub fn set_drive_strength(
+        &mut self,
+        output: usize,
+        strength: DriveStrength,
+    ) -> Result<(), Error> {
+        self.driver.set_drive_strength(output, strength)
+    }
+
+    /// See [`Si5351::set_phase_offset`]
+    pub fn set_phase_offset(&mut self, output: usize, offset: u8) -> Result<(), Error> {
+        self.driver.set_phase_offset(output, offset)
+    }
+
+    /// Runs staging code with the driver, for configuration calls built on
+    /// the ones above
+    pub(crate) fn stage<F>(&mut self, stage: F) -> Result<(), Error>
+    where
+        F: FnOnce(&mut Si5351<I2C, DELAY, OBS>) -> Result<(), Error>,
+    {
+        stage(self.driver)
+    }
+
+    /// Writes the staged changes to the device.
+    ///
+    /// Only registers whose value changes are written. If any of them
+    /// changes anything besides the output enables, all outputs are
+    /// disabled, the registers are written in bursts, the PLLs are reset if
+    /// a staged change asked for it and a PLL or phase offset register
+    /// changed, and the staged output enables are applied last.
+    ///
+    /// If a write fails, the previous register values are written back
+    /// (resetting the PLLs if any of their registers was touched), the
+    /// driver state is restored and the original error is returned.
+    pub fn commit(mut self) -> Result<(), Error> {
+        self.done = true;
+        let Some(staged) = self.driver.staged.take() else {
+            return Ok(());
+        };
+        let target = self.driver.registers;
+        let enable_reg = Registers::OutputEnableControl as u8;
+        // The previous values are needed to tell what changes and to roll
+        // back, read the ones the driver does not know
+        for reg in 0..=LAST_REGISTER {
+            if (staged.contains(reg) || reg == enable_reg) && self.registers.get(reg).is_none() {
+                let mut value = 0;
+                if let Err(error) = self.driver.read8(reg, &mut value) {
+                    self.restore_state();
+                    return Err(error);
+                }
+                self.registers.set(reg, value);
+            }
+        }
+        self.driver.registers = self.registers;
+        let changed = changed_registers(&staged, &target, &self.registers);
+        let gate = changed.iter().any(|&bits| bits != 0);
+        let needs_reset = PLL_REGISTERS
+            .chain(PHASE_REGISTERS)
+            .any(|reg| contains(&changed, reg));
+        let previous_enables = self.registers.get(enable_reg);
+        let enables = target
+            .get(enable_reg)
+            .filter(|_| staged.contains(enable_reg))
+            .or(previous_enables)
+            .unwrap_or(0xff);
+        let driver = &mut *self.driver;
+        let result = (|| {
+            if gate {
+                // Disable all outputs setting CLKx_DIS high
+                driver.write8(enable_reg, 0xff)?;
+                write_runs(driver, &target, &changed)?;
+                if staged.pll_reset != 0 && needs_reset {
+                    driver.write8(Registers::PLLReset as u8, staged.pll_reset)?;
+                }
+            }
+            if gate || previous_enables != Some(enables) {
+                driver.write8(enable_reg, enables)?;
+            }
+            Ok(())
+        })();
+        if result.is_err() {
+            self.rollback(&changed);
+        }
+        result
+    }
+
+    /// Puts the previous values of the changed registers back on the
+    /// device, on a best effort basis as the bus has just failed
+    fn rollback(&mut self, changed: &RegisterSet) {
+        let enable_reg = Registers::OutputEnableControl as u8;
+        let previous = self.registers;
+        let driver = &mut *self.driver;
+        let restored = (|| {
+            driver.write8(enable_reg, 0xff)?;
+            write_runs(driver, &previous, changed)?;
+            if PLL_REGISTERS.into_iter().any(|reg| contains(changed, reg)) {
+                driver.write8(Registers::PLLReset as u8, (1 << 7) | (1 << 5))?;
+            }
+            match previous.get(enable_reg) {
+                Some(enables) => driver.write8(enable_reg, enables),
+                None => Ok(()),
+            }
+        })();
+        self.restore_state();
+        if restored.is_err() {
+            // The device is in an unknown state
+            self.driver.registers.clear();
+        }
+    }
+
+    /// Restores the driver state from before the transaction
+    fn restore_state(&mut self) {
+        self.driver.staged = None;
+        self.driver.config = self.config;
+        self.driver.last_rdiv_value = self.last_rdiv_value;
+        self.driver.outputs = self.outputs;
+        self.driver.registers = self.registers;
+    }
+}
+
+impl<I2C: I2c, DELAY: DelayNs, OBS: Observer> Drop for Transaction<'_, I2C, DELAY, OBS> {
+    fn drop(&mut self) {
+        if !self.done {
+            self.restore_state();
+        }
+    }
+}
+
+/// The PLL A and B parameters
+const PLL_REGISTERS: RangeInclusive<u8> = 26..=41;
+
+/// The initial phase offsets of CLK0..CLK5, applied by a PLL reset
+const PHASE_REGISTERS: RangeInclusive<u8> = 165..=170;
+
+/// One bit per register
+pub(crate) type RegisterSet = [u32; (LAST_REGISTER as usize + 32) / 32];
+
+fn contains(set: &RegisterSet, reg: u8) -> bool {
+    reg <= LAST_REGISTER && set[reg as usize / 32] & (1 << (reg % 32)) != 0
+}
+
+/// The staged registers whose value differs from `previous`, except the
+/// output enables which are written separately
+fn changed_registers(
+    staged: &Staged,
+    target: &RegisterCache,
+    previous: &RegisterCache,
+) -> RegisterSet {
+    let mut changed = [0; (LAST_REGISTER as usize + 32) / 32];
+    for reg in 0..=LAST_REGISTER {
+        if staged.contains(reg)
+            && reg != Registers::OutputEnableControl as u8
+            && target.get(reg) != previous.get(reg)
+        {
+            changed[reg as usize / 32] |= 1 << (reg % 32);
+        }
+    }
+    changed
+}
+
+/// Writes the values of `image` for the registers in `selected`, merging
+/// consecutive registers into bursts
+pub(crate) fn write_runs<I2C: I2c, DELAY: DelayNs, OBS: Observer>(
+    driver: &mut Si5351<I2C, DELAY, OBS>,
+    image: &RegisterCache,
+    selected: &RegisterSet,
+) -> Result<(), Error> {
+    let mut send_buffer = [0_u8; MAX_BURST + 1];
+    let mut len = 0;
+    for reg in 0..=LAST_REGISTER {
+        let value = image.get(reg).filter(|_| contains(selected, reg));
+        if let Some(value) = value {
+            if len == 0 {
+                send_buffer[0] = reg;
+            }
+            len += 1;
+            send_buffer[len] = value;
+        }
+        if len > 0 && (value.is_none() || len == MAX_BURST || reg == LAST_REGISTER) {
+            driver.write_n(&send_buffer[..=len])?;
+            len = 0;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::{FaultyI2c, RegisterFile};
+    use crate::{DeviceConfig, Error, LAST_REGISTER, PLL, Si5351};
+
+    fn freqs(clock_gen: &Si5351<FaultyI2c>) -> [Option<u64>; 3] {
+        [0, 1, 2].map(|output| clock_gen.output_freq_millihertz(output))
+    }
+
+    fn cache(clock_gen: &Si5351<FaultyI2c>) -> Vec<Option<u8>> {
+        (0..=LAST_REGISTER)
+            .map(|reg| clock_gen.registers.get(reg))
+            .collect()
+    }
+
+    /// A driver with CLK0 at 10MHz whose bus injects `faults` from now on
+    fn faulty<F>(faults: F) -> Si5351<FaultyI2c>
+    where
+        F: FnOnce(FaultyI2c, u32) -> FaultyI2c,
+    {
+        let bus = FaultyI2c::new(RegisterFile::new());
+        let mut clock_gen = Si5351::new(bus, DeviceConfig::default()).unwrap();
+        clock_gen.set_freq(0, PLL::A, 10_000_000).unwrap();
+        clock_gen.enable_outputs(true).unwrap();
+        let transactions = clock_gen.i2c_dev.transactions();
+        clock_gen.i2c_dev = faults(clock_gen.i2c_dev.clone(), transactions);
+        clock_gen
+    }
+
+    #[test]
+    fn commits_changed_registers_with_outputs_gated() {
+        let mut clock_gen = faulty(|bus, _| bus);
+        let before = *clock_gen.i2c_dev.inner().registers();
+        let mut transaction = clock_gen.transaction();
+        transaction.set_freq(0, PLL::A, 14_000_000).unwrap();
+        transaction.set_freq(1, PLL::B, 7_000_000).unwrap();
+        // Nothing is written while staging
+        assert_eq!(transaction.driver.i2c_dev.inner().registers(), &before);
+        assert_eq!(transaction.commit(), Ok(()));
+        let freq = clock_gen.output_freq_millihertz(0).unwrap();
+        assert!(freq.abs_diff(14_000_000_000) < 1000, "{freq}");
+        let registers = clock_gen.i2c_dev.inner().registers();
+        assert_eq!(registers[3], 0x00);
+        assert_eq!(registers[177], (1 << 7) | (1 << 5));
+    }
+
+    #[test]
+    fn rolls_back_after_nak_mid_commit() {
+        // Staging reads one register per set_freq, the commit gates the
+        // outputs and then the first burst is NAKed
+        let mut clock_gen = faulty(|bus, next| bus.nak_on_transaction(next + 3, 1));
+        let before = *clock_gen.i2c_dev.inner().registers();
+        let freqs_before = freqs(&clock_gen);
+        let mut transaction = clock_gen.transaction();
+        transaction.set_freq(0, PLL::A, 14_000_000).unwrap();
+        transaction.set_freq(1, PLL::B, 7_000_000).unwrap();
+        assert_eq!(transaction.commit(), Err(Error::I2CNoACK));
+        assert_eq!(clock_gen.i2c_dev.injected(), 1);
+        assert_eq!(clock_gen.i2c_dev.inner().registers(), &before);
+        assert_eq!(freqs(&clock_gen), freqs_before);
+        assert!(clock_gen.staged.is_none());
+        // The driver knows what the device holds and carries on
+        assert_eq!(clock_gen.registers.get(3), Some(0x00));
+        assert_eq!(clock_gen.set_freq(1, PLL::B, 7_000_000), Ok(()));
+    }
+
+    #[test]
+    fn forgets_device_state_when_rollback_fails() {
+        let mut clock_gen = faulty(|bus, next| bus.nak_on_transaction(next + 2, u32::MAX));
+        let freqs_before = freqs(&clock_gen);
+        let mut transaction = clock_gen.transaction();
+        transaction.set_freq(0, PLL::A, 14_000_000).unwrap();
+        assert_eq!(transaction.commit(), Err(Error::I2CNoACK));
+        assert_eq!(freqs(&clock_gen), freqs_before);
+        assert_eq!(clock_gen.registers.get(3), None);
+    }
+
+    #[test]
+    fn drop_restores_state() {
+        let mut clock_gen = faulty(|bus, _| bus);
+        let before = *clock_gen.i2c_dev.inner().registers();
+        let freqs_before = freqs(&clock_gen);
+        let cache_before = cache(&clock_gen);
+        {
+            let mut transaction = clock_gen.transaction();
+            transaction.set_freq(0, PLL::A, 14_000_000).unwrap();
+            transaction.enable_outputs(false).unwrap();
+        }
+        assert_eq!(clock_gen.i2c_dev.inner().registers(), &before);
+        assert!(clock_gen.staged.is_none());
+        assert_eq!(freqs(&clock_gen), freqs_before);
+        assert_eq!(cache(&clock_gen), cache_before);
+    }
+}
@@ -0,0 +1,369 @@
+//! WSPR beacon transmitter.
+//!
+//! [`WsprMessage`] encodes a type 1 message (callsign, 4 character
+//! Maidenhead locator and power) into the 162 channel symbols, and
+//! [`Si5351::transmit_wspr`] keys them as four tones 1.4648Hz apart on an
+//! output:
+//!
+//! ```no_run
+//! # fn example<I2C: embedded_hal::i2c::I2c, D: embedded_hal::delay::DelayNs>(
+//! #     clock_gen: &mut si5351a_adafruit::Si5351<I2C>,
+//! #     delay: &mut D,
+//! # ) -> Result<(), si5351a_adafruit::Error> {
+//! use si5351a_adafruit::wspr::WsprMessage;
+//!
+//! let message = WsprMessage::new("K1ABC", "FN42", 37)?;
+//! // Start at an even minute, 1500Hz into the 30m WSPR window
+//! clock_gen.transmit_wspr(0, 10_140_200_000, &message, delay)?;
+//! # Ok(())
+//! # }
+//! ```
+
+use embedded_hal::delay::DelayNs;
+use embedded_hal::i2c::I2c;
+
+use crate::{Error, HopTable, Observer, Si5351, check};
+
+/// Number of channel symbols in a WSPR transmission
+pub const SYMBOL_COUNT: usize = 162;
+
+/// Duration of a channel symbol (8192 / 12000 s) in nanoseconds
+pub const SYMBOL_DURATION_NS: u32 = 682_666_667;
+
+/// Convolutional code polynomials (K = 32, r = 1/2)
+const POLY1: u32 = 0xf2d0_5351;
+const POLY2: u32 = 0xe461_3c47;
+
+/// Pseudo random synchronisation vector, the LSB of every channel symbol
+const SYNC: [u8; SYMBOL_COUNT] = [
+    1, 1, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 1, 1, 1, 0, 0, 0, 1, 0, 0, 1, 0, 1, 1, 1, 1, 0, 0, 0, 0, 0,
+    0, 0, 1, 0, 0, 1, 0, 1, 0, 0, 0, 0, 0, 0, 1, 0, 1, 1, 0, 0, 1, 1, 0, 1, 0, 0, 0, 1, 1, 0, 1, 0,
+    0, 0, 0, 1, 1, 0, 1, 0, 1, 0, 1, 0, 1, 0, 0, 1, 0, 0, 1, 0, 1, 1, 0, 0, 0, 1, 1, 0, 1, 0, 1, 0,
+    0, 0, 1, 0, 0, 0, 0, 0, 1, 0, 0, 1, 0, 0, 1, 1, 1, 0, 1, 1, 0, 0, 1, 1, 0, 1, 0, 0, 0, 1, 1, 1,
+    0, 0, 0, 0, 0, 1, 0, 1, 0, 0, 1, 1, 0, 0, 0, 0, 0, 0, 0, 1, 1, 0, 1, 0, 1, 1, 0, 0, 0, 1, 1, 0,
+    0, 0,
+];
+
+/// Offset of tone `tone` (0..3) from tone 0, in millihertz (12000 / 8192 Hz
+/// per tone)
+pub const fn tone_offset_millihertz(tone: u8) -> u64 {
+    tone as u64 * 12_000_000 / 8192
+}
+
+/// The 162 channel symbols (tone numbers 0..3) of a type 1 WSPR message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct WsprMessage {
+    symbols: [u8; SYMBOL_COUNT],
+}
+
+impl WsprMessage {
+    /// Encodes a type 1 WSPR message.
+    ///
+    /// callsign: Up to 6 characters, with a digit as the second or third
+    /// character (e.g. `K1ABC` or `DL1ABC`)
+    ///
+    /// locator: 4 character Maidenhead locator (e.g. `FN42`)
+    ///
+    /// power: Transmit power in dBm (0..60, ending in 0, 3 or 7)
+    pub fn new(callsign: &str, locator: &str, power: u8) -> Result<Self, Error> {
+        let callsign = normalise_callsign(callsign)?;
+        let n = pack_callsign(&callsign)?;
+        let m = pack_locator(locator)? * 128 + pack_power(power)? + 64;
+        // Source encoding: 28 bits of callsign followed by 22 bits of
+        // locator and power
+        let mut data = [0_u8; 11];
+        data[0] = (n >> 20) as u8;
+        data[1] = (n >> 12) as u8;
+        data[2] = (n >> 4) as u8;
+        data[3] = ((n & 0x0f) << 4) as u8 | ((m >> 18) & 0x0f) as u8;
+        data[4] = (m >> 10) as u8;
+        data[5] = (m >> 2) as u8;
+        data[6] = ((m & 0x03) << 6) as u8;
+        let coded = convolve(&data);
+        let interleaved = interleave(&coded);
+        let mut symbols = [0; SYMBOL_COUNT];
+        for (symbol, (&sync, &bit)) in symbols.iter_mut().zip(SYNC.iter().zip(interleaved.iter())) {
+            *symbol = sync + 2 * bit;
+        }
+        Ok(Self { symbols })
+    }
+
+    /// The channel symbols, each one a tone number (0..3)
+    pub fn symbols(&self) -> &[u8; SYMBOL_COUNT] {
+        &self.symbols
+    }
+}
+
+/// Upper cases the callsign and pads it to 6 characters so that the
+/// third character is the digit
+fn normalise_callsign(callsign: &str) -> Result<[u8; 6], Error> {
+    let callsign = callsign.trim().as_bytes();
+    check(
+        !callsign.is_empty() && callsign.len() <= 6,
+        Error::InvalidParameter,
+    )?;
+    let mut padded = [b' '; 6];
+    let offset = if callsign.len() > 2 && callsign[2].is_ascii_digit() {
+        0
+    } else {
+        1
+    };
+    check(callsign.len() + offset <= 6, Error::InvalidParameter)?;
+    for (dst, src) in padded[offset..].iter_mut().zip(callsign) {
+        *dst = src.to_ascii_uppercase();
+    }
+    check(padded[2].is_ascii_digit(), Error::InvalidParameter)?;
+    Ok(padded)
+}
+
+/// Maps `0`..`9` to 0..9, `A`..`Z` to 10..35 and space to 36
+fn char_value(c: u8) -> Result<u32, Error> {
+    match c {
+        b'0'..=b'9' => Ok((c - b'0') as u32),
+        b'A'..=b'Z' => Ok((c - b'A') as u32 + 10),
+        b' ' => Ok(36),
+        _ => Err(Error::InvalidParameter),
+    }
+}
+
+/// Maps `A`..`Z` to 0..25 and space to 26 (the callsign suffix)
+fn suffix_value(c: u8) -> Result<u32, Error> {
+    match c {
+        b'A'..=b'Z' => Ok((c - b'A') as u32),
+        b' ' => Ok(26),
+        _ => Err(Error::InvalidParameter),
+    }
+}
+
+fn pack_callsign(callsign: &[u8; 6]) -> Result<u32, Error> {
+    let mut n = char_value(callsign[0])?;
+    // The second character cannot be a space
+    check(callsign[1] != b' ', Error::InvalidParameter)?;
+    n = n * 36 + char_value(callsign[1])?;
+    n = n * 10 + char_value(callsign[2])?;
+    n = n * 27 + suffix_value(callsign[3])?;
+    n = n * 27 + suffix_value(callsign[4])?;
+    n = n * 27 + suffix_value(callsign[5])?;
+    Ok(n)
+}
+
+fn pack_locator(locator: &str) -> Result<u32, Error> {
+    let locator = locator.trim().as_bytes();
+    check(locator.len() == 4, Error::InvalidParameter)?;
+    let field = |c: u8| match c.to_ascii_uppercase() {
+        c @ b'A'..=b'R' => Ok((c - b'A') as u32),
+        _ => Err(Error::InvalidParameter),
+    };
+    let square = |c: u8| match c {
+        b'0'..=b'9' => Ok((c - b'0') as u32),
+        _ => Err(Error::InvalidParameter),
+    };
+    Ok((179 - 10 * field(locator[0])? - square(locator[2])?) * 180
+        + 10 * field(locator[1])?
+        + square(locator[3])?)
+}
+
+fn pack_power(power: u8) -> Result<u32, Error> {
+    check(
+        power <= 60 && matches!(power % 10, 0 | 3 | 7),
+        Error::InvalidParameter,
+    )?;
+    Ok(power as u32)
+}
+
+/// Convolutional encoding of the 50 message bits plus 31 zero tail bits
+fn convolve(data: &[u8; 11]) -> [u8; SYMBOL_COUNT] {
+    let mut coded = [0; SYMBOL_COUNT];
+    let mut reg = 0_u32;
+    for i in 0..SYMBOL_COUNT / 2 {
+        let bit = (data[i / 8] >> (7 - i % 8)) & 1;
+        reg = (reg << 1) | bit as u32;
+        coded[2 * i] = ((reg & POLY1).count_ones() & 1) as u8;
+        coded[2 * i + 1] = ((reg & POLY2).count_ones() & 1) as u8;
+    }
+    coded
+}
+
+/// Reorders the symbols by bit reversed 8 bit addresses
+fn interleave(coded: &[u8; SYMBOL_COUNT]) -> [u8; SYMBOL_COUNT] {
+    let mut interleaved = [0; SYMBOL_COUNT];
+    let mut p = 0;
+    for i in 0..=255_u8 {
+        let j = i.reverse_bits() as usize;
+        if j < SYMBOL_COUNT {
+            interleaved[j] = coded[p];
+            p += 1;
+            if p == SYMBOL_COUNT {
+                break;
+            }
+        }
+    }
+    interleaved
+}
+
+impl<I2C: I2c, DELAY: DelayNs, OBS: Observer> Si5351<I2C, DELAY, OBS> {
+    /// Transmits a WSPR message on an output.
+    ///
+    /// The output is first tuned to `freq` (tone 0), which may need a full
+    /// reconfiguration. The four tones are then precomputed as a
+    /// [`HopTable`] and every symbol is keyed with a single short burst,
+    /// without resetting the PLL. Call this at the start of an even UTC
+    /// minute, delayed by one second.
+    ///
+    /// The output is left running on the last tone.
+    ///
+    /// output: The output channel to use (0..2)
+    ///
+    /// freq: The frequency of tone 0 in millihertz
+    ///
+    /// message: The encoded message
+    ///
+    /// delay: Used for the 0.683s symbol timing
+    pub fn transmit_wspr<D: DelayNs>(
+        &mut self,
+        output: usize,
+        freq: u64,
+        message: &WsprMessage,
+        delay: &mut D,
+    ) -> Result<(), Error> {
+        self.retune_millihertz(output, freq)?;
+        let tones = [0, 1, 2, 3].map(|tone| freq + tone_offset_millihertz(tone));
+        let table = HopTable::new(self, output, tones)?;
+        for &symbol in message.symbols() {
+            self.hop(&table, symbol as usize)?;
+            delay.delay_ns(SYMBOL_DURATION_NS);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Channel symbols written as a string of tone numbers
+    fn symbols(tones: &str) -> [u8; SYMBOL_COUNT] {
+        let mut symbols = [0; SYMBOL_COUNT];
+        assert_eq!(tones.len(), SYMBOL_COUNT);
+        for (symbol, tone) in symbols.iter_mut().zip(tones.bytes()) {
+            *symbol = tone - b'0';
+        }
+        symbols
+    }
+
+    // Reference symbols, cross-checked against the independent `wspr`
+    // crate which reproduces the WSJT-X encoder
+    const REFERENCES: [(&str, &str, u8, &str); 5] = [
+        (
+            "K1ABC",
+            "FN42",
+            37,
+            "330020001020131222100323133220200032012322002232110233210221321222033030\
+             301210212032132003323032203020201023021112330231212221332000010320132222\
+             202332323320031222",
+        ),
+        (
+            "K1A",
+            "FN34",
+            33,
+            "330022001200111020300301133000020210032120200030130231230203301220013210\
+             323210032212110001103232223022001221201312330011232203132202030320112200\
+             222132323120031222",
+        ),
+        (
+            "N6AB",
+            "CM87",
+            0,
+            "310022021020133020300121311202000232010320000210132031212023303022233032\
+             323230212210132201101210221002201023021110332031032003312222230120110220\
+             222332121300031022",
+        ),
+        (
+            "G1ABC",
+            "IO83",
+            37,
+            "330002001020113222322101131222000030010302220232132233010001323222011230\
+             301030012232330023121012221020223201003112332211212001332002212320112222\
+             202332121302233220",
+        ),
+        (
+            "KA1BCD",
+            "AA00",
+            33,
+            "332202023202111002102321111002022032232322202030310231032201321202033032\
+             121030230030332021103032203200203201221312132011230021332022230122110200\
+             002312123322231202",
+        ),
+    ];
+
+    #[test]
+    fn encodes_reference_messages() {
+        for (callsign, locator, power, tones) in REFERENCES {
+            let message = WsprMessage::new(callsign, locator, power).unwrap();
+            assert_eq!(
+                message.symbols(),
+                &symbols(tones),
+                "{callsign} {locator} {power}"
+            );
+        }
+    }
+
+    #[test]
+    fn symbols_carry_sync_vector() {
+        let message = WsprMessage::new("K1ABC", "FN42", 37).unwrap();
+        for (&symbol, &sync) in message.symbols().iter().zip(SYNC.iter()) {
+            assert!(symbol < 4);
+            assert_eq!(symbol & 1, sync);
+        }
+    }
+
+    #[test]
+    fn normalises_callsigns() {
+        // Digit in position 2 gets a leading space, in position 3 none
+        assert_eq!(normalise_callsign("K1ABC"), Ok(*b" K1ABC"));
+        assert_eq!(normalise_callsign("DL1ABC"), Ok(*b"DL1ABC"));
+        assert_eq!(normalise_callsign("K1A"), Ok(*b" K1A  "));
+        assert_eq!(normalise_callsign(" k1abc "), Ok(*b" K1ABC"));
+        assert_eq!(
+            WsprMessage::new("k1abc", "fn42", 37),
+            WsprMessage::new("K1ABC", "FN42", 37)
+        );
+    }
+
+    #[test]
+    fn rejects_long_callsigns() {
+        assert_eq!(normalise_callsign("DL1ABCD"), Err(Error::InvalidParameter));
+        // Six characters, but the digit needs a leading space
+        assert_eq!(normalise_callsign("K1ABCD"), Err(Error::InvalidParameter));
+    }
+
+    #[test]
+    fn rejects_invalid_callsigns() {
+        for callsign in ["", "  ", "ABCDE", "1ABC", "K1AB1", "K1A/P", "K1ÄB"] {
+            assert_eq!(
+                WsprMessage::new(callsign, "FN42", 37),
+                Err(Error::InvalidParameter),
+                "{callsign}"
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_locators_and_powers() {
+        for locator in ["FN4", "FN422", "SN42", "F042", "FNA2"] {
+            assert_eq!(
+                WsprMessage::new("K1ABC", locator, 37),
+                Err(Error::InvalidParameter),
+                "{locator}"
+            );
+        }
+        for power in [1, 35, 61, 63] {
+            assert_eq!(
+                WsprMessage::new("K1ABC", "FN42", power),
+                Err(Error::InvalidParameter),
+                "{power}"
+            );
+        }
+    }
+}
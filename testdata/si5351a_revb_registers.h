@@ -0,0 +1,64 @@
+/*
+ * Si5351A Rev B Configuration Register Export Header File
+ *
+ * This file represents a series of Silicon Labs Si5351A Rev B
+ * register writes that can be performed to load a single configuration
+ * on a device. It was created by a Silicon Labs ClockBuilder Pro
+ * export tool.
+ *
+ * Part:		                                       Si5351A Rev B
+ * Design ID:                                          
+ * Includes Pre/Post Download Control Register Writes: Yes
+ * Created By:                                         ClockBuilder Pro v2.x
+ * Timestamp:                                          
+ *
+ * A complete design report corresponding to this export is included at the end 
+ * of this file.
+ *
+ */
+
+#ifndef SI5351A_REVB_REG_CONFIG_HEADER
+#define SI5351A_REVB_REG_CONFIG_HEADER
+
+#define SI5351A_REVB_REG_CONFIG_NUM_REGS				12
+
+typedef struct
+{
+	unsigned int address; /* 16-bit register address */
+	unsigned char value; /* 8-bit register data */
+
+} si5351a_revb_register_t;
+
+si5351a_revb_register_t const si5351a_revb_registers[SI5351A_REVB_REG_CONFIG_NUM_REGS] =
+{
+	{ 0x0002, 0x53 },
+	{ 0x0003, 0x00 },
+	{ /* CLK0 */ 0x0010, 0x4F },
+	{ 0x0011, /* CLK1 */ 0x4F },
+	{ 0x0012, 0x6F /* CLK2 */ },
+	{ 0x001A, 0x00 },
+	{ 0x001B, 0x05 },
+	{ 0x001C, 0x00 },
+	/* PLL B { 0x0022, 0x00 }, */
+	{ 0x0095, 0x00 },
+	{ 0x00A5, 0x00 },
+	{ 0x00B1, 0xAC },
+	{ 0x00B7, 0x92 },
+};
+
+/*
+ * Design Report
+ *
+ * Overview
+ * ========
+ * Part:               Si5351A
+ * Input Frequency:    25 MHz crystal { XA/XB }
+ *
+ * Outputs
+ * =======
+ * CLK0: 12.288 MHz { 3.3 V CMOS }
+ * CLK1: 100 MHz
+ * CLK2: 60 MHz
+ */
+
+#endif